@@ -1,8 +1,9 @@
 use database::server_joins::JoinResult;
 use flate2::read::ZlibDecoder;
+use io::proxy::NetworkProxy;
 use std::{
     io::{Cursor, Read},
-    net::SocketAddrV4,
+    net::SocketAddr,
 };
 use tokio::{
     io::{AsyncReadExt, AsyncWriteExt},
@@ -13,10 +14,16 @@ use varint::{AsyncVarint, SyncVarintWrite};
 
 pub mod varint;
 
-pub async fn join(addr: SocketAddrV4, version: i32, server_id: i64) -> JoinResult {
+/// Join the server at `addr`, connecting through `proxy`
+pub async fn join(
+    proxy: &NetworkProxy,
+    addr: SocketAddr,
+    version: i32,
+    server_id: i64,
+) -> JoinResult {
     let mut join_data = JoinResult::none(server_id);
 
-    let res = join_internal(addr, version, &mut join_data).await;
+    let res = join_internal(proxy, addr, version, &mut join_data).await;
     if let Err(err) = res {
         join_data.error = Some(err.to_string());
     }
@@ -25,11 +32,12 @@ pub async fn join(addr: SocketAddrV4, version: i32, server_id: i64) -> JoinResul
 }
 
 async fn join_internal(
-    addr: SocketAddrV4,
+    proxy: &NetworkProxy,
+    addr: SocketAddr,
     version: i32,
     _join_data: &mut JoinResult,
 ) -> eyre::Result<()> {
-    let mut stream = TcpStream::connect(addr).await?;
+    let mut stream = proxy.connect(addr).await?;
     let compression = &mut None;
 
     let mut packet = Vec::new();
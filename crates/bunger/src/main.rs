@@ -1,23 +1,38 @@
-use std::{net::SocketAddrV4, str::FromStr};
+use std::{net::SocketAddr, str::FromStr};
 
 use database::{server::PingResult, DatabaseConnection};
+use io::proxy::{self, NetworkProxy};
 
+/// Join the server given as the first argument through the configured proxy,
+/// or a local test server if there's none
 #[tokio::main]
 async fn main() -> eyre::Result<()> {
-    let addr = SocketAddrV4::from_str("127.0.0.1:25569").unwrap();
+    let target = std::env::args()
+        .nth(1)
+        .map(|addr| SocketAddr::from_str(&addr))
+        .transpose()?;
+    let (addr, proxy, mut server) = match target {
+        Some(addr) => (addr, proxy::get_proxy()?, None),
+        None => {
+            let server =
+                ram_server::run_server("1.8.9", 25569, false).expect("unable to start server :<");
+            // the server is on loopback, which a proxy can't reach
+            let addr = SocketAddr::from_str("127.0.0.1:25569").unwrap();
+            (addr, NetworkProxy::Direct, Some(server))
+        }
+    };
 
     let db = DatabaseConnection::new().await.unwrap();
-    let server_id = PingResult::from_ip_port(addr.ip(), addr.port(), &db.pool)
+    let server_id = PingResult::from_ip_port(&addr.ip(), addr.port(), &db.pool)
         .await
         .map(|res| res.id.unwrap())
         .unwrap_or(0);
 
-    let mut server =
-        ram_server::run_server("1.8.9", 25569, false).expect("unable to start server :<");
+    let data = bunger::join(&proxy, addr, 47, server_id).await;
 
-    let data = bunger::join(addr, 47, server_id).await;
-
-    server.kill().expect("Unable to kill child process");
+    if let Some(server) = &mut server {
+        server.kill().expect("Unable to kill child process");
+    }
 
     println!("data = {data:?}");
 
@@ -0,0 +1,85 @@
+//! Compares sending SYNs one syscall at a time to sending them in batches.
+//!
+//! Needs CAP_NET_RAW. Usage: `sendmmsg [interface] [destination ip]`, which
+//! defaults to the loopback interface. To benchmark against a veth pair:
+//! ```sh
+//! ip link add snowstorm0 type veth peer name snowstorm1
+//! ip addr add 10.77.0.1/24 dev snowstorm0
+//! ip link set snowstorm0 up && ip link set snowstorm1 up
+//! cargo r -r --example sendmmsg -- snowstorm0 10.77.0.2
+//! ```
+
+use common::net::{
+    raw_socket::{RawSocket, MAX_BATCH_SIZE},
+    tcp_template::{PacketRepr, TemplatePacket, TemplatePacketRepr},
+};
+use pnet::{
+    packet::tcp::{TcpFlags, TcpOption},
+    util::MacAddr,
+};
+use std::{net::Ipv4Addr, time::Instant};
+
+const PACKET_COUNT: usize = 1_000_000;
+
+fn main() {
+    let mut args = std::env::args().skip(1);
+    let interface_name = args.next().unwrap_or_else(|| "lo".to_string());
+    let dest_addr: Ipv4Addr = args
+        .next()
+        .map(|ip| ip.parse().expect("invalid destination ip"))
+        .unwrap_or(Ipv4Addr::LOCALHOST);
+
+    let mut socket = RawSocket::new(&interface_name).expect("unable to open raw socket");
+    let mut template = TemplatePacket::new(TemplatePacketRepr {
+        flags: TcpFlags::SYN,
+        window: 32768,
+        ttl: 64,
+        urgent_ptr: 0,
+        options: vec![
+            TcpOption::mss(1360),
+            TcpOption::nop(),
+            TcpOption::nop(),
+            TcpOption::sack_perm(),
+        ],
+        gateway_mac: Some(MacAddr::zero()),
+        interface_mac: Some(MacAddr::zero()),
+        source_addr: Ipv4Addr::LOCALHOST.into(),
+    });
+    let packets = (0..PACKET_COUNT)
+        .map(|i| {
+            template
+                .build(PacketRepr {
+                    dest_addr: dest_addr.into(),
+                    dest_port: 1024 + (i % 60000) as u16,
+                    source_port: 61000,
+                    sequence: i as u32,
+                    acknowledgement: 0,
+                    payload: &[],
+                })
+                .to_vec()
+        })
+        .collect::<Vec<_>>();
+    println!("sending {PACKET_COUNT} packets on {interface_name} to {dest_addr}");
+
+    let start_time = Instant::now();
+    for packet in &packets {
+        socket.send_blocking(packet);
+    }
+    report("send", start_time);
+
+    for batch_size in [16, 64, 256, MAX_BATCH_SIZE] {
+        let start_time = Instant::now();
+        for batch in packets.chunks(batch_size) {
+            socket.send_batch_blocking(batch);
+        }
+        report(&format!("sendmmsg ({batch_size} per batch)"), start_time);
+    }
+}
+
+fn report(name: &str, start_time: Instant) {
+    let run_time = Instant::now() - start_time;
+    println!(
+        "{name}: took {run_time:?} ({:.0} pps)",
+        PACKET_COUNT as f64 / run_time.as_secs_f64()
+    );
+}
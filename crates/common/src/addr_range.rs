@@ -1,5 +1,8 @@
 use serde::{Deserialize, Serialize};
-use std::{net::Ipv4Addr, str::FromStr};
+use std::{
+    net::{Ipv4Addr, Ipv6Addr},
+    str::FromStr,
+};
 
 #[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize, Deserialize, Hash)]
 pub struct Ipv4AddrRange {
@@ -17,6 +20,22 @@ impl Ipv4AddrRange {
     }
 }
 
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize, Deserialize, Hash)]
+pub struct Ipv6AddrRange {
+    pub first: Ipv6Addr,
+    pub last: Ipv6Addr,
+}
+
+impl Ipv6AddrRange {
+    pub fn new(first: Ipv6Addr, last: Ipv6Addr) -> Self {
+        Self { first, last }
+    }
+
+    pub fn contains(&self, ip: Ipv6Addr) -> bool {
+        ip >= self.first && ip <= self.last
+    }
+}
+
 impl FromStr for Ipv4AddrRange {
     type Err = eyre::Report;
 
@@ -1,31 +1,264 @@
-use crate::addr_range::Ipv4AddrRange;
+use crate::{
+    addr_range::{Ipv4AddrRange, Ipv6AddrRange},
+    network_range::{SocketAddrRange, SocketAddrV4Range, SocketAddrV6Range},
+};
 use lazy_static::lazy_static;
-use std::{cmp::Ordering, collections::BTreeSet, fs, net::Ipv4Addr};
+use std::{
+    cmp::Ordering,
+    collections::BTreeSet,
+    fs,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::{Arc, RwLock},
+    time::SystemTime,
+};
+
+const EXCLUDE_PATH: &str = "exclude.txt";
 
 lazy_static! {
-    static ref EXCLUDE_LIST: BTreeSet<ExcludeEntry> = {
-        let file = fs::read_to_string("exclude.txt").unwrap();
-        let rows = file.split('\n').filter(|item| item != &"");
-        rows.map(|ip_string| {
-            let ip_string = ip_string.trim();
-            if ip_string.contains('/') {
-                let range = ip_string.parse::<Ipv4AddrRange>().unwrap();
-                ExcludeEntry::Range(range)
-            } else if ip_string.contains('-') {
-                let sections = ip_string.split('-').collect::<Vec<_>>();
-                let range_start = sections[0].parse().unwrap();
-                let range_end = sections[1].parse().unwrap();
-                ExcludeEntry::Range(Ipv4AddrRange::new(range_start, range_end))
-            } else {
-                ExcludeEntry::Address(
-                    ip_string
-                        .parse()
-                        .unwrap_or_else(|_| panic!("Invalid ip string '{}'", ip_string)),
-                )
+    /// Networks opted out through the database, set by [`set_opt_outs`]
+    static ref OPT_OUTS: RwLock<OptOuts> = RwLock::new(OptOuts::default());
+    static ref EXCLUDE_LIST: RwLock<Arc<ExcludeList>> = RwLock::new(Arc::new(
+        ExcludeList::load().expect("unable to load exclude.txt")
+    ));
+}
+
+#[derive(Debug, Default, PartialEq)]
+struct OptOuts {
+    ipv4: Vec<Ipv4AddrRange>,
+    ipv6: Vec<Ipv6AddrRange>,
+}
+
+struct ExcludeList {
+    /// The entries of `exclude.txt` followed by the IPv4 opt-outs
+    entries: BTreeSet<ExcludeEntry>,
+    opt_outs: usize,
+    /// The entries as sorted, non overlapping `(first, last)` ranges
+    ranges: Vec<(u128, u128)>,
+    /// The IPv6 opt-outs as sorted, non overlapping `(first, last)` ranges,
+    /// `exclude.txt` only has IPv4 addresses
+    ipv6_ranges: Vec<(u128, u128)>,
+    modified: Option<SystemTime>,
+}
+
+impl ExcludeList {
+    fn load() -> eyre::Result<Self> {
+        let modified = fs::metadata(EXCLUDE_PATH)?.modified().ok();
+        let file = fs::read_to_string(EXCLUDE_PATH)?;
+        let mut entries = file
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty())
+            .map(parse_entry)
+            .collect::<eyre::Result<Vec<_>>>()?;
+        let opt_outs = OPT_OUTS.read().unwrap();
+        entries.extend(opt_outs.ipv4.iter().copied().map(ExcludeEntry::Range));
+
+        // the set treats overlapping entries as equal and only keeps one of
+        // them, so the ranges are made from every entry
+        let ranges = entries
+            .iter()
+            .map(|entry| match entry {
+                ExcludeEntry::Address(ip) => (u32::from(*ip).into(), u32::from(*ip).into()),
+                ExcludeEntry::Range(range) => {
+                    (u32::from(range.first).into(), u32::from(range.last).into())
+                }
+            })
+            .collect();
+        let ipv6_ranges = opt_outs
+            .ipv6
+            .iter()
+            .map(|range| (range.first.into(), range.last.into()))
+            .collect();
+
+        Ok(Self {
+            entries: entries.into_iter().collect(),
+            opt_outs: opt_outs.ipv4.len() + opt_outs.ipv6.len(),
+            ranges: merge(ranges),
+            ipv6_ranges: merge(ipv6_ranges),
+            modified,
+        })
+    }
+}
+
+/// Sort `ranges` and merge the ones that overlap or touch
+fn merge(mut ranges: Vec<(u128, u128)>) -> Vec<(u128, u128)> {
+    ranges.sort_unstable();
+    let mut merged: Vec<(u128, u128)> = Vec::with_capacity(ranges.len());
+    for (first, last) in ranges {
+        match merged.last_mut() {
+            Some((_, merged_last)) if first <= merged_last.saturating_add(1) => {
+                *merged_last = (*merged_last).max(last);
+            }
+            _ => merged.push((first, last)),
+        }
+    }
+    merged
+}
+
+fn parse_entry(ip_string: &str) -> eyre::Result<ExcludeEntry> {
+    if ip_string.contains('/') {
+        Ok(ExcludeEntry::Range(ip_string.parse::<Ipv4AddrRange>()?))
+    } else if let Some((range_start, range_end)) = ip_string.split_once('-') {
+        Ok(ExcludeEntry::Range(Ipv4AddrRange::new(
+            range_start.trim().parse()?,
+            range_end.trim().parse()?,
+        )))
+    } else {
+        ip_string
+            .parse()
+            .map(ExcludeEntry::Address)
+            .map_err(|_| eyre::eyre!("Invalid ip string '{}'", ip_string))
+    }
+}
+
+fn exclude_list() -> Arc<ExcludeList> {
+    EXCLUDE_LIST.read().unwrap().clone()
+}
+
+/// Read `exclude.txt` again. The old list is kept if the new one is invalid.
+pub fn reload() -> eyre::Result<()> {
+    let list = ExcludeList::load()?;
+    println!(
+        "loaded {} exclude list entries, {} of them opt-outs",
+        list.entries.len() + list.ipv6_ranges.len(),
+        list.opt_outs
+    );
+    *EXCLUDE_LIST.write().unwrap() = Arc::new(list);
+    Ok(())
+}
+
+/// Read `exclude.txt` again if it was modified since it was last read,
+/// returning whether it was.
+pub fn reload_if_changed() -> eyre::Result<bool> {
+    let modified = fs::metadata(EXCLUDE_PATH)?.modified().ok();
+    if modified == exclude_list().modified {
+        return Ok(false);
+    }
+    reload()?;
+    Ok(true)
+}
+
+/// Replace the opt-outs that are merged with `exclude.txt`, reloading the list
+/// if they changed.
+pub fn set_opt_outs(
+    mut ipv4: Vec<Ipv4AddrRange>,
+    mut ipv6: Vec<Ipv6AddrRange>,
+) -> eyre::Result<bool> {
+    ipv4.sort_unstable_by_key(|range| (range.first, range.last));
+    ipv6.sort_unstable_by_key(|range| (range.first, range.last));
+    let opt_outs = OptOuts { ipv4, ipv6 };
+    {
+        let mut current = OPT_OUTS.write().unwrap();
+        if *current == opt_outs {
+            return Ok(false);
+        }
+        *current = opt_outs;
+    }
+    reload()?;
+    Ok(true)
+}
+
+/// Remove every excluded address from `ranges`, returning what's left and how
+/// many addresses were removed. IPv6 ranges only have the opt-outs removed.
+pub fn subtract(ranges: Vec<SocketAddrRange>) -> (Vec<SocketAddrRange>, u64) {
+    let list = exclude_list();
+    subtract_from(&list.ranges, &list.ipv6_ranges, ranges)
+}
+
+/// [`subtract`] with the excluded IPv4 and IPv6 ranges passed in
+fn subtract_from(
+    excluded_v4: &[(u128, u128)],
+    excluded_v6: &[(u128, u128)],
+    ranges: Vec<SocketAddrRange>,
+) -> (Vec<SocketAddrRange>, u64) {
+    let mut allowed = Vec::with_capacity(ranges.len());
+    let mut skipped = 0u64;
+    for range in ranges {
+        let before = range.count_addresses();
+        let mut after = 0u64;
+        match range {
+            SocketAddrRange::V4(range) => {
+                for piece in subtract_v4(excluded_v4, range) {
+                    after = after.saturating_add(piece.count_addresses());
+                    allowed.push(piece.into());
+                }
+            }
+            SocketAddrRange::V6(range) => {
+                for piece in subtract_v6(excluded_v6, range) {
+                    after = after.saturating_add(piece.count_addresses());
+                    allowed.push(piece.into());
+                }
             }
+        }
+        skipped = skipped.saturating_add(before.saturating_sub(after));
+    }
+    (allowed, skipped)
+}
+
+/// Whether `addr` is excluded, for when addresses can't be subtracted up front
+pub fn is_excluded(addr: &SocketAddr) -> bool {
+    let list = exclude_list();
+    let (ranges, ip) = match addr {
+        SocketAddr::V4(addr) => (&list.ranges, u32::from(*addr.ip()).into()),
+        SocketAddr::V6(addr) => (&list.ipv6_ranges, u128::from(*addr.ip())),
+    };
+    let overlapping = ranges.partition_point(|(_, last)| *last < ip);
+    ranges
+        .get(overlapping)
+        .is_some_and(|(first, _)| *first <= ip)
+}
+
+fn subtract_v4(excluded: &[(u128, u128)], range: SocketAddrV4Range) -> Vec<SocketAddrV4Range> {
+    let first = u32::from(*range.start.ip()).into();
+    let last = u32::from(*range.end.ip()).into();
+    subtract_range(excluded, first, last)
+        .into_iter()
+        .map(|(first, last)| {
+            let mut piece = range.clone();
+            piece.start.set_ip(Ipv4Addr::from(first as u32));
+            piece.end.set_ip(Ipv4Addr::from(last as u32));
+            piece
+        })
+        .collect()
+}
+
+fn subtract_v6(excluded: &[(u128, u128)], range: SocketAddrV6Range) -> Vec<SocketAddrV6Range> {
+    let first = u128::from(*range.start.ip());
+    let last = u128::from(*range.end.ip());
+    subtract_range(excluded, first, last)
+        .into_iter()
+        .map(|(first, last)| {
+            let mut piece = range.clone();
+            piece.start.set_ip(Ipv6Addr::from(first));
+            piece.end.set_ip(Ipv6Addr::from(last));
+            piece
         })
         .collect()
-    };
+}
+
+/// The parts of `first..=last` that aren't in `excluded`, with addresses as
+/// integers so it works for both IPv4 and IPv6
+fn subtract_range(excluded: &[(u128, u128)], first: u128, last: u128) -> Vec<(u128, u128)> {
+    let mut pieces = Vec::new();
+    // the next address that might be allowed, `None` once we're past `last`
+    let mut cursor = Some(first);
+    let overlapping = excluded.partition_point(|(_, excluded_last)| *excluded_last < first);
+    for (excluded_first, excluded_last) in &excluded[overlapping..] {
+        let Some(next) = cursor else {
+            break;
+        };
+        if *excluded_first > last {
+            break;
+        }
+        if *excluded_first > next {
+            pieces.push((next, excluded_first - 1));
+        }
+        cursor = excluded_last.checked_add(1).filter(|next| *next <= last);
+    }
+    if let Some(next) = cursor {
+        pieces.push((next, last));
+    }
+    pieces
 }
 
 #[derive(Debug, Clone)]
@@ -80,11 +313,14 @@ impl Ord for ExcludeEntry {
 }
 
 pub fn is_allowed(ip: Ipv4Addr) -> bool {
-    !EXCLUDE_LIST.contains(&ExcludeEntry::Address(ip))
+    !exclude_list().entries.contains(&ExcludeEntry::Address(ip))
 }
 
 pub fn blocked_range_for_ip(ip: Ipv4Addr) -> Option<ExcludeEntry> {
-    EXCLUDE_LIST.get(&ExcludeEntry::Address(ip)).cloned()
+    exclude_list()
+        .entries
+        .get(&ExcludeEntry::Address(ip))
+        .cloned()
 }
 
 pub fn next_allowed(ip: Ipv4Addr) -> Ipv4Addr {
@@ -102,3 +338,101 @@ pub fn next_allowed(ip: Ipv4Addr) -> Ipv4Addr {
     }
     ip
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::IpAddr;
+
+    fn v4(start: &str, end: &str) -> SocketAddrRange {
+        SocketAddrV4Range::new(start.parse().unwrap(), end.parse().unwrap()).into()
+    }
+
+    fn v6(start: &str, end: &str) -> SocketAddrRange {
+        SocketAddrV6Range::new(start.parse().unwrap(), end.parse().unwrap()).into()
+    }
+
+    fn ip(ip: &str) -> u128 {
+        match ip.parse::<IpAddr>().unwrap() {
+            IpAddr::V4(ip) => u32::from(ip).into(),
+            IpAddr::V6(ip) => ip.into(),
+        }
+    }
+
+    #[test]
+    fn subtracts_overlaps_at_either_end() {
+        assert_eq!(subtract_range(&[(5, 12)], 10, 20), vec![(13, 20)]);
+        assert_eq!(subtract_range(&[(18, 30)], 10, 20), vec![(10, 17)]);
+        assert_eq!(subtract_range(&[(5, 10), (20, 30)], 10, 20), vec![(11, 19)]);
+    }
+
+    #[test]
+    fn subtracts_a_hole_in_the_middle() {
+        assert_eq!(
+            subtract_range(&[(12, 13), (16, 16)], 10, 20),
+            vec![(10, 11), (14, 15), (17, 20)]
+        );
+    }
+
+    #[test]
+    fn subtracts_everything() {
+        assert!(subtract_range(&[(10, 20)], 10, 20).is_empty());
+        assert!(subtract_range(&[(0, u128::MAX)], 10, 20).is_empty());
+        assert!(subtract_range(&[(0, 14), (15, 30)], 10, 20).is_empty());
+    }
+
+    #[test]
+    fn keeps_ranges_next_to_excluded_ones() {
+        assert_eq!(subtract_range(&[(0, 9), (21, 30)], 10, 20), vec![(10, 20)]);
+        assert_eq!(subtract_range(&[], 10, 20), vec![(10, 20)]);
+    }
+
+    #[test]
+    fn merges_overlapping_and_touching_ranges() {
+        assert_eq!(
+            merge(vec![(20, 25), (0, 5), (6, 10), (3, 4), (12, 15), (14, 18)]),
+            vec![(0, 10), (12, 18), (20, 25)]
+        );
+        assert_eq!(
+            merge(vec![(u128::MAX - 1, u128::MAX), (u128::MAX, u128::MAX)]),
+            vec![(u128::MAX - 1, u128::MAX)]
+        );
+    }
+
+    #[test]
+    fn split_pieces_keep_their_ports() {
+        let excluded = [(ip("10.0.0.4"), ip("10.0.0.5"))];
+        let (allowed, skipped) =
+            subtract_from(&excluded, &[], vec![v4("10.0.0.0:25565", "10.0.0.9:25570")]);
+        assert_eq!(
+            allowed,
+            vec![
+                v4("10.0.0.0:25565", "10.0.0.3:25570"),
+                v4("10.0.0.6:25565", "10.0.0.9:25570"),
+            ]
+        );
+        assert_eq!(skipped, 2 * 6);
+    }
+
+    #[test]
+    fn subtracts_ipv6_opt_outs() {
+        let excluded = [(ip("2001:db8::"), ip("2001:db8::ff"))];
+        let (allowed, skipped) = subtract_from(
+            // the ipv4 list doesn't apply to ipv6 ranges
+            &[(0, u128::MAX)],
+            &excluded,
+            vec![
+                v6("[2001:db8::f0]:25565", "[2001:db8::10f]:25566"),
+                v6("[2001:db8:1::]:25565", "[2001:db8:1::]:25565"),
+            ],
+        );
+        assert_eq!(
+            allowed,
+            vec![
+                v6("[2001:db8::100]:25565", "[2001:db8::10f]:25566"),
+                v6("[2001:db8:1::]:25565", "[2001:db8:1::]:25565"),
+            ]
+        );
+        assert_eq!(skipped, 16 * 2);
+    }
+}
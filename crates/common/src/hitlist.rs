@@ -0,0 +1,128 @@
+//! IPv6 hitlists, since the IPv6 address space is far too large to scan.
+//!
+//! One entry per line, anything after a `#` is ignored:
+//! ```text
+//! 2001:db8::1            # scanned on the default port
+//! [2001:db8::2]:25566    # scanned on the given port
+//! 2001:db8:1::/120       # every address in the prefix, on the default port
+//! ```
+
+use crate::network_range::SocketAddrV6Range;
+use std::{
+    net::{Ipv6Addr, SocketAddrV6},
+    path::Path,
+};
+
+/// Prefixes with more host bits than this are rejected, so a prefix has at
+/// most 2^16 addresses
+pub const MAX_PREFIX_HOST_BITS: u32 = 16;
+
+pub fn load(path: impl AsRef<Path>, default_port: u16) -> eyre::Result<Vec<SocketAddrV6Range>> {
+    let file = std::fs::read_to_string(path)?;
+    parse(&file, default_port)
+}
+
+pub fn parse(hitlist: &str, default_port: u16) -> eyre::Result<Vec<SocketAddrV6Range>> {
+    hitlist
+        .lines()
+        .enumerate()
+        .filter_map(|(line_number, line)| {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                None
+            } else {
+                Some(
+                    parse_entry(line, default_port)
+                        .map_err(|err| eyre::eyre!("line {}: {err}", line_number + 1)),
+                )
+            }
+        })
+        .collect()
+}
+
+fn parse_entry(entry: &str, default_port: u16) -> eyre::Result<SocketAddrV6Range> {
+    if let Ok(addr) = entry.parse::<SocketAddrV6>() {
+        return Ok(SocketAddrV6Range::new(addr, addr));
+    }
+    if let Some((ip, prefix_len)) = entry.split_once('/') {
+        let ip = ip.parse::<Ipv6Addr>()?;
+        let prefix_len = prefix_len.parse::<u32>()?;
+        if prefix_len > 128 {
+            return Err(eyre::eyre!("invalid prefix length /{prefix_len}"));
+        }
+        let host_bits = 128 - prefix_len;
+        if host_bits > MAX_PREFIX_HOST_BITS {
+            return Err(eyre::eyre!(
+                "/{prefix_len} is too large, prefixes can be at most /{}",
+                128 - MAX_PREFIX_HOST_BITS
+            ));
+        }
+        let host_mask = (1u128 << host_bits) - 1;
+        let first = u128::from(ip) & !host_mask;
+        return Ok(SocketAddrV6Range::new(
+            SocketAddrV6::new(first.into(), default_port, 0, 0),
+            SocketAddrV6::new((first | host_mask).into(), default_port, 0, 0),
+        ));
+    }
+    let addr = SocketAddrV6::new(entry.parse()?, default_port, 0, 0);
+    Ok(SocketAddrV6Range::new(addr, addr))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PORT: u16 = 25565;
+
+    fn range(start: &str, end: &str, port: u16) -> SocketAddrV6Range {
+        SocketAddrV6Range::new(
+            SocketAddrV6::new(start.parse().unwrap(), port, 0, 0),
+            SocketAddrV6::new(end.parse().unwrap(), port, 0, 0),
+        )
+    }
+
+    #[test]
+    fn parses_every_kind_of_entry() {
+        let hitlist = "\
+            # a comment on its own line\n\
+            2001:db8::1\n\
+            \n\
+            [2001:db8::2]:25566   # a comment after an entry\n\
+            2001:db8:1::17/120\n";
+        assert_eq!(
+            parse(hitlist, PORT).unwrap(),
+            [
+                range("2001:db8::1", "2001:db8::1", PORT),
+                range("2001:db8::2", "2001:db8::2", 25566),
+                range("2001:db8:1::", "2001:db8:1::ff", PORT),
+            ]
+        );
+    }
+
+    #[test]
+    fn accepts_prefixes_up_to_the_limit() {
+        assert_eq!(
+            parse("2001:db8::/112", PORT).unwrap(),
+            [range("2001:db8::", "2001:db8::ffff", PORT)]
+        );
+        assert_eq!(
+            parse("2001:db8::1/128", PORT).unwrap(),
+            [range("2001:db8::1", "2001:db8::1", PORT)]
+        );
+    }
+
+    #[test]
+    fn rejects_larger_prefixes() {
+        let err = parse("2001:db8::/111", PORT).unwrap_err().to_string();
+        assert!(err.contains("/111 is too large"), "{err}");
+        assert!(parse("2001:db8::/129", PORT).is_err());
+    }
+
+    #[test]
+    fn errors_name_the_line() {
+        let err = parse("2001:db8::1\n# comment\nnot an address\n", PORT)
+            .unwrap_err()
+            .to_string();
+        assert!(err.starts_with("line 3: "), "{err}");
+    }
+}
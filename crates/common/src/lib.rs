@@ -1,4 +1,5 @@
 pub mod addr_range;
 pub mod exclude;
+pub mod hitlist;
 pub mod net;
 pub mod network_range;
@@ -0,0 +1,117 @@
+//! Resolving the gateway's MAC address. Packets sent through a raw socket
+//! skip the kernel's neighbour table, so we have to ask ourselves.
+
+use super::{raw_socket::RawSocket, tcp::ETH_HEADER_LEN};
+use pnet::{
+    packet::{
+        arp::{ArpHardwareTypes, ArpOperations, ArpPacket, MutableArpPacket},
+        ethernet::{EtherTypes, EthernetPacket, MutableEthernetPacket},
+        Packet,
+    },
+    util::MacAddr,
+};
+use std::{
+    net::Ipv4Addr,
+    time::{Duration, Instant},
+};
+
+const ARP_ATTEMPTS: u32 = 3;
+const ARP_TIMEOUT: Duration = Duration::from_secs(1);
+const ARP_PACKET_LEN: usize = 28;
+
+/// The IPv4 default gateway of the interface, read from `/proc/net/route`.
+/// Returns `None` if the interface has no default route.
+pub fn default_gateway(interface_name: &str) -> eyre::Result<Option<Ipv4Addr>> {
+    let routes = std::fs::read_to_string("/proc/net/route")?;
+    let mut best: Option<(u32, Ipv4Addr)> = None;
+    // Iface Destination Gateway Flags RefCnt Use Metric Mask ...
+    for line in routes.lines().skip(1) {
+        let fields = line.split_whitespace().collect::<Vec<_>>();
+        if fields.len() < 8 || fields[0] != interface_name {
+            continue;
+        }
+        if fields[1] != "00000000" || fields[7] != "00000000" {
+            continue;
+        }
+        // addresses are printed as native endian hex
+        let gateway = Ipv4Addr::from(u32::from_str_radix(fields[2], 16)?.to_ne_bytes());
+        let metric = fields[6].parse::<u32>()?;
+        if gateway.is_unspecified() {
+            continue;
+        }
+        if best.map_or(true, |(best_metric, _)| metric < best_metric) {
+            best = Some((metric, gateway));
+        }
+    }
+    Ok(best.map(|(_, gateway)| gateway))
+}
+
+/// Ask the link who has `target_ip`, retrying a few times before giving up.
+pub fn resolve(
+    interface_name: &str,
+    interface_mac: MacAddr,
+    source_ip: Ipv4Addr,
+    target_ip: Ipv4Addr,
+) -> eyre::Result<MacAddr> {
+    let mut socket = RawSocket::new(interface_name)?;
+    let request = arp_request(interface_mac, source_ip, target_ip);
+    let mut buffer = [0u8; 1514];
+
+    for attempt in 1..=ARP_ATTEMPTS {
+        socket.send_blocking(&request);
+        let deadline = Instant::now() + ARP_TIMEOUT;
+        while Instant::now() < deadline {
+            let len = match socket.recv(&mut buffer) {
+                Ok(len) => len,
+                Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => {
+                    std::thread::sleep(Duration::from_millis(10));
+                    continue;
+                }
+                Err(err) => return Err(err.into()),
+            };
+            if let Some(mac) = parse_arp_reply(&buffer[..len], target_ip) {
+                return Ok(mac);
+            }
+        }
+        println!("no arp reply from {target_ip} (attempt {attempt}/{ARP_ATTEMPTS})");
+    }
+
+    Err(eyre::eyre!(
+        "gateway {target_ip} didn't answer {ARP_ATTEMPTS} arp requests on {interface_name}"
+    ))
+}
+
+fn arp_request(interface_mac: MacAddr, source_ip: Ipv4Addr, target_ip: Ipv4Addr) -> Vec<u8> {
+    let mut packet = vec![0u8; ETH_HEADER_LEN + ARP_PACKET_LEN];
+
+    let mut ethernet = MutableEthernetPacket::new(&mut packet).unwrap();
+    ethernet.set_destination(MacAddr::broadcast());
+    ethernet.set_source(interface_mac);
+    ethernet.set_ethertype(EtherTypes::Arp);
+
+    let mut arp = MutableArpPacket::new(&mut packet[ETH_HEADER_LEN..]).unwrap();
+    arp.set_hardware_type(ArpHardwareTypes::Ethernet);
+    arp.set_protocol_type(EtherTypes::Ipv4);
+    arp.set_hw_addr_len(6);
+    arp.set_proto_addr_len(4);
+    arp.set_operation(ArpOperations::Request);
+    arp.set_sender_hw_addr(interface_mac);
+    arp.set_sender_proto_addr(source_ip);
+    arp.set_target_hw_addr(MacAddr::zero());
+    arp.set_target_proto_addr(target_ip);
+
+    packet
+}
+
+fn parse_arp_reply(packet: &[u8], target_ip: Ipv4Addr) -> Option<MacAddr> {
+    let ethernet = EthernetPacket::new(packet)?;
+    if ethernet.get_ethertype() != EtherTypes::Arp {
+        return None;
+    }
+    let arp = ArpPacket::new(ethernet.payload())?;
+    if arp.get_operation() == ArpOperations::Reply && arp.get_sender_proto_addr() == target_ip {
+        Some(arp.get_sender_hw_addr())
+    } else {
+        None
+    }
+}
@@ -0,0 +1,225 @@
+//! Keeps the kernel from answering the SYN-ACKs we get with a RST, since it
+//! doesn't know about our connections.
+
+use super::source_port::SourcePort;
+use serde::Deserialize;
+use std::{
+    io::{self, Write},
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream},
+    process::{Command, Stdio},
+    time::Duration,
+};
+
+/// nftables table that holds our rule
+const NFT_TABLE: &str = "snowstorm";
+/// How long a connection to a firewalled port has to hang to count as dropped,
+/// the kernel resets connections on loopback immediately
+const VERIFY_TIMEOUT: Duration = Duration::from_millis(500);
+
+#[derive(Deserialize, Clone, Copy, Default, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Firewall {
+    /// The rule is set up by hand, see the README
+    #[default]
+    Manual,
+    Nftables,
+    Iptables,
+}
+
+/// A rule dropping incoming packets to our source ports, which is removed
+/// again with [`FirewallRule::remove`] or when it's dropped, so returning
+/// early with an error doesn't leave it behind.
+#[derive(Debug)]
+pub struct FirewallRule {
+    firewall: Firewall,
+    source_port: SourcePort,
+    /// The iptables commands we added a rule with, so we don't remove rules
+    /// that were there before us
+    iptables: Vec<&'static str>,
+    removed: bool,
+}
+
+impl FirewallRule {
+    pub fn install(firewall: Firewall, source_port: SourcePort) -> eyre::Result<Self> {
+        let mut rule = FirewallRule {
+            firewall,
+            source_port,
+            iptables: Vec::new(),
+            removed: false,
+        };
+        match firewall {
+            Firewall::Manual => {}
+            Firewall::Nftables => {
+                // declaring the table first makes deleting it work even if it
+                // doesn't exist yet
+                let script = format!(
+                    "table inet {NFT_TABLE} {{}}\n\
+                     delete table inet {NFT_TABLE}\n\
+                     table inet {NFT_TABLE} {{\n\
+                         chain input {{\n\
+                             type filter hook input priority filter - 1; policy accept;\n\
+                             tcp dport {} drop\n\
+                         }}\n\
+                     }}\n",
+                    port_range(source_port, "-")
+                );
+                run("nft", &["-f", "-"], Some(&script))?;
+            }
+            Firewall::Iptables => {
+                for iptables in ["iptables", "ip6tables"] {
+                    if run(iptables, &iptables_rule("-C", source_port), None).is_ok() {
+                        continue;
+                    }
+                    // dropping the rule removes the ipv4 rule again if this fails
+                    run(iptables, &iptables_rule("-I", source_port), None)?;
+                    rule.iptables.push(iptables);
+                }
+            }
+        }
+        if firewall != Firewall::Manual {
+            println!("installed {firewall:?} rule for source port {source_port:?}");
+        }
+        Ok(rule)
+    }
+
+    /// Remove the rule, unlike dropping it this tells us if that failed
+    pub fn remove(mut self) -> eyre::Result<()> {
+        self.removed = true;
+        self.delete()
+    }
+
+    fn delete(&self) -> eyre::Result<()> {
+        match self.firewall {
+            Firewall::Manual => {}
+            Firewall::Nftables => {
+                run("nft", &["delete", "table", "inet", NFT_TABLE], None)?;
+            }
+            Firewall::Iptables => {
+                for iptables in &self.iptables {
+                    run(iptables, &iptables_rule("-D", self.source_port), None)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for FirewallRule {
+    fn drop(&mut self) {
+        if self.removed {
+            return;
+        }
+        if let Err(err) = self.delete() {
+            eprintln!("unable to remove firewall rule: {err}");
+        }
+    }
+}
+
+/// Make sure the kernel drops packets to our source ports instead of resetting
+/// the connection, by connecting to them over loopback.
+pub fn verify(source_port: SourcePort) -> eyre::Result<()> {
+    let ports = match source_port {
+        SourcePort::Number(port) => vec![port],
+        SourcePort::Range { min, max } => vec![min, max],
+    };
+    for port in ports {
+        for ip in [Ipv4Addr::LOCALHOST.into(), Ipv6Addr::LOCALHOST.into()] {
+            let addr = SocketAddr::new(ip, port);
+            match TcpStream::connect_timeout(&addr, VERIFY_TIMEOUT) {
+                Err(err) if err.kind() == io::ErrorKind::TimedOut => {}
+                Err(err) if err.kind() == io::ErrorKind::ConnectionRefused => {
+                    return Err(eyre::eyre!(
+                        "the kernel resets connections to {addr}, so it would reset every \
+                         connection we make. Drop packets to the source port with your \
+                         firewall or set scanner.firewall"
+                    ));
+                }
+                Ok(_) => {
+                    return Err(eyre::eyre!(
+                        "something is listening on {addr}, which is one of our source ports"
+                    ));
+                }
+                // most likely no ipv6 on loopback
+                Err(_) => {}
+            }
+        }
+    }
+    Ok(())
+}
+
+fn port_range(source_port: SourcePort, separator: &str) -> String {
+    match source_port {
+        SourcePort::Number(port) => port.to_string(),
+        SourcePort::Range { min, max } => format!("{min}{separator}{max}"),
+    }
+}
+
+fn iptables_rule(command: &str, source_port: SourcePort) -> Vec<String> {
+    let ports = port_range(source_port, ":");
+    format!("{command} INPUT -p tcp --dport {ports} -j DROP")
+        .split(' ')
+        .map(String::from)
+        .collect()
+}
+
+fn run(program: &str, args: &[impl AsRef<str>], stdin: Option<&str>) -> eyre::Result<()> {
+    let mut child = Command::new(program)
+        .args(args.iter().map(|arg| arg.as_ref()))
+        .stdin(if stdin.is_some() {
+            Stdio::piped()
+        } else {
+            Stdio::null()
+        })
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|err| eyre::eyre!("unable to run {program}: {err}"))?;
+    if let (Some(input), Some(mut child_stdin)) = (stdin, child.stdin.take()) {
+        child_stdin.write_all(input.as_bytes())?;
+    }
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        return Err(eyre::eyre!(
+            "{program} failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_a_single_port() {
+        let port = SourcePort::Number(61000);
+        assert_eq!(port_range(port, "-"), "61000");
+        assert_eq!(
+            iptables_rule("-I", port),
+            ["-I", "INPUT", "-p", "tcp", "--dport", "61000", "-j", "DROP"]
+        );
+    }
+
+    #[test]
+    fn formats_a_port_range() {
+        let ports = SourcePort::Range {
+            min: 61000,
+            max: 61999,
+        };
+        assert_eq!(port_range(ports, "-"), "61000-61999");
+        assert_eq!(
+            iptables_rule("-D", ports),
+            [
+                "-D",
+                "INPUT",
+                "-p",
+                "tcp",
+                "--dport",
+                "61000:61999",
+                "-j",
+                "DROP"
+            ]
+        );
+    }
+}
@@ -1,4 +1,11 @@
+pub mod arp;
+pub mod firewall;
+pub mod packet_ring;
+pub mod pcap;
+pub mod rate_limit;
 pub mod raw_socket;
+pub mod replay;
+pub mod source_ip;
 pub mod source_port;
 pub mod tcp;
 pub mod tcp_template;
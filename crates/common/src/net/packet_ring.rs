@@ -0,0 +1,563 @@
+//! Memory mapped `PACKET_RX_RING` receiver with a kernel side BPF filter, see
+//! https://www.kernel.org/doc/Documentation/networking/packet_mmap.txt
+
+use super::{raw_socket::ETH_P_ALL, source_port::SourcePort};
+use std::{
+    io, mem, ptr,
+    sync::atomic::{fence, Ordering},
+    time::{Duration, Instant},
+};
+
+const SOL_PACKET: libc::c_int = 263;
+const PACKET_RX_RING: libc::c_int = 5;
+const PACKET_VERSION: libc::c_int = 10;
+const TPACKET_V2: libc::c_int = 1;
+const SO_ATTACH_FILTER: libc::c_int = 26;
+
+const TP_STATUS_KERNEL: u32 = 0;
+const TP_STATUS_USER: u32 = 1;
+
+/// Room left in every frame for the `tpacket2_hdr` and `sockaddr_ll`
+const FRAME_HEADER_SPACE: usize = 128;
+const BLOCK_SIZE: usize = 1 << 20;
+const BLOCK_COUNT: usize = 64;
+/// Longest we wait in `poll` before checking the next frame again, so a
+/// missed wakeup doesn't stall the receiver
+const POLL_TIMEOUT_MS: libc::c_int = 100;
+
+#[allow(dead_code)]
+#[repr(C)]
+struct tpacket_req {
+    tp_block_size: libc::c_uint,
+    tp_block_nr: libc::c_uint,
+    tp_frame_size: libc::c_uint,
+    tp_frame_nr: libc::c_uint,
+}
+
+#[allow(dead_code)]
+#[repr(C)]
+struct tpacket2_hdr {
+    tp_status: u32,
+    tp_len: u32,
+    tp_snaplen: u32,
+    tp_mac: u16,
+    tp_net: u16,
+    tp_sec: u32,
+    tp_nsec: u32,
+    tp_vlan_tci: u16,
+    tp_vlan_tpid: u16,
+    tp_padding: [u8; 4],
+}
+
+#[allow(dead_code)]
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct sock_filter {
+    code: u16,
+    jt: u8,
+    jf: u8,
+    k: u32,
+}
+
+#[allow(dead_code)]
+#[repr(C)]
+struct sock_fprog {
+    len: libc::c_ushort,
+    filter: *const sock_filter,
+}
+
+pub struct PacketRing {
+    lower: libc::c_int,
+    ring: *mut u8,
+    ring_size: usize,
+    frame_size: usize,
+    frame_count: usize,
+    /// Index of the next frame the kernel will fill
+    current_frame: usize,
+}
+
+// the ring is only ever accessed through `&mut self`
+unsafe impl Send for PacketRing {}
+
+impl PacketRing {
+    /// Create a receive ring on the interface that only receives packets
+    /// matching `filter`.
+    pub fn new(
+        interface_index: libc::c_int,
+        mtu: usize,
+        filter: &[sock_filter],
+    ) -> io::Result<Self> {
+        let lower = unsafe {
+            let lower = libc::socket(libc::AF_PACKET, libc::SOCK_RAW, ETH_P_ALL.to_be() as i32);
+            if lower == -1 {
+                return Err(io::Error::last_os_error());
+            }
+            lower
+        };
+        // close the socket if anything below fails
+        let mut ring = PacketRing {
+            lower,
+            ring: ptr::null_mut(),
+            ring_size: 0,
+            frame_size: 0,
+            frame_count: 0,
+            current_frame: 0,
+        };
+
+        // the filter has to be attached before binding, otherwise packets that
+        // arrive in between skip it
+        let program = sock_fprog {
+            len: filter.len() as libc::c_ushort,
+            filter: filter.as_ptr(),
+        };
+        setsockopt(lower, libc::SOL_SOCKET, SO_ATTACH_FILTER, &program)?;
+        setsockopt(lower, SOL_PACKET, PACKET_VERSION, &TPACKET_V2)?;
+
+        let frame_size = (mtu + FRAME_HEADER_SPACE).next_power_of_two();
+        let frame_count = BLOCK_SIZE / frame_size * BLOCK_COUNT;
+        let request = tpacket_req {
+            tp_block_size: BLOCK_SIZE as libc::c_uint,
+            tp_block_nr: BLOCK_COUNT as libc::c_uint,
+            tp_frame_size: frame_size as libc::c_uint,
+            tp_frame_nr: frame_count as libc::c_uint,
+        };
+        setsockopt(lower, SOL_PACKET, PACKET_RX_RING, &request)?;
+
+        let ring_size = BLOCK_SIZE * BLOCK_COUNT;
+        let map = |flags| unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                ring_size,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED | flags,
+                lower,
+                0,
+            )
+        };
+        let mut mapped = map(libc::MAP_LOCKED);
+        if mapped == libc::MAP_FAILED {
+            // usually RLIMIT_MEMLOCK being lower than the ring, the ring still
+            // works if it's swapped out, just slower
+            eprintln!(
+                "unable to lock the receive ring in memory ({}), mapping it unlocked",
+                io::Error::last_os_error()
+            );
+            mapped = map(0);
+        }
+        if mapped == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        ring.ring = mapped as *mut u8;
+        ring.ring_size = ring_size;
+        ring.frame_size = frame_size;
+        ring.frame_count = frame_count;
+
+        let sockaddr = libc::sockaddr_ll {
+            sll_family: libc::AF_PACKET as u16,
+            sll_protocol: ETH_P_ALL.to_be() as u16,
+            sll_ifindex: interface_index,
+            sll_hatype: 0,
+            sll_pkttype: 0,
+            sll_halen: 0,
+            sll_addr: [0; 8],
+        };
+        unsafe {
+            let res = libc::bind(
+                lower,
+                &sockaddr as *const libc::sockaddr_ll as *const libc::sockaddr,
+                mem::size_of::<libc::sockaddr_ll>() as libc::socklen_t,
+            );
+            if res == -1 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+
+        Ok(ring)
+    }
+
+    fn frame_header(&self, index: usize) -> *mut tpacket2_hdr {
+        unsafe { self.ring.add(index * self.frame_size) as *mut tpacket2_hdr }
+    }
+
+    /// Wait up to `timeout` for the next packet and pass it to `f`, starting at
+    /// the link layer header. The packet is handed back to the kernel once `f`
+    /// returns. `None` if no packet came in.
+    pub fn recv_with<T>(
+        &mut self,
+        timeout: Duration,
+        f: impl FnOnce(&[u8]) -> T,
+    ) -> io::Result<Option<T>> {
+        let header = self.frame_header(self.current_frame);
+        let deadline = Instant::now() + timeout;
+        loop {
+            let status = unsafe { ptr::read_volatile(ptr::addr_of!((*header).tp_status)) };
+            if status & TP_STATUS_USER != 0 {
+                break;
+            }
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Ok(None);
+            }
+            let mut poll_fd = libc::pollfd {
+                fd: self.lower,
+                events: libc::POLLIN | libc::POLLERR,
+                revents: 0,
+            };
+            // rounded up so we don't spin through the last millisecond
+            let poll_timeout = remaining
+                .as_micros()
+                .div_ceil(1000)
+                .min(POLL_TIMEOUT_MS as u128) as libc::c_int;
+            if unsafe { libc::poll(&mut poll_fd, 1, poll_timeout) } == -1 {
+                let err = io::Error::last_os_error();
+                if err.kind() != io::ErrorKind::Interrupted {
+                    return Err(err);
+                }
+            }
+        }
+        fence(Ordering::Acquire);
+
+        let res = unsafe {
+            let packet = std::slice::from_raw_parts(
+                (header as *const u8).add((*header).tp_mac as usize),
+                (*header).tp_snaplen as usize,
+            );
+            f(packet)
+        };
+
+        fence(Ordering::Release);
+        unsafe { ptr::write_volatile(ptr::addr_of_mut!((*header).tp_status), TP_STATUS_KERNEL) };
+        self.current_frame = (self.current_frame + 1) % self.frame_count;
+
+        Ok(Some(res))
+    }
+}
+
+impl Drop for PacketRing {
+    fn drop(&mut self) {
+        unsafe {
+            if !self.ring.is_null() {
+                libc::munmap(self.ring as *mut libc::c_void, self.ring_size);
+            }
+            libc::close(self.lower);
+        }
+    }
+}
+
+fn setsockopt<T>(
+    lower: libc::c_int,
+    level: libc::c_int,
+    name: libc::c_int,
+    value: &T,
+) -> io::Result<()> {
+    unsafe {
+        let res = libc::setsockopt(
+            lower,
+            level,
+            name,
+            value as *const T as *const libc::c_void,
+            mem::size_of::<T>() as libc::socklen_t,
+        );
+        if res == -1 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+// classic BPF opcodes
+const BPF_LD_H_ABS: u16 = 0x28;
+const BPF_LD_B_ABS: u16 = 0x30;
+const BPF_LD_H_IND: u16 = 0x48;
+const BPF_LDX_B_MSH: u16 = 0xb1;
+const BPF_AND_K: u16 = 0x54;
+const BPF_JEQ_K: u16 = 0x15;
+const BPF_JGT_K: u16 = 0x25;
+const BPF_JGE_K: u16 = 0x35;
+const BPF_JSET_K: u16 = 0x45;
+const BPF_RET_K: u16 = 0x06;
+
+const IPV6_HEADER_LEN: u32 = 40;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Label {
+    Ipv6,
+    Accept,
+    Drop,
+}
+
+#[derive(Clone, Copy)]
+enum Jump {
+    Next,
+    To(Label),
+}
+
+enum Instruction {
+    Op(u16, u32, Jump, Jump),
+    Label(Label),
+}
+
+/// BPF program that only accepts unfragmented TCP packets sent to one of our
+/// source ports, over IPv4 or IPv6.
+pub fn tcp_port_filter(eth_header_len: usize, source_port: SourcePort) -> Vec<sock_filter> {
+    use Instruction::{Label as L, Op};
+    use Jump::{Next, To};
+    use Label::*;
+
+    let (min_port, max_port) = match source_port {
+        SourcePort::Number(port) => (port, port),
+        SourcePort::Range { min, max } => (min, max),
+    };
+    let eth = eth_header_len as u32;
+
+    let mut program = Vec::new();
+    if eth_header_len > 0 {
+        // A = ethertype
+        program.push(Op(BPF_LD_H_ABS, 12, Next, Next));
+        program.push(Op(BPF_JEQ_K, 0x0800, Next, To(Ipv6)));
+    } else {
+        // no ethernet header, so A = ip version
+        program.push(Op(BPF_LD_B_ABS, 0, Next, Next));
+        program.push(Op(BPF_AND_K, 0xf0, Next, Next));
+        program.push(Op(BPF_JEQ_K, 0x40, Next, To(Ipv6)));
+    }
+    program.extend([
+        // ipv4: protocol == tcp
+        Op(BPF_LD_B_ABS, eth + 9, Next, Next),
+        Op(BPF_JEQ_K, 6, Next, To(Drop)),
+        // not a fragment
+        Op(BPF_LD_H_ABS, eth + 6, Next, Next),
+        Op(BPF_JSET_K, 0x1fff, To(Drop), Next),
+        // x = ipv4 header length, then load the tcp destination port
+        Op(BPF_LDX_B_MSH, eth, Next, Next),
+        Op(BPF_LD_H_IND, eth + 2, Next, Next),
+        // min_port <= port <= max_port
+        Op(BPF_JGE_K, min_port as u32, Next, To(Drop)),
+        Op(BPF_JGT_K, max_port as u32, To(Drop), To(Accept)),
+        // ipv6, A is still the ethertype or ip version
+        L(Ipv6),
+        Op(
+            BPF_JEQ_K,
+            if eth_header_len > 0 { 0x86dd } else { 0x60 },
+            Next,
+            To(Drop),
+        ),
+        // next header == tcp, extension headers aren't supported
+        Op(BPF_LD_B_ABS, eth + 6, Next, Next),
+        Op(BPF_JEQ_K, 6, Next, To(Drop)),
+        Op(BPF_LD_H_ABS, eth + IPV6_HEADER_LEN + 2, Next, Next),
+        Op(BPF_JGE_K, min_port as u32, Next, To(Drop)),
+        Op(BPF_JGT_K, max_port as u32, To(Drop), To(Accept)),
+        // accept the whole packet
+        L(Accept),
+        Op(BPF_RET_K, u32::MAX, Next, Next),
+        L(Drop),
+        Op(BPF_RET_K, 0, Next, Next),
+    ]);
+
+    let mut labels = Vec::new();
+    let mut index = 0;
+    for instruction in &program {
+        match instruction {
+            Op(..) => index += 1,
+            L(label) => labels.push((*label, index)),
+        }
+    }
+    let label_index = |label| {
+        labels
+            .iter()
+            .find(|(l, _)| *l == label)
+            .map(|(_, index)| *index)
+            .unwrap()
+    };
+
+    program
+        .into_iter()
+        .filter_map(|instruction| match instruction {
+            Op(code, k, jt, jf) => Some((code, k, jt, jf)),
+            L(_) => None,
+        })
+        .enumerate()
+        .map(|(index, (code, k, jt, jf))| {
+            // jumps are relative to the next instruction
+            let offset = |jump| match jump {
+                Next => 0,
+                To(label) => (label_index(label) - index - 1) as u8,
+            };
+            sock_filter {
+                code,
+                jt: offset(jt),
+                jf: offset(jf),
+                k,
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ETH_HEADER_LEN: usize = 14;
+    const TCP: u8 = 6;
+    const UDP: u8 = 17;
+    const IPV6_FRAGMENT: u8 = 44;
+
+    /// Run `program` over `packet` like the kernel would, returning how many
+    /// bytes it keeps. `None` if it read past the end of the packet, which
+    /// drops it.
+    fn run(program: &[sock_filter], packet: &[u8]) -> Option<u32> {
+        let byte = |offset: u32| packet.get(offset as usize).copied().map(u32::from);
+        let half = |offset: u32| Some((byte(offset)? << 8) | byte(offset + 1)?);
+
+        let (mut a, mut x) = (0u32, 0u32);
+        let mut pc = 0;
+        loop {
+            let sock_filter { code, jt, jf, k } = program[pc];
+            pc += 1;
+            let jump = |taken: bool| (if taken { jt } else { jf }) as usize;
+            match code {
+                BPF_LD_H_ABS => a = half(k)?,
+                BPF_LD_B_ABS => a = byte(k)?,
+                BPF_LD_H_IND => a = half(x + k)?,
+                BPF_LDX_B_MSH => x = (byte(k)? & 0xf) * 4,
+                BPF_AND_K => a &= k,
+                BPF_JEQ_K => pc += jump(a == k),
+                BPF_JGT_K => pc += jump(a > k),
+                BPF_JGE_K => pc += jump(a >= k),
+                BPF_JSET_K => pc += jump((a & k) != 0),
+                BPF_RET_K => return Some(k),
+                _ => panic!("unknown opcode {code:#x}"),
+            }
+        }
+    }
+
+    fn ethernet(eth: bool, ethertype: u16, ip: Vec<u8>) -> Vec<u8> {
+        if !eth {
+            return ip;
+        }
+        let mut frame = vec![0; ETH_HEADER_LEN];
+        frame[12..14].copy_from_slice(&ethertype.to_be_bytes());
+        frame.extend(ip);
+        frame
+    }
+
+    fn tcp_header(port: u16) -> Vec<u8> {
+        let mut header = vec![0; 20];
+        header[0..2].copy_from_slice(&25565u16.to_be_bytes());
+        header[2..4].copy_from_slice(&port.to_be_bytes());
+        header
+    }
+
+    /// An IPv4 packet with `options` extra words in its header
+    fn ipv4(eth: bool, protocol: u8, fragment: u16, options: usize, port: u16) -> Vec<u8> {
+        let mut packet = vec![0; 20 + options * 4];
+        packet[0] = 0x45 + options as u8;
+        packet[6..8].copy_from_slice(&fragment.to_be_bytes());
+        packet[9] = protocol;
+        packet.extend(tcp_header(port));
+        ethernet(eth, 0x0800, packet)
+    }
+
+    fn ipv6(eth: bool, next_header: u8, port: u16) -> Vec<u8> {
+        let mut packet = vec![0; IPV6_HEADER_LEN as usize];
+        packet[0] = 0x60;
+        packet[6] = next_header;
+        packet.extend(tcp_header(port));
+        ethernet(eth, 0x86dd, packet)
+    }
+
+    fn accepts(program: &[sock_filter], packet: &[u8]) -> bool {
+        match run(program, packet).unwrap_or(0) {
+            0 => false,
+            u32::MAX => true,
+            kept => panic!("only kept {kept} bytes"),
+        }
+    }
+
+    fn filter(eth: bool, source_port: SourcePort) -> Vec<sock_filter> {
+        tcp_port_filter(if eth { ETH_HEADER_LEN } else { 0 }, source_port)
+    }
+
+    #[test]
+    fn accepts_a_single_port() {
+        for eth in [true, false] {
+            let program = filter(eth, SourcePort::Number(61000));
+            for (port, accepted) in [(61000, true), (60999, false), (61001, false)] {
+                assert_eq!(accepts(&program, &ipv4(eth, TCP, 0, 0, port)), accepted);
+                assert_eq!(accepts(&program, &ipv6(eth, TCP, port)), accepted);
+            }
+        }
+    }
+
+    #[test]
+    fn accepts_a_port_range() {
+        for eth in [true, false] {
+            let program = filter(
+                eth,
+                SourcePort::Range {
+                    min: 61000,
+                    max: 61010,
+                },
+            );
+            for (port, accepted) in [
+                (61000, true),
+                (61005, true),
+                (61010, true),
+                (60999, false),
+                (61011, false),
+            ] {
+                assert_eq!(accepts(&program, &ipv4(eth, TCP, 0, 0, port)), accepted);
+                assert_eq!(accepts(&program, &ipv6(eth, TCP, port)), accepted);
+            }
+        }
+    }
+
+    #[test]
+    fn skips_ipv4_options() {
+        for eth in [true, false] {
+            let program = filter(eth, SourcePort::Number(61000));
+            assert!(accepts(&program, &ipv4(eth, TCP, 0, 2, 61000)));
+            assert!(!accepts(&program, &ipv4(eth, TCP, 0, 2, 61001)));
+        }
+    }
+
+    #[test]
+    fn rejects_other_protocols() {
+        for eth in [true, false] {
+            let program = filter(eth, SourcePort::Number(61000));
+            assert!(!accepts(&program, &ipv4(eth, UDP, 0, 0, 61000)));
+            assert!(!accepts(&program, &ipv6(eth, UDP, 61000)));
+        }
+        // arp
+        let program = filter(true, SourcePort::Number(61000));
+        assert!(!accepts(&program, &ethernet(true, 0x0806, vec![0; 28])));
+    }
+
+    #[test]
+    fn rejects_fragments() {
+        for eth in [true, false] {
+            let program = filter(eth, SourcePort::Number(61000));
+            // the first fragment has the tcp header, the rest don't
+            let more_fragments = 0x2000;
+            assert!(accepts(&program, &ipv4(eth, TCP, more_fragments, 0, 61000)));
+            assert!(!accepts(&program, &ipv4(eth, TCP, 1, 0, 61000)));
+            assert!(!accepts(&program, &ipv4(eth, TCP, 0x1fff, 0, 61000)));
+            // an ipv6 fragment header, which we don't look past
+            assert!(!accepts(&program, &ipv6(eth, IPV6_FRAGMENT, 61000)));
+        }
+    }
+
+    #[test]
+    fn jumps_stay_in_the_program() {
+        let program = filter(true, SourcePort::Number(61000));
+        for (index, instruction) in program.iter().enumerate() {
+            if instruction.code == BPF_RET_K {
+                continue;
+            }
+            let furthest = index + 1 + instruction.jt.max(instruction.jf) as usize;
+            assert!(furthest < program.len(), "{index}: {instruction:?}");
+        }
+        assert_eq!(program.last().unwrap().code, BPF_RET_K);
+    }
+}
@@ -0,0 +1,143 @@
+//! Reading and writing libpcap capture files, the format `tcpdump -w` writes
+//! and wireshark reads. pcapng isn't supported.
+
+use std::{
+    io::{self, Read, Write},
+    time::Duration,
+};
+
+pub const LINKTYPE_ETHERNET: u32 = 1;
+/// Packets start with their IP header
+pub const LINKTYPE_RAW: u32 = 101;
+/// What `tcpdump -i any` captures
+pub const LINKTYPE_LINUX_SLL: u32 = 113;
+
+const MAGIC_MICROS: u32 = 0xa1b2c3d4;
+const MAGIC_NANOS: u32 = 0xa1b23c4d;
+const SNAPLEN: u32 = 65535;
+
+pub struct PcapReader<R> {
+    reader: R,
+    /// Whether the file was written on a machine with the other endianness
+    swapped: bool,
+    nanos: bool,
+    linktype: u32,
+}
+
+impl<R: Read> PcapReader<R> {
+    pub fn new(mut reader: R) -> eyre::Result<Self> {
+        let mut header = [0; 24];
+        reader.read_exact(&mut header)?;
+        let magic = u32::from_le_bytes(header[0..4].try_into().unwrap());
+        let (swapped, nanos) = match magic {
+            MAGIC_MICROS => (false, false),
+            MAGIC_NANOS => (false, true),
+            _ if magic.swap_bytes() == MAGIC_MICROS => (true, false),
+            _ if magic.swap_bytes() == MAGIC_NANOS => (true, true),
+            _ => return Err(eyre::eyre!("not a pcap file (magic {magic:#010x})")),
+        };
+        let mut capture = Self {
+            reader,
+            swapped,
+            nanos,
+            linktype: 0,
+        };
+        capture.linktype = capture.u32(&header[20..24]);
+        match capture.linktype {
+            LINKTYPE_ETHERNET | LINKTYPE_RAW | LINKTYPE_LINUX_SLL => Ok(capture),
+            linktype => Err(eyre::eyre!("unsupported pcap link type {linktype}")),
+        }
+    }
+
+    fn u32(&self, bytes: &[u8]) -> u32 {
+        let value = u32::from_le_bytes(bytes.try_into().unwrap());
+        if self.swapped {
+            value.swap_bytes()
+        } else {
+            value
+        }
+    }
+
+    /// The next frame and when it was captured, or `None` at the end of the
+    /// file
+    pub fn next_frame(&mut self) -> eyre::Result<Option<(Duration, Vec<u8>)>> {
+        let mut header = [0; 16];
+        match self.reader.read_exact(&mut header) {
+            Ok(()) => {}
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(err) => return Err(err.into()),
+        }
+        let seconds = self.u32(&header[0..4]);
+        let fraction = self.u32(&header[4..8]);
+        let length = self.u32(&header[8..12]);
+        let timestamp = if self.nanos {
+            Duration::new(seconds as u64, fraction)
+        } else {
+            Duration::new(seconds as u64, 0) + Duration::from_micros(fraction as u64)
+        };
+        let mut frame = vec![0; length as usize];
+        self.reader.read_exact(&mut frame)?;
+        Ok(Some((timestamp, frame)))
+    }
+
+    /// The IP packet in a frame, if it has one
+    pub fn ip_packet<'a>(&self, frame: &'a [u8]) -> Option<&'a [u8]> {
+        const ETHERTYPE_IPV4: u16 = 0x0800;
+        const ETHERTYPE_IPV6: u16 = 0x86dd;
+        const ETHERTYPE_VLAN: u16 = 0x8100;
+
+        let (ethertype, payload) = match self.linktype {
+            LINKTYPE_RAW => return Some(frame),
+            LINKTYPE_ETHERNET => {
+                let ethertype = u16::from_be_bytes(frame.get(12..14)?.try_into().unwrap());
+                if ethertype == ETHERTYPE_VLAN {
+                    let ethertype = u16::from_be_bytes(frame.get(16..18)?.try_into().unwrap());
+                    (ethertype, frame.get(18..)?)
+                } else {
+                    (ethertype, frame.get(14..)?)
+                }
+            }
+            LINKTYPE_LINUX_SLL => (
+                u16::from_be_bytes(frame.get(14..16)?.try_into().unwrap()),
+                frame.get(16..)?,
+            ),
+            _ => return None,
+        };
+        matches!(ethertype, ETHERTYPE_IPV4 | ETHERTYPE_IPV6).then_some(payload)
+    }
+}
+
+/// Writes raw IP packets
+pub struct PcapWriter<W: Write> {
+    writer: W,
+}
+
+impl<W: Write> PcapWriter<W> {
+    pub fn new(mut writer: W) -> io::Result<Self> {
+        let mut header = Vec::with_capacity(24);
+        header.extend_from_slice(&MAGIC_MICROS.to_le_bytes());
+        header.extend_from_slice(&2u16.to_le_bytes());
+        header.extend_from_slice(&4u16.to_le_bytes());
+        // timezone and timestamp accuracy, always 0
+        header.extend_from_slice(&[0; 8]);
+        header.extend_from_slice(&SNAPLEN.to_le_bytes());
+        header.extend_from_slice(&LINKTYPE_RAW.to_le_bytes());
+        writer.write_all(&header)?;
+        Ok(Self { writer })
+    }
+
+    /// Write a packet captured `timestamp` after the unix epoch
+    pub fn write_packet(&mut self, timestamp: Duration, packet: &[u8]) -> io::Result<()> {
+        let mut header = Vec::with_capacity(16);
+        header.extend_from_slice(&(timestamp.as_secs() as u32).to_le_bytes());
+        header.extend_from_slice(&timestamp.subsec_micros().to_le_bytes());
+        header.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+        header.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+        self.writer.write_all(&header)?;
+        self.writer.write_all(packet)
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
@@ -0,0 +1,113 @@
+use serde::{Deserialize, Serialize};
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RateLimit {
+    /// Packets per second, 0 means unlimited
+    pub max_pps: u64,
+    /// How many packets can be sent at once after being idle
+    pub burst_size: u64,
+}
+
+/// Token bucket limiting how many packets are sent per second.
+///
+/// Clones share the same bucket, so the limit can be changed while the
+/// scanner is running.
+#[derive(Clone)]
+pub struct RateLimiter {
+    bucket: Arc<Mutex<TokenBucket>>,
+}
+
+struct TokenBucket {
+    limit: RateLimit,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(limit: RateLimit) -> Self {
+        Self {
+            bucket: Arc::new(Mutex::new(TokenBucket {
+                limit,
+                tokens: limit.burst_size as f64,
+                last_refill: Instant::now(),
+            })),
+        }
+    }
+
+    pub fn unlimited() -> Self {
+        Self::new(RateLimit {
+            max_pps: 0,
+            burst_size: 0,
+        })
+    }
+
+    pub fn limit(&self) -> RateLimit {
+        self.bucket.lock().unwrap().limit
+    }
+
+    pub fn set_limit(&self, limit: RateLimit) {
+        let mut bucket = self.bucket.lock().unwrap();
+        bucket.refill();
+        bucket.limit = limit;
+        bucket.tokens = bucket.tokens.min(limit.burst_size.max(1) as f64);
+    }
+
+    /// Wait until a packet is allowed to be sent.
+    pub async fn acquire(&self) {
+        loop {
+            let wait = self.bucket.lock().unwrap().take();
+            match wait {
+                Some(duration) => tokio::time::sleep(duration).await,
+                None => return,
+            }
+        }
+    }
+
+    /// Block the thread until a packet is allowed to be sent, for use outside
+    /// of the runtime.
+    pub fn blocking_acquire(&self) {
+        loop {
+            let wait = self.bucket.lock().unwrap().take();
+            match wait {
+                Some(duration) => std::thread::sleep(duration),
+                None => return,
+            }
+        }
+    }
+}
+
+impl TokenBucket {
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = (now - self.last_refill).as_secs_f64();
+        self.last_refill = now;
+        self.tokens = (self.tokens + elapsed * self.limit.max_pps as f64)
+            .min(self.limit.burst_size.max(1) as f64);
+    }
+
+    /// Take a token, or return how long to wait until one is available.
+    fn take(&mut self) -> Option<Duration> {
+        if self.limit.max_pps == 0 {
+            return None;
+        }
+        self.refill();
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            None
+        } else {
+            Some(Duration::from_secs_f64(
+                (1.0 - self.tokens) / self.limit.max_pps as f64,
+            ))
+        }
+    }
+}
+
+impl Default for RateLimiter {
+    fn default() -> Self {
+        Self::unlimited()
+    }
+}
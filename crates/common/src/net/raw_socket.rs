@@ -40,6 +40,8 @@ pub const SIOCGIFMTU: libc::c_ulong = 0x8921;
 pub const SIOCGIFINDEX: libc::c_ulong = 0x8933;
 pub const ETH_P_ALL: libc::c_short = 0x0003;
 pub const ETH_P_IEEE802154: libc::c_short = 0x00F6;
+/// `UIO_MAXIOV`, the most messages `sendmmsg` will send in one call
+pub const MAX_BATCH_SIZE: usize = 1024;
 
 #[derive(Debug)]
 pub struct RawSocket {
@@ -150,6 +152,61 @@ impl RawSocket {
             }
         }
     }
+
+    /// Send up to [`MAX_BATCH_SIZE`] packets with a single `sendmmsg` call,
+    /// returning how many were sent.
+    pub fn send_batch<T: AsRef<[u8]>>(&mut self, buffers: &[T]) -> io::Result<usize> {
+        let buffers = &buffers[..buffers.len().min(MAX_BATCH_SIZE)];
+        let mut iovecs = buffers
+            .iter()
+            .map(|buffer| {
+                let buffer = buffer.as_ref();
+                libc::iovec {
+                    iov_base: buffer.as_ptr() as *mut libc::c_void,
+                    iov_len: buffer.len(),
+                }
+            })
+            .collect::<Vec<_>>();
+        let mut messages = iovecs
+            .iter_mut()
+            .map(|iovec| {
+                // the socket is bound to the interface, so no address is needed
+                let mut msg_hdr: libc::msghdr = unsafe { mem::zeroed() };
+                msg_hdr.msg_iov = iovec;
+                msg_hdr.msg_iovlen = 1;
+                libc::mmsghdr {
+                    msg_hdr,
+                    msg_len: 0,
+                }
+            })
+            .collect::<Vec<_>>();
+
+        unsafe {
+            let sent = libc::sendmmsg(
+                self.lower,
+                messages.as_mut_ptr(),
+                messages.len() as libc::c_uint,
+                0,
+            );
+            if sent == -1 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(sent as usize)
+        }
+    }
+
+    pub fn send_batch_blocking<T: AsRef<[u8]>>(&mut self, buffers: &[T]) {
+        let mut buffers = buffers;
+        while !buffers.is_empty() {
+            match self.send_batch(buffers) {
+                Ok(sent) => buffers = &buffers[sent..],
+                Err(err) => match err.kind() {
+                    std::io::ErrorKind::WouldBlock => {}
+                    e => panic!("error sending packets: {:?}", e),
+                },
+            }
+        }
+    }
 }
 
 impl Clone for RawSocket {
@@ -0,0 +1,270 @@
+//! A fake network that answers our packets with the server side of a
+//! recorded capture, so the handshake, reassembly and parsing run exactly like
+//! they would on a real network.
+//!
+//! When we send a SYN to a server the next recorded connection to it is
+//! replayed. Its server packets are rewritten to acknowledge our sequence
+//! numbers instead of the recorded ones, and each is sent once we've sent as
+//! many packets with a SYN, FIN or payload as the recorded client had before
+//! it. Pure ACKs aren't counted since how many there are depends on timing.
+//! This only lines up with a capture of snowstorm itself, e.g.
+//! `tcpdump -w scan.pcap tcp port 61000`.
+
+use super::{
+    pcap::{PcapReader, PcapWriter},
+    tcp::{build_tcp_packet, parse_ip_packet, PacketRepr},
+};
+use pnet::packet::tcp::{Tcp, TcpFlags};
+use std::{
+    collections::{HashMap, VecDeque},
+    fs::File,
+    io::{self, BufReader, BufWriter},
+    net::SocketAddr,
+    path::Path,
+    sync::{mpsc, Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// TTL of the packets we replay
+const REPLAY_TTL: u8 = 64;
+
+/// A recorded connection, from the server's side
+struct Connection {
+    client_sequence: u32,
+    /// Server packets and how many packets the client counted before each
+    packets: VecDeque<(usize, Tcp)>,
+}
+
+/// A recorded connection being replayed to one of our connections
+struct Replaying {
+    connection: Connection,
+    our_sequence: u32,
+    sent: usize,
+}
+
+struct Replay {
+    /// Connections that weren't replayed yet, by server
+    recorded: HashMap<SocketAddr, VecDeque<Connection>>,
+    /// By server and our address
+    replaying: HashMap<(SocketAddr, SocketAddr), Replaying>,
+    output: Option<PcapWriter<BufWriter<File>>>,
+}
+
+/// Sends our packets to the replay, cloned for every thread that sends
+#[derive(Clone)]
+pub struct ReplaySender {
+    replay: Arc<Mutex<Replay>>,
+    responses: mpsc::Sender<Vec<u8>>,
+}
+
+pub struct ReplayReceiver {
+    responses: mpsc::Receiver<Vec<u8>>,
+}
+
+pub struct ReplayNetwork {
+    pub sender: ReplaySender,
+    pub receiver: ReplayReceiver,
+    /// Every server in the capture, in the order they were first contacted
+    pub servers: Vec<SocketAddr>,
+}
+
+impl ReplayNetwork {
+    /// Replay the server side of `capture`. Every packet we send and get is
+    /// written to `output` if it's set.
+    pub fn open(capture: &Path, output: Option<&Path>) -> eyre::Result<Self> {
+        let mut reader = PcapReader::new(BufReader::new(File::open(capture)?))?;
+        let mut recorded: HashMap<SocketAddr, VecDeque<Connection>> = HashMap::new();
+        let mut servers = Vec::new();
+        // the connection every (client, server) pair is on and how many
+        // packets the client counted
+        let mut open: HashMap<(SocketAddr, SocketAddr), (usize, usize)> = HashMap::new();
+
+        while let Some((_, frame)) = reader.next_frame()? {
+            let Some((source_ip, destination_ip, tcp)) =
+                reader.ip_packet(&frame).and_then(parse_ip_packet)
+            else {
+                continue;
+            };
+            let source = SocketAddr::new(source_ip, tcp.source);
+            let destination = SocketAddr::new(destination_ip, tcp.destination);
+
+            if tcp.flags & (TcpFlags::SYN | TcpFlags::ACK) == TcpFlags::SYN {
+                // a retransmitted SYN is the same connection
+                if open.contains_key(&(source, destination)) {
+                    continue;
+                }
+                if !recorded.contains_key(&destination) {
+                    servers.push(destination);
+                }
+                let connections = recorded.entry(destination).or_default();
+                connections.push_back(Connection {
+                    client_sequence: tcp.sequence,
+                    packets: VecDeque::new(),
+                });
+                open.insert((source, destination), (connections.len() - 1, 1));
+            } else if let Some((_, counted)) = open.get_mut(&(source, destination)) {
+                if tcp.flags & TcpFlags::RST != 0 {
+                    open.remove(&(source, destination));
+                } else if counts(&tcp) {
+                    *counted += 1;
+                }
+            } else if let Some(&(index, counted)) = open.get(&(destination, source)) {
+                recorded
+                    .get_mut(&source)
+                    .expect("open connections are recorded")[index]
+                    .packets
+                    .push_back((counted, tcp));
+            }
+        }
+        println!(
+            "replaying {} connections to {} servers from {}",
+            recorded.values().map(VecDeque::len).sum::<usize>(),
+            servers.len(),
+            capture.display()
+        );
+
+        let output = match output {
+            Some(path) => Some(PcapWriter::new(BufWriter::new(File::create(path)?))?),
+            None => None,
+        };
+        let (responses_sender, responses) = mpsc::channel();
+        Ok(Self {
+            sender: ReplaySender {
+                replay: Arc::new(Mutex::new(Replay {
+                    recorded,
+                    replaying: HashMap::new(),
+                    output,
+                })),
+                responses: responses_sender,
+            },
+            receiver: ReplayReceiver { responses },
+            servers,
+        })
+    }
+}
+
+/// Whether a client packet is counted to decide when to answer it
+fn counts(tcp: &Tcp) -> bool {
+    tcp.flags & (TcpFlags::SYN | TcpFlags::FIN) != 0 || !tcp.payload.is_empty()
+}
+
+impl Replay {
+    fn record(&mut self, packet: &[u8]) {
+        let Some(output) = &mut self.output else {
+            return;
+        };
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        if let Err(err) = output
+            .write_packet(timestamp, packet)
+            .and_then(|_| output.flush())
+        {
+            eprintln!("unable to write replay output: {err}");
+            self.output = None;
+        }
+    }
+}
+
+impl ReplaySender {
+    pub fn send(&self, packet: &[u8]) {
+        let mut replay = self.replay.lock().unwrap();
+        replay.record(packet);
+        let Some((source_ip, destination_ip, tcp)) = parse_ip_packet(packet) else {
+            return;
+        };
+        let local = SocketAddr::new(source_ip, tcp.source);
+        let server = SocketAddr::new(destination_ip, tcp.destination);
+        let key = (server, local);
+
+        if tcp.flags & (TcpFlags::SYN | TcpFlags::ACK) == TcpFlags::SYN {
+            let Some(connection) = replay
+                .recorded
+                .get_mut(&server)
+                .and_then(VecDeque::pop_front)
+            else {
+                // nothing's listening
+                return;
+            };
+            replay.replaying.insert(
+                key,
+                Replaying {
+                    connection,
+                    our_sequence: tcp.sequence,
+                    sent: 1,
+                },
+            );
+        } else if let Some(replaying) = replay.replaying.get_mut(&key) {
+            if tcp.flags & TcpFlags::RST != 0 {
+                replay.replaying.remove(&key);
+                return;
+            }
+            if counts(&tcp) {
+                replaying.sent += 1;
+            }
+        } else {
+            return;
+        }
+
+        let Some(replaying) = replay.replaying.get_mut(&key) else {
+            return;
+        };
+        let mut responses = Vec::new();
+        while let Some((counted, _)) = replaying.connection.packets.front() {
+            if *counted > replaying.sent {
+                break;
+            }
+            let (_, tcp) = replaying.connection.packets.pop_front().unwrap();
+            let acknowledgement = if tcp.flags & TcpFlags::ACK != 0 {
+                tcp.acknowledgement
+                    .wrapping_sub(replaying.connection.client_sequence)
+                    .wrapping_add(replaying.our_sequence)
+            } else {
+                tcp.acknowledgement
+            };
+            responses.push(build_tcp_packet(
+                PacketRepr {
+                    dest_addr: local.ip(),
+                    dest_port: local.port(),
+                    source_addr: server.ip(),
+                    source_port: server.port(),
+                    sequence: tcp.sequence,
+                    acknowledgement,
+                    flags: tcp.flags,
+                    window: tcp.window,
+                    urgent_ptr: tcp.urgent_ptr,
+                    options: &tcp.options,
+                    payload: &tcp.payload,
+                },
+                REPLAY_TTL,
+                None,
+                None,
+            ));
+        }
+        if replaying.connection.packets.is_empty() {
+            replay.replaying.remove(&key);
+        }
+        for response in responses {
+            replay.record(&response);
+            let _ = self.responses.send(response);
+        }
+    }
+}
+
+impl ReplayReceiver {
+    /// Wait up to `timeout` for the next packet the replay sends us, `None` if
+    /// it didn't send one
+    pub fn recv_with<T>(
+        &mut self,
+        timeout: Duration,
+        f: impl FnOnce(&[u8]) -> T,
+    ) -> io::Result<Option<T>> {
+        match self.responses.recv_timeout(timeout) {
+            Ok(packet) => Ok(Some(f(&packet))),
+            Err(mpsc::RecvTimeoutError::Timeout) => Ok(None),
+            Err(mpsc::RecvTimeoutError::Disconnected) => {
+                Err(io::Error::from(io::ErrorKind::BrokenPipe))
+            }
+        }
+    }
+}
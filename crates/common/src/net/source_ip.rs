@@ -0,0 +1,80 @@
+use serde::Deserialize;
+use std::{
+    hash::{DefaultHasher, Hash, Hasher},
+    net::{IpAddr, SocketAddr},
+};
+
+#[derive(Deserialize, Clone, Copy, Default, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum SourceIpSelection {
+    /// Take turns using each address
+    #[default]
+    RoundRobin,
+    /// Always use the same address for a destination
+    Hash,
+}
+
+/// One of our addresses and its position in [`SourceIps::iter`], so things
+/// kept for every address can be indexed by it
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SourceIp {
+    pub ip: IpAddr,
+    pub index: usize,
+}
+
+/// The addresses probes are sent from.
+#[derive(Clone, Debug)]
+pub struct SourceIps {
+    ipv4: Vec<IpAddr>,
+    ipv6: Vec<IpAddr>,
+    selection: SourceIpSelection,
+    next: usize,
+}
+
+impl SourceIps {
+    pub fn new(ips: &[IpAddr], selection: SourceIpSelection) -> Self {
+        let (ipv4, ipv6) = ips.iter().partition(|ip| ip.is_ipv4());
+        Self {
+            ipv4,
+            ipv6,
+            selection,
+            next: 0,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ipv4.is_empty() && self.ipv6.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &IpAddr> {
+        self.ipv4.iter().chain(&self.ipv6)
+    }
+
+    /// Pick the address to send a probe to `addr` from, which is always of the
+    /// same family as `addr`. Returns `None` if we have no such address.
+    pub fn pick(&mut self, addr: &SocketAddr) -> Option<SourceIp> {
+        let (ips, offset) = match addr {
+            SocketAddr::V4(_) => (&self.ipv4, 0),
+            SocketAddr::V6(_) => (&self.ipv6, self.ipv4.len()),
+        };
+        if ips.is_empty() {
+            return None;
+        }
+        let index = match self.selection {
+            SourceIpSelection::RoundRobin => {
+                self.next = self.next.wrapping_add(1);
+                self.next
+            }
+            SourceIpSelection::Hash => {
+                let mut hasher = DefaultHasher::new();
+                addr.hash(&mut hasher);
+                hasher.finish() as usize
+            }
+        };
+        let index = index % ips.len();
+        Some(SourceIp {
+            ip: ips[index],
+            index: offset + index,
+        })
+    }
+}
@@ -1,6 +1,6 @@
 use serde::Deserialize;
 
-#[derive(Deserialize, Clone, Copy)]
+#[derive(Deserialize, Clone, Copy, Debug)]
 #[serde(untagged)]
 pub enum SourcePort {
     Number(u16),
@@ -11,17 +11,25 @@ impl SourcePort {
     /// Pick a source port based on the given seed.
     ///
     /// If the source port is a range, then the port is chosen uniformly from
-    /// the range. Otherwise, the port is the given number.
+    /// the range (including `max`). Otherwise, the port is the given number.
     pub fn pick(&self, seed: u32) -> u16 {
         match self {
             SourcePort::Number(port) => *port,
             SourcePort::Range { min, max } => {
-                let range = max - min;
-                (seed % range as u32) as u16 + min
+                let range = (max - min) as u32 + 1;
+                (seed % range) as u16 + min
             }
         }
     }
 
+    /// Port 0 can't be sent from, and a range has to have at least one port
+    pub fn is_valid(&self) -> bool {
+        match self {
+            SourcePort::Number(port) => *port != 0,
+            SourcePort::Range { min, max } => *min != 0 && min <= max,
+        }
+    }
+
     pub fn contains(&self, port: u16) -> bool {
         match self {
             SourcePort::Number(p) => *p == port,
@@ -35,3 +43,60 @@ impl Default for SourcePort {
         SourcePort::Number(61000)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeSet;
+
+    #[test]
+    fn picks_every_port_in_the_range() {
+        let source_port = SourcePort::Range {
+            min: 61000,
+            max: 61003,
+        };
+        let picked = (0..100)
+            .chain(u32::MAX - 100..=u32::MAX)
+            .map(|seed| source_port.pick(seed))
+            .collect::<BTreeSet<_>>();
+        assert_eq!(picked, (61000..=61003).collect());
+    }
+
+    #[test]
+    fn picks_from_the_whole_port_space() {
+        let source_port = SourcePort::Range { min: 1, max: 65535 };
+        assert_eq!(source_port.pick(0), 1);
+        assert_eq!(source_port.pick(65534), 65535);
+        assert_eq!(source_port.pick(65535), 1);
+        assert!(source_port.contains(source_port.pick(u32::MAX)));
+    }
+
+    #[test]
+    fn picks_the_only_port() {
+        assert_eq!(SourcePort::Number(61000).pick(12345), 61000);
+        let single = SourcePort::Range {
+            min: 61000,
+            max: 61000,
+        };
+        assert_eq!(single.pick(12345), 61000);
+    }
+
+    #[test]
+    fn validates() {
+        assert!(SourcePort::Number(61000).is_valid());
+        assert!(SourcePort::Range { min: 1, max: 65535 }.is_valid());
+        assert!(SourcePort::Range {
+            min: 61000,
+            max: 61000
+        }
+        .is_valid());
+
+        assert!(!SourcePort::Number(0).is_valid());
+        assert!(!SourcePort::Range { min: 0, max: 10 }.is_valid());
+        assert!(!SourcePort::Range {
+            min: 61001,
+            max: 61000
+        }
+        .is_valid());
+    }
+}
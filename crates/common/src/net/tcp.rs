@@ -1,52 +1,294 @@
 use crate::net::tcp_template::TemplatePacketRepr;
 
 use super::{
+    arp,
+    packet_ring::{self, PacketRing},
+    rate_limit::RateLimiter,
     raw_socket::RawSocket,
+    replay::{ReplayNetwork, ReplayReceiver, ReplaySender},
+    source_ip::{SourceIp, SourceIpSelection, SourceIps},
     source_port::SourcePort,
     tcp_template::{self, TemplatePacket},
 };
 use pnet::{
-    datalink::{self, Channel, Config, DataLinkReceiver, NetworkInterface},
+    datalink::{self, NetworkInterface},
     packet::{
-        ethernet::EthernetPacket,
         ip::IpNextHeaderProtocols::{self},
-        ipv4::{Ipv4, Ipv4Packet},
-        tcp::{Tcp, TcpFlags, TcpOption, TcpPacket},
+        ipv4::Ipv4Packet,
+        ipv6::Ipv6Packet,
+        tcp::{Tcp, TcpFlags, TcpOption, TcpOptionNumbers, TcpPacket},
         FromPacket, Packet,
     },
     util::MacAddr,
 };
+use serde::Deserialize;
 use std::{
-    io::Write,
-    net::{IpAddr, Ipv4Addr, SocketAddrV4},
-    time::Duration,
+    io::{self, Write},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 use tracing::warn;
 
 pub const ETH_HEADER_LEN: usize = 14;
 
-pub fn get_interface() -> NetworkInterface {
-    let interface_name = default_net::get_default_interface().unwrap().name;
+/// Find the interface with the given name, or the default interface if the
+/// name is empty.
+pub fn get_interface(interface_name: &str) -> eyre::Result<NetworkInterface> {
+    let interface_name = if interface_name.is_empty() {
+        default_net::get_default_interface()
+            .map_err(|err| eyre::eyre!("unable to find the default interface: {err}"))?
+            .name
+    } else {
+        interface_name.to_string()
+    };
     println!("interface name: {interface_name}");
 
     // Find the network interface with the provided name
     let interfaces = datalink::interfaces();
+    let names = interfaces
+        .iter()
+        .map(|i| i.name.as_str())
+        .collect::<Vec<_>>()
+        .join(", ");
     interfaces
         .into_iter()
         .find(|i| i.name == interface_name)
-        .unwrap()
+        .ok_or_else(|| eyre::eyre!("no interface named {interface_name} (found {names})"))
+}
+
+/// Find the MAC address packets leaving the interface should be sent to.
+fn get_gateway_mac(
+    interface: &NetworkInterface,
+    interface_ipv4: Option<Ipv4Addr>,
+) -> eyre::Result<Option<MacAddr>> {
+    let Some(interface_mac) = interface.mac else {
+        // no mac = no ethernet header
+        return Ok(None);
+    };
+    if interface.is_loopback() {
+        return Ok(Some(MacAddr::zero()));
+    }
+
+    if let Some(interface_ipv4) = interface_ipv4 {
+        let gateway = arp::default_gateway(&interface.name)?.ok_or_else(|| {
+            eyre::eyre!(
+                "interface {} has no default gateway, set scanner.interface_name to an interface that does",
+                interface.name
+            )
+        })?;
+        println!("gateway: {gateway}");
+        return arp::resolve(&interface.name, interface_mac, interface_ipv4, gateway).map(Some);
+    }
+
+    // an ipv6 only interface, we only know the gateway if it's the default one
+    match default_net::get_default_gateway() {
+        Ok(gateway)
+            if default_net::get_default_interface()
+                .is_ok_and(|default| default.name == interface.name) =>
+        {
+            Ok(Some(MacAddr::from(gateway.mac_addr.octets())))
+        }
+        _ => Err(eyre::eyre!(
+            "unable to find the gateway of ipv6 only interface {}",
+            interface.name
+        )),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TcpOptionKind {
+    Mss,
+    Nop,
+    SackPerm,
+    Timestamp,
+    WindowScale,
+}
+
+/// What our TCP packets look like, so they blend in with a real OS
+#[derive(Debug, Clone, Deserialize)]
+pub struct TcpFingerprint {
+    /// Window sent in SYNs
+    pub window: u16,
+    pub ttl: u8,
+    /// Only sent if `options` contains [`TcpOptionKind::WindowScale`]
+    #[serde(default)]
+    pub window_scale: u8,
+    /// Options sent in SYNs, in order
+    pub options: Vec<TcpOptionKind>,
+}
+
+/// A named [`TcpFingerprint`], or a custom one.
+///
+/// ```toml
+/// [scanner.fingerprint]
+/// profile = "custom"
+/// window = 64240
+/// ttl = 64
+/// window_scale = 7
+/// options = ["mss", "sack_perm", "timestamp", "nop", "window_scale"]
+/// ```
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(tag = "profile", rename_all = "snake_case")]
+pub enum FingerprintProfile {
+    #[default]
+    Linux,
+    Windows,
+    Minimal,
+    Custom(TcpFingerprint),
+}
+
+impl FingerprintProfile {
+    pub fn fingerprint(&self) -> TcpFingerprint {
+        match self {
+            FingerprintProfile::Linux => TcpFingerprint::linux(),
+            FingerprintProfile::Windows => TcpFingerprint::windows(),
+            FingerprintProfile::Minimal => TcpFingerprint::minimal(),
+            FingerprintProfile::Custom(fingerprint) => fingerprint.clone(),
+        }
+    }
+}
+
+impl TcpFingerprint {
+    pub fn linux() -> Self {
+        Self {
+            window: 64240,
+            ttl: 64,
+            window_scale: 7,
+            options: vec![
+                TcpOptionKind::Mss,
+                TcpOptionKind::SackPerm,
+                TcpOptionKind::Timestamp,
+                TcpOptionKind::Nop,
+                TcpOptionKind::WindowScale,
+            ],
+        }
+    }
+
+    pub fn windows() -> Self {
+        Self {
+            window: 64240,
+            ttl: 128,
+            window_scale: 8,
+            options: vec![
+                TcpOptionKind::Mss,
+                TcpOptionKind::Nop,
+                TcpOptionKind::WindowScale,
+                TcpOptionKind::Nop,
+                TcpOptionKind::Nop,
+                TcpOptionKind::SackPerm,
+            ],
+        }
+    }
+
+    /// Only the MSS, this is the smallest SYN most servers will accept
+    pub fn minimal() -> Self {
+        Self {
+            window: 65535,
+            ttl: 64,
+            window_scale: 0,
+            options: vec![TcpOptionKind::Mss],
+        }
+    }
+
+    fn has_option(&self, kind: TcpOptionKind) -> bool {
+        self.options.contains(&kind)
+    }
+
+    /// Options to send in a SYN. The timestamp is set again for every SYN
+    /// built from them, see [`TemplatePacket::set_timestamp`].
+    pub fn syn_options(&self, mss: u16) -> Vec<TcpOption> {
+        self.options
+            .iter()
+            .map(|kind| match kind {
+                TcpOptionKind::Mss => TcpOption::mss(mss),
+                TcpOptionKind::Nop => TcpOption::nop(),
+                TcpOptionKind::SackPerm => TcpOption::sack_perm(),
+                TcpOptionKind::Timestamp => TcpOption::timestamp(timestamp(), 0),
+                TcpOptionKind::WindowScale => TcpOption::wscale(self.window_scale),
+            })
+            .collect()
+    }
+
+    /// Options to send after the handshake. Only the timestamp is repeated,
+    /// if the server does timestamps too.
+    pub fn options(&self, peer: PeerOptions) -> Vec<TcpOption> {
+        match peer.timestamp {
+            Some(echo) if self.has_option(TcpOptionKind::Timestamp) => vec![
+                TcpOption::nop(),
+                TcpOption::nop(),
+                TcpOption::timestamp(timestamp(), echo),
+            ],
+            _ => vec![],
+        }
+    }
+
+    /// Window to send after the handshake, which is scaled down if both sides
+    /// asked for window scaling
+    pub fn scaled_window(&self, peer: PeerOptions) -> u16 {
+        if self.has_option(TcpOptionKind::WindowScale) && peer.window_scale {
+            self.window >> self.window_scale.min(14)
+        } else {
+            self.window
+        }
+    }
+}
+
+/// What the server agreed to in its SYN-ACK, which decides the options and
+/// window we send after the handshake
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct PeerOptions {
+    /// The server's latest TSval, which our timestamps echo. `None` if it
+    /// doesn't do timestamps.
+    pub timestamp: Option<u32>,
+    /// Whether the server sent the window scale option
+    pub window_scale: bool,
+}
+
+impl PeerOptions {
+    pub fn from_syn_ack(syn_ack: &Tcp) -> Self {
+        Self {
+            timestamp: timestamp_value(syn_ack),
+            window_scale: syn_ack
+                .options
+                .iter()
+                .any(|option| option.number == TcpOptionNumbers::WSCALE),
+        }
+    }
+
+    /// Echo the timestamp of a later segment from the server
+    pub fn update(&mut self, segment: &Tcp) {
+        if self.timestamp.is_some() {
+            if let Some(timestamp) = timestamp_value(segment) {
+                self.timestamp = Some(timestamp);
+            }
+        }
+    }
+}
+
+/// The TSval of a segment's timestamp option
+fn timestamp_value(tcp: &Tcp) -> Option<u32> {
+    let option = tcp
+        .options
+        .iter()
+        .find(|option| option.number == TcpOptionNumbers::TIMESTAMPS)?;
+    Some(u32::from_be_bytes(option.data.get(..4)?.try_into().ok()?))
 }
 
-#[derive(Debug, Clone)]
-pub struct TcpFingerprint {}
-// TODO: make the fingerprint easily customizable
-#[allow(clippy::derivable_impls)]
 impl Default for TcpFingerprint {
     fn default() -> Self {
-        Self {}
+        Self::linux()
     }
 }
 
+/// Millisecond clock for the TCP timestamp option
+fn timestamp() -> u32 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u32
+}
+
 #[derive(Debug, Clone)]
 pub struct Fingerprint {
     /// Link type
@@ -54,6 +296,15 @@ pub struct Fingerprint {
     pub tcp: TcpFingerprint,
 }
 
+impl Fingerprint {
+    pub fn new(tcp: TcpFingerprint) -> Self {
+        Self {
+            tcp,
+            ..Default::default()
+        }
+    }
+}
+
 impl Default for Fingerprint {
     fn default() -> Self {
         let mss = 1360;
@@ -65,6 +316,16 @@ impl Default for Fingerprint {
     }
 }
 
+/// A SYN for [`StatelessTcpWriteHalf::send_syn_batch`]
+#[derive(Debug, Clone, Copy)]
+pub struct Syn {
+    pub addr: SocketAddr,
+    pub source: SocketAddr,
+    /// [`SourceIp::index`] of the source address
+    pub source_index: usize,
+    pub sequence: u32,
+}
+
 pub struct StatelessTcp {
     pub read: StatelessTcpReadHalf,
     pub write: StatelessTcpWriteHalf,
@@ -72,7 +333,7 @@ pub struct StatelessTcp {
 
 #[derive(Clone)]
 pub struct StatelessTcpWriteHalf {
-    source_ip: Ipv4Addr,
+    source_ips: SourceIps,
     #[allow(dead_code)]
     source_port: SourcePort,
 
@@ -81,102 +342,255 @@ pub struct StatelessTcpWriteHalf {
 
     mtu: usize,
 
-    socket: RawSocket,
+    socket: PacketSender,
+    rate_limiter: RateLimiter,
 
     pub fingerprint: Fingerprint,
 
-    template_syn_packet: TemplatePacket,
+    /// One SYN template for every source ip, in the same order
+    syn_templates: Vec<TemplatePacket>,
+    /// Reused buffers for [`StatelessTcpWriteHalf::send_syn_batch`]
+    syn_batch: Vec<Vec<u8>>,
 }
 
 pub struct StatelessTcpReadHalf {
     interface_mac: Option<MacAddr>,
-    source_port: SourcePort,
 
-    // tx: Box<dyn DataLinkSender>,
-    rx: Box<dyn DataLinkReceiver>,
+    /// Only receives TCP packets sent to our source ports
+    rx: PacketReceiver,
+}
+
+/// Where packets are sent, the network or a replayed capture
+#[derive(Clone)]
+enum PacketSender {
+    Raw(RawSocket),
+    Replay(ReplaySender),
+}
+
+enum PacketReceiver {
+    Ring(PacketRing),
+    Replay(ReplayReceiver),
+}
+
+impl PacketSender {
+    fn send_blocking(&mut self, packet: &[u8]) {
+        match self {
+            PacketSender::Raw(socket) => socket.send_blocking(packet),
+            PacketSender::Replay(replay) => replay.send(packet),
+        }
+    }
+
+    fn send_batch_blocking<T: AsRef<[u8]>>(&mut self, packets: &[T]) {
+        match self {
+            PacketSender::Raw(socket) => socket.send_batch_blocking(packets),
+            PacketSender::Replay(replay) => {
+                for packet in packets {
+                    replay.send(packet.as_ref());
+                }
+            }
+        }
+    }
+}
+
+impl PacketReceiver {
+    fn recv_with<T>(
+        &mut self,
+        timeout: Duration,
+        f: impl FnOnce(&[u8]) -> T,
+    ) -> io::Result<Option<T>> {
+        match self {
+            PacketReceiver::Ring(ring) => ring.recv_with(timeout, f),
+            PacketReceiver::Replay(replay) => replay.recv_with(timeout, f),
+        }
+    }
 }
 
 impl StatelessTcp {
     /// Create a new stateless TCP instance.
     ///
     /// For the source port I usually do 61000 and then firewall it with
-    /// `iptables -A INPUT -p tcp --dport 61000 -j DROP`
-    pub fn new(source_port: SourcePort) -> Self {
-        let interface = get_interface();
+    /// `iptables -A INPUT -p tcp --dport 61000 -j DROP`. A range of ports is
+    /// firewalled the same way with `--dport min:max`.
+    ///
+    /// SYNs are sent no faster than `rate_limiter` allows. IPv4 and IPv6
+    /// addresses can only be scanned if the interface has an address of that
+    /// family, packets to the other family are skipped.
+    ///
+    /// If `interface_name` is empty the default interface is used. Probes are
+    /// sent from `source_ips`, or from the interface's own addresses if it's
+    /// empty.
+    pub fn new(
+        interface_name: &str,
+        source_ips: &[IpAddr],
+        source_ip_selection: SourceIpSelection,
+        source_port: SourcePort,
+        fingerprint: Fingerprint,
+        rate_limiter: RateLimiter,
+    ) -> eyre::Result<Self> {
+        let interface = get_interface(interface_name)?;
         println!("interface: {:?}", interface);
 
-        println!("{:?}", default_net::get_default_interface());
-        let gateway_mac = if let Ok(default_gateway) = default_net::get_default_gateway() {
-            Some(MacAddr::from(default_gateway.mac_addr.octets()))
-        } else {
-            None
-        };
-
-        // Create a channel to receive on
-        let (_tx, rx) = match datalink::channel(
-            &interface,
-            Config {
-                read_timeout: Some(Duration::ZERO),
-                // read_timeout: Some(Duration::from_secs(1)),
-                ..Default::default()
-            },
-            // Config::default(),
-        ) {
-            Ok(Channel::Ethernet(tx, rx)) => (tx, rx),
-            Ok(_) => panic!("unhandled channel type"),
-            Err(e) => panic!("unable to create channel: {}", e),
-        };
+        let interface_ipv4 = interface.ips.iter().find_map(|ip| match ip.ip() {
+            IpAddr::V4(ip) => Some(ip),
+            IpAddr::V6(_) => None,
+        });
+        // link local addresses can't reach the internet
+        let interface_ipv6 = interface.ips.iter().find_map(|ip| match ip.ip() {
+            IpAddr::V6(ip) if !ip.is_loopback() && ip.segments()[0] & 0xffc0 != 0xfe80 => Some(ip),
+            _ => None,
+        });
+        if interface_ipv4.is_none() && interface_ipv6.is_none() {
+            return Err(eyre::eyre!(
+                "interface {} has no ip addresses",
+                interface.name
+            ));
+        }
 
-        let interface_ipv4 = match interface.ips.iter().find(|ip| ip.is_ipv4()).unwrap().ip() {
-            IpAddr::V4(ip) => ip,
-            IpAddr::V6(_) => panic!("ipv6 not supported"),
+        let source_ips = if source_ips.is_empty() {
+            let interface_ips = [
+                interface_ipv4.map(IpAddr::V4),
+                interface_ipv6.map(IpAddr::V6),
+            ];
+            SourceIps::new(
+                &interface_ips.into_iter().flatten().collect::<Vec<_>>(),
+                source_ip_selection,
+            )
+        } else {
+            // replies to other addresses won't be routed to us, and the receive
+            // filter doesn't look at the destination so we'd never notice
+            for ip in source_ips {
+                if !interface.ips.iter().any(|network| network.ip() == *ip) {
+                    return Err(eyre::eyre!(
+                        "source ip {ip} isn't an address of {}, add it with `ip addr add {ip} dev {}` or remove it from scanner.source_ips",
+                        interface.name,
+                        interface.name
+                    ));
+                }
+            }
+            SourceIps::new(source_ips, source_ip_selection)
         };
-
-        let mut socket = RawSocket::new(&interface.name).unwrap();
+        println!("source ips: {:?}", source_ips.iter().collect::<Vec<_>>());
 
         let interface_mac = interface.mac;
+        let gateway_mac = get_gateway_mac(&interface, interface_ipv4)?;
+        println!("gateway mac: {gateway_mac:?}");
+
+        let mut socket = RawSocket::new(&interface.name)?;
 
-        let mut mtu = socket.interface_mtu().unwrap();
+        let mut mtu = socket.interface_mtu()?;
         if interface_mac.is_some() {
             mtu += ETH_HEADER_LEN;
         }
 
-        let fingerprint = Fingerprint::default();
-
-        let write_half = StatelessTcpWriteHalf {
-            source_ip: interface_ipv4,
+        let eth_header_len = if interface_mac.is_some() {
+            ETH_HEADER_LEN
+        } else {
+            0
+        };
+        let rx = PacketRing::new(
+            interface.index as libc::c_int,
+            mtu,
+            &packet_ring::tcp_port_filter(eth_header_len, source_port),
+        )
+        .map_err(|err| eyre::eyre!("unable to create receive ring: {err}"))?;
+
+        Ok(Self::from_parts(
+            PacketSender::Raw(socket),
+            PacketReceiver::Ring(rx),
+            source_ips,
             source_port,
-
             gateway_mac,
             interface_mac,
             mtu,
+            fingerprint,
+            rate_limiter,
+        ))
+    }
 
-            socket,
+    /// Send and receive packets through a replayed capture instead of the
+    /// network. Probes are sent from `source_ips`, or from documentation
+    /// addresses if it's empty.
+    pub fn replay(
+        network: ReplayNetwork,
+        source_ips: &[IpAddr],
+        source_ip_selection: SourceIpSelection,
+        source_port: SourcePort,
+        fingerprint: Fingerprint,
+        rate_limiter: RateLimiter,
+    ) -> Self {
+        const REPLAY_MTU: usize = 1500;
+
+        let source_ips = if source_ips.is_empty() {
+            SourceIps::new(
+                &[
+                    IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)),
+                    IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1)),
+                ],
+                source_ip_selection,
+            )
+        } else {
+            SourceIps::new(source_ips, source_ip_selection)
+        };
+        Self::from_parts(
+            PacketSender::Replay(network.sender),
+            PacketReceiver::Replay(network.receiver),
+            source_ips,
+            source_port,
+            None,
+            None,
+            REPLAY_MTU,
+            fingerprint,
+            rate_limiter,
+        )
+    }
 
-            template_syn_packet: TemplatePacket::new(TemplatePacketRepr {
+    #[allow(clippy::too_many_arguments)]
+    fn from_parts(
+        socket: PacketSender,
+        rx: PacketReceiver,
+        source_ips: SourceIps,
+        source_port: SourcePort,
+        gateway_mac: Option<MacAddr>,
+        interface_mac: Option<MacAddr>,
+        mtu: usize,
+        fingerprint: Fingerprint,
+        rate_limiter: RateLimiter,
+    ) -> Self {
+        let template_syn_packet = |source_addr: IpAddr| {
+            TemplatePacket::new(TemplatePacketRepr {
                 flags: TcpFlags::SYN,
-                window: 32768,
+                window: fingerprint.tcp.window,
+                ttl: fingerprint.tcp.ttl,
                 urgent_ptr: 0,
-                options: vec![
-                    TcpOption::mss(fingerprint.mss),
-                    TcpOption::nop(),
-                    TcpOption::nop(),
-                    TcpOption::sack_perm(),
-                ],
+                options: fingerprint.tcp.syn_options(fingerprint.mss),
                 gateway_mac,
                 interface_mac,
-                source_addr: interface_ipv4,
-            }),
+                source_addr,
+            })
+        };
+
+        let write_half = StatelessTcpWriteHalf {
+            source_port,
+
+            gateway_mac,
+            interface_mac,
+            mtu,
+
+            socket,
+            rate_limiter,
+
+            syn_templates: source_ips
+                .iter()
+                .map(|ip| template_syn_packet(*ip))
+                .collect(),
+            source_ips,
+            syn_batch: Vec::new(),
 
             fingerprint,
         };
 
         StatelessTcp {
-            read: StatelessTcpReadHalf {
-                source_port,
-                interface_mac,
-                rx,
-            },
+            read: StatelessTcpReadHalf { interface_mac, rx },
             write: write_half,
         }
     }
@@ -194,125 +608,195 @@ impl StatelessTcpWriteHalf {
         self.gateway_mac.is_some() && self.interface_mac.is_some()
     }
 
-    pub fn send_syn(&mut self, addr: SocketAddrV4, source_port: u16, sequence: u32) {
-        let packet = self.template_syn_packet.build(tcp_template::PacketRepr {
-            dest_addr: *addr.ip(),
-            dest_port: addr.port(),
-            sequence,
+    pub fn rate_limiter(&self) -> &RateLimiter {
+        &self.rate_limiter
+    }
+
+    /// Pick the address to send a probe to `addr` from, or `None` if we have
+    /// no address of the same family.
+    pub fn pick_source_ip(&mut self, addr: &SocketAddr) -> Option<SourceIp> {
+        self.source_ips.pick(addr)
+    }
+
+    pub fn send_syn(&mut self, syn: Syn) {
+        let Some(template) = self.syn_templates.get_mut(syn.source_index) else {
+            return;
+        };
+        self.rate_limiter.blocking_acquire();
+        template.set_timestamp(timestamp());
+        let packet = template.build(tcp_template::PacketRepr {
+            dest_addr: syn.addr.ip(),
+            dest_port: syn.addr.port(),
+            sequence: syn.sequence,
             acknowledgement: 0,
             payload: &[],
-            source_port,
+            source_port: syn.source.port(),
         });
 
         self.socket.send_blocking(packet);
     }
 
+    /// Send every SYN with as few syscalls as possible. Unlike
+    /// [`send_syn`](Self::send_syn) this doesn't wait for the rate limiter, the
+    /// caller should've acquired a token for every SYN. Returns how many were
+    /// sent, SYNs from a source we have no template for are skipped.
+    pub fn send_syn_batch(&mut self, syns: &[Syn]) -> usize {
+        let now = timestamp();
+        let mut count = 0;
+        for syn in syns {
+            let Some(template) = self.syn_templates.get_mut(syn.source_index) else {
+                continue;
+            };
+            template.set_timestamp(now);
+            let packet = template.build(tcp_template::PacketRepr {
+                dest_addr: syn.addr.ip(),
+                dest_port: syn.addr.port(),
+                sequence: syn.sequence,
+                acknowledgement: 0,
+                payload: &[],
+                source_port: syn.source.port(),
+            });
+            if count == self.syn_batch.len() {
+                self.syn_batch.push(Vec::new());
+            }
+            let buffer = &mut self.syn_batch[count];
+            buffer.clear();
+            buffer.extend_from_slice(packet);
+            count += 1;
+        }
+
+        self.socket.send_batch_blocking(&self.syn_batch[..count]);
+        count
+    }
+
+    /// Send already built packets with as few syscalls as possible.
+    pub fn send_batch<T: AsRef<[u8]>>(&mut self, packets: &[T]) {
+        self.socket.send_batch_blocking(packets);
+    }
+
     pub fn send_ack(
         &mut self,
-        addr: SocketAddrV4,
-        source_port: u16,
+        addr: SocketAddr,
+        source: SocketAddr,
         sequence: u32,
         acknowledgement: u32,
+        peer: PeerOptions,
     ) {
+        let options = self.fingerprint.tcp.options(peer);
         self.send_tcp(PacketRepr {
-            dest_addr: *addr.ip(),
+            dest_addr: addr.ip(),
             dest_port: addr.port(),
             sequence,
             acknowledgement,
             flags: TcpFlags::ACK,
-            window: 32768,
+            window: self.fingerprint.tcp.scaled_window(peer),
             urgent_ptr: 0,
-            options: &[TcpOption::nop(), TcpOption::nop(), TcpOption::sack_perm()],
+            options: &options,
             payload: &[],
-            source_port,
+            source_addr: source.ip(),
+            source_port: source.port(),
         });
     }
 
     pub fn send_rst(
         &mut self,
-        addr: SocketAddrV4,
-        source_port: u16,
+        addr: SocketAddr,
+        source: SocketAddr,
         sequence: u32,
         acknowledgement: u32,
+        peer: PeerOptions,
     ) {
+        let options = self.fingerprint.tcp.options(peer);
         self.send_tcp(PacketRepr {
-            dest_addr: *addr.ip(),
+            dest_addr: addr.ip(),
             dest_port: addr.port(),
-            source_port,
+            source_addr: source.ip(),
+            source_port: source.port(),
             sequence,
             acknowledgement,
             flags: TcpFlags::RST | TcpFlags::ACK,
-            window: 32768,
+            window: self.fingerprint.tcp.scaled_window(peer),
             urgent_ptr: 0,
-            options: &[TcpOption::nop(), TcpOption::nop(), TcpOption::sack_perm()],
+            options: &options,
             payload: &[],
         });
     }
 
     pub fn send_fin(
         &mut self,
-        addr: SocketAddrV4,
-        source_port: u16,
+        addr: SocketAddr,
+        source: SocketAddr,
         sequence: u32,
         acknowledgement: u32,
+        peer: PeerOptions,
     ) {
+        let options = self.fingerprint.tcp.options(peer);
         self.send_tcp(PacketRepr {
-            dest_addr: *addr.ip(),
+            dest_addr: addr.ip(),
             dest_port: addr.port(),
-            source_port,
+            source_addr: source.ip(),
+            source_port: source.port(),
             sequence,
             acknowledgement,
             flags: TcpFlags::FIN | TcpFlags::ACK,
-            window: 32768,
+            window: self.fingerprint.tcp.scaled_window(peer),
             urgent_ptr: 0,
-            options: &[TcpOption::nop(), TcpOption::nop(), TcpOption::sack_perm()],
+            options: &options,
             payload: &[],
         });
     }
 
     pub fn send_data(
         &mut self,
-        addr: SocketAddrV4,
-        source_port: u16,
+        addr: SocketAddr,
+        source: SocketAddr,
         sequence: u32,
         acknowledgement: u32,
         payload: &[u8],
+        peer: PeerOptions,
     ) {
+        let options = self.fingerprint.tcp.options(peer);
         self.send_tcp(PacketRepr {
-            dest_addr: *addr.ip(),
+            dest_addr: addr.ip(),
             dest_port: addr.port(),
-            source_port,
+            source_addr: source.ip(),
+            source_port: source.port(),
             sequence,
             acknowledgement,
             flags: TcpFlags::PSH | TcpFlags::ACK,
-            window: 32768,
+            window: self.fingerprint.tcp.scaled_window(peer),
             urgent_ptr: 0,
-            options: &[TcpOption::nop(), TcpOption::nop(), TcpOption::sack_perm()],
+            options: &options,
             payload,
         });
     }
 
     pub fn send_tcp(&mut self, repr: PacketRepr) {
-        let source_addr = SocketAddrV4::new(self.source_ip, repr.source_port);
-        let packet = build_tcp_packet(repr, self.gateway_mac, self.interface_mac, source_addr);
+        let packet = build_tcp_packet(
+            repr,
+            self.fingerprint.tcp.ttl,
+            self.gateway_mac,
+            self.interface_mac,
+        );
         self.socket.send_blocking(&packet);
     }
 }
 
-fn build_tcp_packet(
+pub(super) fn build_tcp_packet(
     repr: PacketRepr,
+    ttl: u8,
     gateway_mac: Option<MacAddr>,
     interface_mac: Option<MacAddr>,
-    source_addr: SocketAddrV4,
 ) -> Vec<u8> {
     let mut template = TemplatePacket::new(TemplatePacketRepr {
         flags: repr.flags,
         window: repr.window,
+        ttl,
         urgent_ptr: repr.urgent_ptr,
         options: repr.options.to_vec(),
         gateway_mac,
         interface_mac,
-        source_addr: *source_addr.ip(),
+        source_addr: repr.source_addr,
     });
     template
         .build(tcp_template::PacketRepr {
@@ -327,27 +811,29 @@ fn build_tcp_packet(
 }
 
 impl StatelessTcpReadHalf {
-    pub fn recv(&mut self) -> Option<(Ipv4, Tcp)> {
+    /// Wait up to `timeout` for the next TCP packet sent to one of our source
+    /// ports, returning its source and destination addresses. `Ok(None)` if
+    /// nothing came in, so the caller can do other work in between.
+    pub fn recv(&mut self, timeout: Duration) -> io::Result<Option<(IpAddr, IpAddr, Tcp)>> {
+        let eth_header_len = if self.interface_mac.is_some() {
+            ETH_HEADER_LEN
+        } else {
+            // no interface mac = no ethernet header
+            0
+        };
+        let deadline = Instant::now() + timeout;
         loop {
-            match self.rx.next() {
-                Ok(packet) => {
-                    let payload_for_ipv4 = if self.interface_mac.is_some() {
-                        let ethernet = EthernetPacket::new(packet).unwrap();
-                        ethernet.payload().to_vec()
-                    } else {
-                        // no interface mac = no ethernet header
-                        packet.to_vec()
-                    };
-
-                    if let Some(ipv4) = Ipv4Packet::new(&payload_for_ipv4) {
-                        if let Some(tcp) = process_ipv4(&ipv4) {
-                            if self.source_port.contains(tcp.destination) {
-                                return Some((ipv4.from_packet(), tcp));
-                            }
-                        }
-                    }
-                }
-                Err(_) => return None,
+            // the packet is parsed in place in the ring and only the headers we
+            // return are copied out
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            let packet = self.rx.recv_with(remaining, |packet| {
+                parse_ip_packet(packet.get(eth_header_len..)?)
+            })?;
+            match packet {
+                Some(Some(packet)) => return Ok(Some(packet)),
+                // the filter already dropped almost everything we can't parse
+                Some(None) => continue,
+                None => return Ok(None),
             }
         }
     }
@@ -355,9 +841,10 @@ impl StatelessTcpReadHalf {
 
 #[derive(Debug)]
 pub struct PacketRepr<'a> {
-    pub dest_addr: Ipv4Addr,
+    pub dest_addr: IpAddr,
     pub dest_port: u16,
 
+    pub source_addr: IpAddr,
     pub source_port: u16,
 
     pub sequence: u32,
@@ -369,6 +856,31 @@ pub struct PacketRepr<'a> {
     pub payload: &'a [u8],
 }
 
+/// The addresses and TCP segment of an IP packet, if it's TCP
+pub(super) fn parse_ip_packet(packet: &[u8]) -> Option<(IpAddr, IpAddr, Tcp)> {
+    match packet.first()? >> 4 {
+        4 => {
+            let ipv4 = Ipv4Packet::new(packet)?;
+            let tcp = process_ipv4(&ipv4)?;
+            Some((
+                IpAddr::V4(ipv4.get_source()),
+                IpAddr::V4(ipv4.get_destination()),
+                tcp,
+            ))
+        }
+        6 => {
+            let ipv6 = Ipv6Packet::new(packet)?;
+            let tcp = process_ipv6(&ipv6)?;
+            Some((
+                IpAddr::V6(ipv6.get_source()),
+                IpAddr::V6(ipv6.get_destination()),
+                tcp,
+            ))
+        }
+        _ => None,
+    }
+}
+
 fn process_ipv4(ipv4: &Ipv4Packet) -> Option<Tcp> {
     match ipv4.get_next_level_protocol() {
         IpNextHeaderProtocols::Tcp => {
@@ -393,3 +905,11 @@ fn process_ipv4(ipv4: &Ipv4Packet) -> Option<Tcp> {
         _ => None,
     }
 }
+
+/// Extension headers aren't supported, servers don't send them in practice
+fn process_ipv6(ipv6: &Ipv6Packet) -> Option<Tcp> {
+    match ipv6.get_next_header() {
+        IpNextHeaderProtocols::Tcp => TcpPacket::new(ipv6.payload()).map(|tcp| tcp.from_packet()),
+        _ => None,
+    }
+}
@@ -4,43 +4,50 @@ use pnet::{
         ethernet::{EtherTypes, Ethernet, MutableEthernetPacket},
         ip::IpNextHeaderProtocols,
         ipv4::{self, MutableIpv4Packet},
-        tcp::{MutableTcpPacket, TcpOption, TcpOptionPacket},
+        ipv6::MutableIpv6Packet,
+        tcp::{MutableTcpPacket, TcpOption, TcpOptionNumbers, TcpOptionPacket},
     },
     util::MacAddr,
 };
 use pnet_macros_support::packet::MutablePacket;
-use std::net::Ipv4Addr;
+use std::net::IpAddr;
 
 #[derive(Clone)]
 pub struct TemplatePacket {
     packet: Vec<u8>,
 
     // source addr needs to be stored for the checksum
-    source_addr: Ipv4Addr,
+    source_addr: IpAddr,
 
     eth_header_len: usize,
-    // ipv4 header length is constant (20 bytes) so it doesn't need to be here
-    // ipv4_header_len: usize,
+    // we never send ip options so this is either 20 or 40 bytes
+    ip_header_len: usize,
     tcp_header_len: usize,
+    /// Where the TSval of the timestamp option is in `packet`, if there is one
+    timestamp_offset: Option<usize>,
 }
 
 const IPV4_HEADER_LEN: usize = 20;
+const IPV6_HEADER_LEN: usize = 40;
 
 /// Parts of a packet that will be the same for every packet
 pub struct TemplatePacketRepr {
     pub flags: u8,
     pub window: u16,
+    pub ttl: u8,
     pub urgent_ptr: u16,
     pub options: Vec<TcpOption>,
 
     pub gateway_mac: Option<MacAddr>,
     pub interface_mac: Option<MacAddr>,
-    pub source_addr: Ipv4Addr,
+    /// Packets built from the template are sent to addresses of the same
+    /// family as this
+    pub source_addr: IpAddr,
 }
 
 /// Parts of a packet that will be different for every packet
 pub struct PacketRepr<'a> {
-    pub dest_addr: Ipv4Addr,
+    pub dest_addr: IpAddr,
     pub dest_port: u16,
     pub source_port: u16,
     pub sequence: u32,
@@ -63,11 +70,26 @@ impl TemplatePacket {
             0
         };
 
-        let mut packet = vec![0u8; eth_header_len + IPV4_HEADER_LEN + tcp_header_len];
+        let ip_header_len = match repr.source_addr {
+            IpAddr::V4(_) => IPV4_HEADER_LEN,
+            IpAddr::V6(_) => IPV6_HEADER_LEN,
+        };
+
+        let mut packet = vec![0u8; eth_header_len + ip_header_len + tcp_header_len];
+
+        let mut option_offset = eth_header_len + ip_header_len + 20;
+        let mut timestamp_offset = None;
+        for option in &repr.options {
+            if option.number == TcpOptionNumbers::TIMESTAMPS {
+                // after the kind and length
+                timestamp_offset = Some(option_offset + 2);
+            }
+            option_offset += TcpOptionPacket::packet_size(option);
+        }
 
         // TCP
         let mut mutable_tcp_packet =
-            MutableTcpPacket::new(&mut packet[eth_header_len + IPV4_HEADER_LEN..]).unwrap();
+            MutableTcpPacket::new(&mut packet[eth_header_len + ip_header_len..]).unwrap();
         // mutable_tcp_packet.set_source(repr.source_port);
         // mutable_tcp_packet.set_destination(repr.dest_port);
         // mutable_tcp_packet.set_sequence(repr.sequence);
@@ -86,42 +108,62 @@ impl TemplatePacket {
         // );
         // mutable_tcp_packet.set_checksum(checksum);
 
-        // IPv4
         assert_eq!(
             packet[..packet.len() - tcp_header_len],
-            vec![0u8; eth_header_len + IPV4_HEADER_LEN]
+            vec![0u8; eth_header_len + ip_header_len]
         );
-        let mut mutable_ipv4_packet: MutableIpv4Packet =
-            MutableIpv4Packet::new(&mut packet[eth_header_len..]).unwrap();
-
-        mutable_ipv4_packet.set_version(4); // ipv4 lol
-        mutable_ipv4_packet.set_header_length(5); // linux always sets this to 5 so so do we
-        mutable_ipv4_packet.set_dscp(0); // prescedence and delay, don't care so 0
-        mutable_ipv4_packet.set_ecn(0); // reserved
-        mutable_ipv4_packet.set_identification(1); // https://github.com/torvalds/linux/blob/master/net/ipv4/ip_output.c#L165
-        mutable_ipv4_packet.set_flags(0b010); // please don't fragment :pleading_face:
-        mutable_ipv4_packet.set_fragment_offset(0); // fragmentation is disabled so 0
-        mutable_ipv4_packet.set_ttl(64); // default unix ttl
-        mutable_ipv4_packet.set_next_level_protocol(IpNextHeaderProtocols::Tcp);
-        mutable_ipv4_packet.set_source(repr.source_addr);
-        // mutable_ipv4_packet.set_destination(ipv4_packet.destination);
-        mutable_ipv4_packet.set_options(&[]);
-
-        // ```
-        // mutable_ipv4_packet.set_total_length((IPV4_HEADER_LEN + tcp_header_len) as u16);
-        // mutable_ipv4_packet.set_checksum(ipv4::checksum(&mutable_ipv4_packet.to_immutable()));
-        // ```
+        match repr.source_addr {
+            IpAddr::V4(source_addr) => {
+                // IPv4
+                let mut mutable_ipv4_packet: MutableIpv4Packet =
+                    MutableIpv4Packet::new(&mut packet[eth_header_len..]).unwrap();
+
+                mutable_ipv4_packet.set_version(4); // ipv4 lol
+                mutable_ipv4_packet.set_header_length(5); // linux always sets this to 5 so so do we
+                mutable_ipv4_packet.set_dscp(0); // prescedence and delay, don't care so 0
+                mutable_ipv4_packet.set_ecn(0); // reserved
+                mutable_ipv4_packet.set_identification(1); // https://github.com/torvalds/linux/blob/master/net/ipv4/ip_output.c#L165
+                mutable_ipv4_packet.set_flags(0b010); // please don't fragment :pleading_face:
+                mutable_ipv4_packet.set_fragment_offset(0); // fragmentation is disabled so 0
+                mutable_ipv4_packet.set_ttl(repr.ttl);
+                mutable_ipv4_packet.set_next_level_protocol(IpNextHeaderProtocols::Tcp);
+                mutable_ipv4_packet.set_source(source_addr);
+                // mutable_ipv4_packet.set_destination(ipv4_packet.destination);
+                mutable_ipv4_packet.set_options(&[]);
+
+                // ```
+                // mutable_ipv4_packet.set_total_length((IPV4_HEADER_LEN + tcp_header_len) as u16);
+                // mutable_ipv4_packet.set_checksum(ipv4::checksum(&mutable_ipv4_packet.to_immutable()));
+                // ```
+            }
+            IpAddr::V6(source_addr) => {
+                // IPv6
+                let mut mutable_ipv6_packet =
+                    MutableIpv6Packet::new(&mut packet[eth_header_len..]).unwrap();
+
+                mutable_ipv6_packet.set_version(6);
+                mutable_ipv6_packet.set_traffic_class(0);
+                mutable_ipv6_packet.set_flow_label(0);
+                mutable_ipv6_packet.set_next_header(IpNextHeaderProtocols::Tcp);
+                mutable_ipv6_packet.set_hop_limit(repr.ttl);
+                mutable_ipv6_packet.set_source(source_addr);
+                // the payload length and destination are set in build
+            }
+        }
 
         if eth_header_len > 0 {
             // Ethernet
             let ethernet_packet = Ethernet {
                 destination: repr.gateway_mac.unwrap(),
                 source: repr.interface_mac.unwrap(),
-                ethertype: EtherTypes::Ipv4,
+                ethertype: match repr.source_addr {
+                    IpAddr::V4(_) => EtherTypes::Ipv4,
+                    IpAddr::V6(_) => EtherTypes::Ipv6,
+                },
                 payload: vec![],
             };
             assert_eq!(
-                packet[..packet.len() - tcp_header_len - IPV4_HEADER_LEN],
+                packet[..packet.len() - tcp_header_len - ip_header_len],
                 vec![0u8; eth_header_len]
             );
             let mut mutable_ethernet_packet = MutableEthernetPacket::new(&mut packet).unwrap();
@@ -136,20 +178,37 @@ impl TemplatePacket {
             source_addr: repr.source_addr,
 
             eth_header_len,
+            ip_header_len,
             tcp_header_len,
+            timestamp_offset,
         }
     }
 
-    /// Build the packet with the given options
+    pub fn source_addr(&self) -> IpAddr {
+        self.source_addr
+    }
+
+    /// Set the TSval sent in packets built from now on, if the template has a
+    /// timestamp option
+    pub fn set_timestamp(&mut self, timestamp: u32) {
+        if let Some(offset) = self.timestamp_offset {
+            self.packet[offset..offset + 4].copy_from_slice(&timestamp.to_be_bytes());
+        }
+    }
+
+    /// Build the packet with the given options.
+    ///
+    /// Panics if `repr.dest_addr` isn't the same family as the template's
+    /// source address.
     pub fn build(&mut self, repr: PacketRepr) -> &[u8] {
         self.packet.resize(
-            self.eth_header_len + IPV4_HEADER_LEN + self.tcp_header_len + repr.payload.len(),
+            self.eth_header_len + self.ip_header_len + self.tcp_header_len + repr.payload.len(),
             0,
         );
 
         // TCP
         let mut mutable_tcp_packet =
-            MutableTcpPacket::new(&mut self.packet[self.eth_header_len + IPV4_HEADER_LEN..])
+            MutableTcpPacket::new(&mut self.packet[self.eth_header_len + self.ip_header_len..])
                 .unwrap();
         mutable_tcp_packet.set_source(repr.source_port);
         mutable_tcp_packet.set_destination(repr.dest_port);
@@ -158,24 +217,110 @@ impl TemplatePacket {
         if !repr.payload.is_empty() {
             mutable_tcp_packet.payload_mut()[..repr.payload.len()].copy_from_slice(repr.payload);
         }
-        let checksum = pnet::packet::tcp::ipv4_checksum(
-            &mutable_tcp_packet.to_immutable(),
-            &self.source_addr,
-            &repr.dest_addr,
-        );
-        mutable_tcp_packet.set_checksum(checksum);
 
-        // IPv4
-        let mut mutable_ipv4_packet: MutableIpv4Packet =
-            MutableIpv4Packet::new(&mut self.packet[self.eth_header_len..]).unwrap();
-        mutable_ipv4_packet.set_destination(repr.dest_addr);
-        mutable_ipv4_packet
-            .set_total_length((IPV4_HEADER_LEN + self.tcp_header_len + repr.payload.len()) as u16);
+        match (self.source_addr, repr.dest_addr) {
+            (IpAddr::V4(source_addr), IpAddr::V4(dest_addr)) => {
+                let checksum = pnet::packet::tcp::ipv4_checksum(
+                    &mutable_tcp_packet.to_immutable(),
+                    &source_addr,
+                    &dest_addr,
+                );
+                mutable_tcp_packet.set_checksum(checksum);
 
-        mutable_ipv4_packet.set_checksum(ipv4::checksum(&mutable_ipv4_packet.to_immutable()));
+                // IPv4
+                let mut mutable_ipv4_packet: MutableIpv4Packet =
+                    MutableIpv4Packet::new(&mut self.packet[self.eth_header_len..]).unwrap();
+                mutable_ipv4_packet.set_destination(dest_addr);
+                mutable_ipv4_packet.set_total_length(
+                    (IPV4_HEADER_LEN + self.tcp_header_len + repr.payload.len()) as u16,
+                );
+
+                mutable_ipv4_packet
+                    .set_checksum(ipv4::checksum(&mutable_ipv4_packet.to_immutable()));
+            }
+            (IpAddr::V6(source_addr), IpAddr::V6(dest_addr)) => {
+                let checksum = pnet::packet::tcp::ipv6_checksum(
+                    &mutable_tcp_packet.to_immutable(),
+                    &source_addr,
+                    &dest_addr,
+                );
+                mutable_tcp_packet.set_checksum(checksum);
+
+                // IPv6, which has no header checksum
+                let mut mutable_ipv6_packet =
+                    MutableIpv6Packet::new(&mut self.packet[self.eth_header_len..]).unwrap();
+                mutable_ipv6_packet.set_destination(dest_addr);
+                mutable_ipv6_packet
+                    .set_payload_length((self.tcp_header_len + repr.payload.len()) as u16);
+            }
+            (source_addr, dest_addr) => {
+                panic!("can't send from {source_addr} to {dest_addr}, they're different families")
+            }
+        }
 
         // the ethernet fields are already good
 
         &self.packet
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::net::tcp::TcpFingerprint;
+    use pnet::packet::{
+        ipv4::Ipv4Packet,
+        tcp::{TcpOptionNumbers, TcpPacket},
+        Packet,
+    };
+    use std::net::Ipv4Addr;
+
+    fn timestamps(packet: &[u8]) -> (u32, u32) {
+        let ip = Ipv4Packet::new(packet).unwrap();
+        let tcp = TcpPacket::new(ip.payload()).unwrap();
+        let option = tcp
+            .get_options()
+            .into_iter()
+            .find(|option| option.number == TcpOptionNumbers::TIMESTAMPS)
+            .unwrap();
+        (
+            u32::from_be_bytes(option.data[..4].try_into().unwrap()),
+            u32::from_be_bytes(option.data[4..].try_into().unwrap()),
+        )
+    }
+
+    #[test]
+    fn timestamp_is_set_per_packet() {
+        let mut template = TemplatePacket::new(TemplatePacketRepr {
+            flags: 0,
+            window: 64240,
+            ttl: 64,
+            urgent_ptr: 0,
+            options: TcpFingerprint::linux().syn_options(1360),
+            gateway_mac: None,
+            interface_mac: None,
+            source_addr: IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)),
+        });
+        let repr = || PacketRepr {
+            dest_addr: IpAddr::V4(Ipv4Addr::new(192, 0, 2, 2)),
+            dest_port: 25565,
+            source_port: 61000,
+            sequence: 1,
+            acknowledgement: 0,
+            payload: &[],
+        };
+
+        template.set_timestamp(1234);
+        assert_eq!(timestamps(template.build(repr())), (1234, 0));
+        template.set_timestamp(5678);
+        let packet = template.build(repr()).to_vec();
+        assert_eq!(timestamps(&packet), (5678, 0));
+
+        let ip = Ipv4Packet::new(&packet).unwrap();
+        let tcp = TcpPacket::new(ip.payload()).unwrap();
+        assert_eq!(
+            tcp.get_checksum(),
+            pnet::packet::tcp::ipv4_checksum(&tcp, &ip.get_source(), &ip.get_destination())
+        );
+    }
+}
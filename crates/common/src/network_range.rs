@@ -1,5 +1,6 @@
+use perfect_rand::PerfectRng;
 use serde::{Deserialize, Serialize};
-use std::net::SocketAddrV4;
+use std::net::{SocketAddr, SocketAddrV4, SocketAddrV6};
 
 use crate::addr_range::Ipv4AddrRange;
 
@@ -85,12 +86,16 @@ impl SocketAddrV4Range {
         }
     }
 
+    /// Ranges that end before they start are empty
     pub fn count_addresses(&self) -> u64 {
-        let ip_count = 1 + u32::from(*self.end.ip()) - u32::from(*self.start.ip());
-        let port_count = 1 as u64 + self.end.port() as u64 - self.start.port() as u64;
-        ip_count as u64 * port_count
+        let ip_count = (u32::from(*self.end.ip()) as u64 + 1)
+            .saturating_sub(u32::from(*self.start.ip()) as u64);
+        let port_count = (self.end.port() as u64 + 1).saturating_sub(self.start.port() as u64);
+        ip_count * port_count
     }
 
+    /// The `index`th address in the range, going through every port of an ip
+    /// before moving on to the next ip. [`ScanOrder`] does the shuffling.
     pub fn random(&self, index: u64) -> SocketAddrV4 {
         let start_port = self.start.port();
         let start_ip = u32::from(*self.start.ip());
@@ -131,11 +136,122 @@ impl From<(Ipv4AddrRange, u16)> for SocketAddrV4Range {
     }
 }
 
+/// IPv6 addresses can't be enumerated, so these ranges are meant to be small
+/// (e.g. from a hitlist) and only the lowest 64 bits of the address are
+/// counted.
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct SocketAddrV6Range {
+    pub start: SocketAddrV6,
+    pub end: SocketAddrV6,
+}
+
+impl SocketAddrV6Range {
+    pub fn new(start: SocketAddrV6, end: SocketAddrV6) -> Self {
+        Self { start, end }
+    }
+
+    pub fn contains(&self, addr: &SocketAddrV6) -> bool {
+        addr.ip() >= self.start.ip()
+            && addr.ip() <= self.end.ip()
+            && addr.port() >= self.start.port()
+            && addr.port() <= self.end.port()
+    }
+
+    /// Ranges that end before they start are empty
+    pub fn count_addresses(&self) -> u64 {
+        let ip_count = u128::from(*self.end.ip())
+            .checked_sub(u128::from(*self.start.ip()))
+            .map_or(0, |count| {
+                count.saturating_add(1).min(u64::MAX as u128) as u64
+            });
+        let port_count = (self.end.port() as u64 + 1).saturating_sub(self.start.port() as u64);
+        ip_count.saturating_mul(port_count)
+    }
+
+    pub fn random(&self, index: u64) -> SocketAddrV6 {
+        let start_port = self.start.port();
+        let start_ip = u128::from(*self.start.ip());
+
+        let port_count = 1 + self.end.port() as u64 - self.start.port() as u64;
+        let ip = (index / port_count) as u128;
+        let port = (index % port_count) as u16;
+        SocketAddrV6::new((start_ip + ip).into(), start_port + port, 0, 0)
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub enum SocketAddrRange {
+    V4(SocketAddrV4Range),
+    V6(SocketAddrV6Range),
+}
+
+impl SocketAddrRange {
+    pub fn contains(&self, addr: &SocketAddr) -> bool {
+        match (self, addr) {
+            (SocketAddrRange::V4(range), SocketAddr::V4(addr)) => range.contains(addr),
+            (SocketAddrRange::V6(range), SocketAddr::V6(addr)) => range.contains(addr),
+            _ => false,
+        }
+    }
+
+    pub fn count_addresses(&self) -> u64 {
+        match self {
+            SocketAddrRange::V4(range) => range.count_addresses(),
+            SocketAddrRange::V6(range) => range.count_addresses(),
+        }
+    }
+
+    pub fn random(&self, index: u64) -> SocketAddr {
+        match self {
+            SocketAddrRange::V4(range) => range.random(index).into(),
+            SocketAddrRange::V6(range) => range.random(index).into(),
+        }
+    }
+}
+
+impl From<SocketAddrV4Range> for SocketAddrRange {
+    fn from(value: SocketAddrV4Range) -> Self {
+        SocketAddrRange::V4(value)
+    }
+}
+
+impl From<SocketAddrV6Range> for SocketAddrRange {
+    fn from(value: SocketAddrV6Range) -> Self {
+        SocketAddrRange::V6(value)
+    }
+}
+
 pub trait RangesExt {
+    type Addr;
+
     fn count_addresses(&self) -> u64;
-    fn get_addr_at(&self, index: u64) -> SocketAddrV4;
+    fn get_addr_at(&self, index: u64) -> Self::Addr;
+}
+impl RangesExt for Vec<SocketAddrRange> {
+    type Addr = SocketAddr;
+
+    fn count_addresses(&self) -> u64 {
+        self.iter().map(|range| range.count_addresses()).sum()
+    }
+
+    fn get_addr_at(&self, index: u64) -> SocketAddr {
+        let mut cursor = 0;
+        let mut cursor_total = 0;
+        while let Some(range) = self.get(cursor) {
+            let range_size = range.count_addresses();
+            if cursor_total + range_size <= index {
+                cursor_total += range_size;
+                cursor += 1;
+                continue;
+            }
+            return range.random(index - cursor_total);
+        }
+        panic!(":(")
+    }
 }
 impl RangesExt for Vec<SocketAddrV4Range> {
+    type Addr = SocketAddrV4;
+
     fn count_addresses(&self) -> u64 {
         self.iter().map(|range| range.count_addresses()).sum()
     }
@@ -155,3 +271,131 @@ impl RangesExt for Vec<SocketAddrV4Range> {
         panic!(":(")
     }
 }
+
+/// The order addresses are scanned in. Indexes are shuffled with a Feistel
+/// permutation so consecutive probes land all over the ranges instead of
+/// walking a /24 one address at a time, and the shuffled index is looked up
+/// with a binary search over where every range starts.
+pub struct ScanOrder {
+    ranges: Vec<SocketAddrRange>,
+    /// The index of the first address in every range
+    starts: Vec<u64>,
+    total: u64,
+    seed: u64,
+    rng: PerfectRng,
+}
+
+impl ScanOrder {
+    pub fn new(ranges: Vec<SocketAddrRange>, seed: u64) -> Self {
+        let mut starts = Vec::with_capacity(ranges.len());
+        let mut total = 0u64;
+        for range in &ranges {
+            starts.push(total);
+            total = total.saturating_add(range.count_addresses());
+        }
+        Self {
+            ranges,
+            starts,
+            total,
+            seed,
+            // the rng can't shuffle an empty range, but then it's never used
+            rng: PerfectRng::new(total.max(1), seed, 3),
+        }
+    }
+
+    pub fn ranges(&self) -> &[SocketAddrRange] {
+        &self.ranges
+    }
+
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    pub fn count_addresses(&self) -> u64 {
+        self.total
+    }
+
+    /// The `index`th address to scan, every index below
+    /// [`ScanOrder::count_addresses`] gives a different address.
+    pub fn get_addr_at(&self, index: u64) -> SocketAddr {
+        let index = self.rng.shuffle(index);
+        let range = self.starts.partition_point(|start| *start <= index) - 1;
+        self.ranges[range].random(index - self.starts[range])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    fn v4(start: &str, end: &str) -> SocketAddrRange {
+        SocketAddrV4Range::new(start.parse().unwrap(), end.parse().unwrap()).into()
+    }
+
+    fn v6(start: &str, end: &str) -> SocketAddrRange {
+        SocketAddrV6Range::new(start.parse().unwrap(), end.parse().unwrap()).into()
+    }
+
+    /// Every address `order` gives, checking that they're all different and
+    /// in one of the ranges
+    fn scan(order: &ScanOrder) -> Vec<SocketAddr> {
+        let addrs = (0..order.count_addresses())
+            .map(|index| order.get_addr_at(index))
+            .collect::<Vec<_>>();
+        let unique = addrs.iter().collect::<HashSet<_>>();
+        assert_eq!(unique.len(), addrs.len(), "{addrs:?}");
+        for addr in &addrs {
+            assert!(
+                order.ranges().iter().any(|range| range.contains(addr)),
+                "{addr} isn't in any range"
+            );
+        }
+        addrs
+    }
+
+    #[test]
+    fn scans_a_single_address() {
+        let order = ScanOrder::new(vec![v4("10.0.0.1:25565", "10.0.0.1:25565")], 1);
+        assert_eq!(order.count_addresses(), 1);
+        assert_eq!(scan(&order), vec!["10.0.0.1:25565".parse().unwrap()]);
+    }
+
+    #[test]
+    fn scans_every_address_once() {
+        let ranges = vec![
+            // 4 ips with 2 ports each
+            v4("10.0.0.0:25565", "10.0.0.3:25566"),
+            // empty, it ends before it starts
+            v4("10.0.1.5:25565", "10.0.1.4:25565"),
+            v6("[2001:db8::1]:25565", "[2001:db8::3]:25565"),
+            v6("[2001:db8::9]:25565", "[2001:db8::8]:25565"),
+            v4("10.0.2.0:80", "10.0.2.0:80"),
+        ];
+        for seed in [0, 1, 0xdead_beef] {
+            let order = ScanOrder::new(ranges.clone(), seed);
+            assert_eq!(order.count_addresses(), 8 + 3 + 1);
+            let addrs = scan(&order);
+            assert!(addrs.iter().any(SocketAddr::is_ipv4));
+            assert!(addrs.iter().any(SocketAddr::is_ipv6));
+        }
+    }
+
+    #[test]
+    fn scans_nothing_when_every_range_is_empty() {
+        let order = ScanOrder::new(vec![v4("10.0.0.1:25565", "10.0.0.0:25565")], 1);
+        assert_eq!(order.count_addresses(), 0);
+        assert_eq!(ScanOrder::new(Vec::new(), 1).count_addresses(), 0);
+    }
+
+    #[test]
+    fn same_seed_same_order() {
+        let ranges = vec![
+            v4("10.0.0.0:25565", "10.0.0.255:25565"),
+            v6("[2001:db8::]:25565", "[2001:db8::ff]:25565"),
+        ];
+        let first = scan(&ScanOrder::new(ranges.clone(), 42));
+        let second = scan(&ScanOrder::new(ranges, 42));
+        assert_eq!(first, second);
+    }
+}
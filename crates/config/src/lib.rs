@@ -1,12 +1,23 @@
+use common::net::{
+    firewall::Firewall, source_ip::SourceIpSelection, source_port::SourcePort,
+    tcp::FingerprintProfile,
+};
 use serde::Deserialize;
 use smart_default::SmartDefault;
 use std::{
+    net::{IpAddr, SocketAddr},
     path::{Path, PathBuf},
+    str::FromStr,
     sync::{Arc, LazyLock},
 };
 
-static CONFIG: LazyLock<Arc<Config>> =
-    LazyLock::new(|| Arc::new(Config::new("Snowstorm.toml").expect("Snowstorm.toml not found")));
+/// `Snowstorm.toml`, or the file `SNOWSTORM_CONFIG` points to
+static CONFIG: LazyLock<Arc<Config>> = LazyLock::new(|| {
+    let path = std::env::var_os("SNOWSTORM_CONFIG")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from("Snowstorm.toml"));
+    Arc::new(Config::new(&path).unwrap_or_else(|_| panic!("{} not found", path.display())))
+});
 
 #[derive(Deserialize)]
 pub struct Config {
@@ -30,6 +41,9 @@ pub struct Config {
 
     #[serde(default)]
     pub ram_server: RamServerConfig,
+
+    #[serde(default)]
+    pub proxy: ProxyConfig,
 }
 
 impl Config {
@@ -67,13 +81,113 @@ pub struct ScannerConfig {
     #[serde(default = "_true")]
     #[default = false]
     pub enabled: bool,
+    /// Can be overridden with `--backend`
+    #[serde(default)]
+    pub backend: Backend,
     pub interface_name: String,
-    #[serde(default = "default_source_port")]
-    pub source_port: u16,
+    /// Addresses to send probes from, the interface's own if empty
+    #[serde(default)]
+    pub source_ips: Vec<IpAddr>,
+    #[serde(default)]
+    pub source_ip_selection: SourceIpSelection,
+    /// A port or a `{ min, max }` range, 61000 by default
+    #[serde(default)]
+    pub source_port: SourcePort,
+    /// How the firewall rule for the source port is set up
+    #[serde(default)]
+    pub firewall: Firewall,
     pub task_size_sanity_limit: u64,
     pub mode_duration: u64,
     #[serde(default = "_true")]
     pub push_to_db: bool,
+    #[serde(default)]
+    pub max_pps: u64,
+    #[serde(default = "default_burst_size")]
+    #[default = 1024]
+    pub burst_size: u64,
+    #[serde(default = "default_connection_timeout")]
+    #[default = 10]
+    pub connection_timeout: u64,
+    #[serde(default = "default_max_connections")]
+    #[default = 1_000_000]
+    pub max_connections: usize,
+    #[serde(default = "default_max_response_bytes")]
+    #[default = 1_048_576]
+    pub max_response_bytes: usize,
+    /// How many times every address is pinged again in case a ping was lost
+    #[serde(default)]
+    pub retries: u8,
+    /// Seconds between the tries
+    #[serde(default = "default_retry_delay")]
+    #[default = 2]
+    pub retry_delay: u64,
+    #[serde(default = "default_receive_workers")]
+    #[default = 4]
+    pub receive_workers: usize,
+    #[serde(default = "default_receive_queue_size")]
+    #[default = 8192]
+    pub receive_queue_size: usize,
+    /// Status responses decoded at once, workers wait for a free slot after
+    /// that
+    #[serde(default = "default_decode_tasks")]
+    #[default = 1024]
+    pub decode_tasks: usize,
+    #[serde(default)]
+    pub fingerprint: FingerprintProfile,
+    /// IPv6 addresses to scan, see `common::hitlist` for the format
+    pub ipv6_hitlist: Option<PathBuf>,
+    /// Where the progress of a scan is saved so it can be resumed
+    pub checkpoint: Option<PathBuf>,
+    /// Seconds between saving the progress
+    #[serde(default = "default_checkpoint_interval")]
+    #[default = 60]
+    pub checkpoint_interval: u64,
+    /// Capture the `pcap` backend replays the servers from
+    pub pcap_replay: Option<PathBuf>,
+    /// Where the `pcap` backend writes every packet it sends and gets
+    pub pcap_output: Option<PathBuf>,
+}
+
+/// How servers are pinged, see the `io` crate
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Backend {
+    /// Stateless SYN scanning with raw sockets, needs CAP_NET_RAW
+    Pnet,
+    /// A full TCP connection for every address, through `[proxy]`
+    Tcp,
+    /// Answers from the servers in `testing_data` instead of the network
+    Replay,
+    /// Like `pnet`, but the servers' side comes from `scanner.pcap_replay`
+    Pcap,
+}
+
+/// Debug builds replay `testing_data` unless told otherwise, so they never
+/// scan the internet by accident
+impl Default for Backend {
+    fn default() -> Self {
+        if cfg!(debug_assertions) {
+            Backend::Replay
+        } else {
+            Backend::Pnet
+        }
+    }
+}
+
+impl FromStr for Backend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "pnet" => Ok(Backend::Pnet),
+            "tcp" => Ok(Backend::Tcp),
+            "replay" => Ok(Backend::Replay),
+            "pcap" => Ok(Backend::Pcap),
+            _ => Err(format!(
+                "unknown backend '{s}', expected pnet, tcp, replay or pcap"
+            )),
+        }
+    }
 }
 
 #[derive(Deserialize, SmartDefault)]
@@ -100,6 +214,38 @@ pub struct RamServerConfig {
     pub temp_fs_path: PathBuf,
 }
 
+/// Where status pings over full connections and joins come from, see
+/// `io::proxy`. The SYN scanner always sends from `scanner.interface_name`.
+#[derive(Deserialize, Default)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ProxyConfig {
+    #[default]
+    Direct,
+    /// Connections are spread over the servers round robin
+    Socks5 {
+        servers: Vec<SocketAddr>,
+        username: Option<String>,
+        password: Option<String>,
+    },
+    /// A userspace WireGuard tunnel
+    Wireguard(WireguardConfig),
+}
+
+/// The peer we tunnel through, keys are base64 like `wg` prints them
+#[derive(Deserialize)]
+pub struct WireguardConfig {
+    pub private_key: String,
+    pub peer_public_key: String,
+    pub preshared_key: Option<String>,
+    /// Where the peer listens
+    pub endpoint: SocketAddr,
+    /// Our address inside the tunnel
+    pub address: IpAddr,
+    /// Seconds between keepalives, for peers that are only reachable while
+    /// a NAT mapping is open
+    pub persistent_keepalive: Option<u16>,
+}
+
 #[derive(Deserialize, SmartDefault)]
 pub struct OauthConfig {
     #[serde(default)]
@@ -136,6 +282,30 @@ pub struct OauthForgejoConfig {
 const fn _true() -> bool {
     true
 }
-const fn default_source_port() -> u16 {
-    61000
+const fn default_burst_size() -> u64 {
+    1024
+}
+const fn default_connection_timeout() -> u64 {
+    10
+}
+const fn default_max_connections() -> usize {
+    1_000_000
+}
+const fn default_max_response_bytes() -> usize {
+    1_048_576
+}
+const fn default_retry_delay() -> u64 {
+    2
+}
+const fn default_checkpoint_interval() -> u64 {
+    60
+}
+const fn default_receive_workers() -> usize {
+    4
+}
+const fn default_receive_queue_size() -> usize {
+    8192
+}
+const fn default_decode_tasks() -> usize {
+    1024
 }
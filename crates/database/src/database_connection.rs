@@ -1,5 +1,5 @@
 use sqlx::{postgres::PgPoolOptions, PgPool, Row};
-use std::net::{Ipv4Addr, SocketAddrV4};
+use std::net::{IpAddr, SocketAddr};
 
 #[derive(Clone, Debug)]
 pub struct DatabaseConnection {
@@ -15,18 +15,13 @@ impl DatabaseConnection {
         Ok(Self { pool })
     }
 
-    pub async fn get_rescan(&self) -> eyre::Result<Vec<SocketAddrV4>> {
+    pub async fn get_rescan(&self) -> eyre::Result<Vec<SocketAddr>> {
         let res = sqlx::query("SELECT ip, port FROM servers")
             .fetch_all(&self.pool)
             .await?;
         Ok(res
             .iter()
-            .map(|id| {
-                SocketAddrV4::new(
-                    Ipv4Addr::from(id.get::<i32, _>("ip") as u32),
-                    id.get::<i16, _>("port") as u16,
-                )
-            })
+            .map(|id| SocketAddr::new(id.get::<IpAddr, _>("ip"), id.get::<i16, _>("port") as u16))
             .collect())
     }
 }
@@ -46,6 +46,14 @@ impl ForgejoUserInfo {
             .await
             .unwrap()
     }
+
+    pub async fn get_user_id(user_id: i64, pool: &PgPool) -> Option<Self> {
+        sqlx::query_as("SELECT * FROM forgejo_users WHERE user_id = $1::BIGINT")
+            .bind(user_id)
+            .fetch_optional(pool)
+            .await
+            .unwrap()
+    }
 }
 
 impl DbPush for ForgejoUserInfo {
@@ -6,6 +6,7 @@ pub mod autocomplete;
 pub mod database_connection;
 pub mod discord_user;
 pub mod forgejo_user;
+pub mod opt_out;
 pub mod player;
 pub mod server;
 pub mod server_joins;
@@ -0,0 +1,279 @@
+use super::DbPush;
+use serde::Serialize;
+use sqlx::{
+    types::ipnetwork::{IpNetwork, Ipv4Network, Ipv6Network},
+    PgPool, Row,
+};
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+/// A network whose owner asked not to be scanned. Opt-outs are never deleted,
+/// removing one only sets `removed_at` so the log stays complete.
+#[derive(sqlx::FromRow, Serialize, Debug)]
+pub struct OptOut {
+    pub id: Option<i64>,
+    pub cidr: IpNetwork,
+    /// Who asked, usually an email address
+    pub requester: String,
+    pub reason: String,
+    /// When they asked, which isn't always when it was recorded
+    pub requested_at: i64,
+    /// The user that recorded it
+    pub created_by: i64,
+    /// Where it was recorded from, `web` or `discord`
+    pub created_via: String,
+    pub created_at: i64,
+    pub removed_at: Option<i64>,
+}
+
+/// An entry in the log of every change to the opt-outs
+#[derive(sqlx::FromRow, Serialize, Debug)]
+pub struct OptOutLog {
+    pub id: i64,
+    pub opt_out_id: i64,
+    /// `added` or `removed`
+    pub action: String,
+    pub user_id: i64,
+    pub via: String,
+    pub at: i64,
+}
+
+impl OptOut {
+    /// Host bits are cleared, so `10.0.0.1/8` opts out `10.0.0.0/8`.
+    pub fn new(
+        cidr: &str,
+        requester: &str,
+        reason: &str,
+        requested_at: i64,
+        created_by: i64,
+        created_via: &str,
+    ) -> eyre::Result<Self> {
+        let cidr = match cidr.trim().parse::<IpNetwork>()? {
+            IpNetwork::V4(network) => {
+                IpNetwork::V4(Ipv4Network::new(network.network(), network.prefix())?)
+            }
+            IpNetwork::V6(network) => {
+                IpNetwork::V6(Ipv6Network::new(network.network(), network.prefix())?)
+            }
+        };
+        Ok(Self {
+            id: None,
+            cidr,
+            requester: requester.to_string(),
+            reason: reason.to_string(),
+            requested_at,
+            created_by,
+            created_via: created_via.to_string(),
+            created_at: 0,
+            removed_at: None,
+        })
+    }
+
+    /// The first and last address of the network, if it's IPv4
+    pub fn ipv4_range(&self) -> Option<(Ipv4Addr, Ipv4Addr)> {
+        match self.cidr {
+            IpNetwork::V4(network) => Some((network.network(), network.broadcast())),
+            IpNetwork::V6(_) => None,
+        }
+    }
+
+    /// The first and last address of the network, if it's IPv6
+    pub fn ipv6_range(&self) -> Option<(Ipv6Addr, Ipv6Addr)> {
+        match self.cidr {
+            IpNetwork::V4(_) => None,
+            IpNetwork::V6(network) => {
+                let last = u128::from(network.network()) | !u128::from(network.mask());
+                Some((network.network(), last.into()))
+            }
+        }
+    }
+
+    pub async fn get_id(id: i64, pool: &PgPool) -> Option<Self> {
+        sqlx::query_as("SELECT * FROM opt_outs WHERE id = $1::BIGINT")
+            .bind(id)
+            .fetch_optional(pool)
+            .await
+            .unwrap()
+    }
+
+    pub async fn get_all(pool: &PgPool) -> Vec<Self> {
+        sqlx::query_as("SELECT * FROM opt_outs ORDER BY id")
+            .fetch_all(pool)
+            .await
+            .unwrap()
+    }
+
+    /// The opt-outs that weren't removed. Unlike the other getters this
+    /// doesn't panic, since the scanner polls it in the background.
+    pub async fn get_active(pool: &PgPool) -> eyre::Result<Vec<Self>> {
+        Ok(
+            sqlx::query_as("SELECT * FROM opt_outs WHERE removed_at IS NULL ORDER BY id")
+                .fetch_all(pool)
+                .await?,
+        )
+    }
+
+    pub async fn remove(&mut self, user_id: i64, via: &str, pool: &PgPool) -> eyre::Result<()> {
+        let Some(id) = self.id else {
+            return Err(eyre::eyre!("opt-out was never pushed"));
+        };
+        if self.removed_at.is_some() {
+            return Err(eyre::eyre!("opt-out {id} was already removed"));
+        }
+        let mut tx = pool.begin().await?;
+        // checked again in the update, someone else might've removed it since
+        // we read it
+        let Some(row) = sqlx::query(
+            "UPDATE opt_outs SET removed_at = EXTRACT(epoch from now())
+            WHERE id = $1::BIGINT AND removed_at IS NULL
+            RETURNING removed_at",
+        )
+        .bind(id)
+        .fetch_optional(&mut *tx)
+        .await?
+        else {
+            return Err(eyre::eyre!("opt-out {id} was already removed"));
+        };
+        let removed_at: i64 = row.get("removed_at");
+        log(id, "removed", user_id, via, &mut tx).await?;
+        tx.commit().await?;
+        self.removed_at = Some(removed_at);
+        Ok(())
+    }
+}
+
+impl OptOutLog {
+    pub async fn get_all(pool: &PgPool) -> Vec<Self> {
+        sqlx::query_as("SELECT * FROM opt_out_log ORDER BY id")
+            .fetch_all(pool)
+            .await
+            .unwrap()
+    }
+}
+
+async fn log(
+    opt_out_id: i64,
+    action: &str,
+    user_id: i64,
+    via: &str,
+    tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "INSERT INTO opt_out_log (opt_out_id, action, user_id, via)
+        VALUES ($1::BIGINT, $2::TEXT, $3::BIGINT, $4::TEXT)",
+    )
+    .bind(opt_out_id)
+    .bind(action)
+    .bind(user_id)
+    .bind(via)
+    .execute(&mut **tx)
+    .await?;
+    Ok(())
+}
+
+impl DbPush for OptOut {
+    /// Opt-outs can only be added, changing one means removing it and adding
+    /// a new one
+    async fn push(&mut self, pool: &PgPool) -> Result<(), eyre::Report> {
+        if let Some(id) = self.id {
+            return Err(eyre::eyre!("opt-out {id} was already pushed"));
+        }
+        let mut tx = pool.begin().await?;
+        let row = sqlx::query(
+            "INSERT INTO opt_outs (
+                cidr,
+                requester,
+                reason,
+                requested_at,
+                created_by,
+                created_via
+            ) VALUES (
+                $1::CIDR,
+                $2::TEXT,
+                $3::TEXT,
+                $4::BIGINT,
+                $5::BIGINT,
+                $6::TEXT
+            )
+            RETURNING id, created_at",
+        )
+        .bind(self.cidr)
+        .bind(&self.requester)
+        .bind(&self.reason)
+        .bind(self.requested_at)
+        .bind(self.created_by)
+        .bind(&self.created_via)
+        .fetch_one(&mut *tx)
+        .await?;
+        let id: i64 = row.get("id");
+        log(id, "added", self.created_by, &self.created_via, &mut tx).await?;
+        tx.commit().await?;
+
+        self.id = Some(id);
+        self.created_at = row.get("created_at");
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn opt_out(cidr: &str) -> OptOut {
+        OptOut::new(cidr, "abuse@example.com", "no thanks", 0, 1, "web").unwrap()
+    }
+
+    #[test]
+    fn clears_host_bits() {
+        assert_eq!(
+            opt_out("10.1.2.3/8").cidr,
+            "10.0.0.0/8".parse::<IpNetwork>().unwrap()
+        );
+        assert_eq!(
+            opt_out(" 2001:db8::1/32 ").cidr,
+            "2001:db8::/32".parse::<IpNetwork>().unwrap()
+        );
+        assert_eq!(
+            opt_out("10.1.2.3/32").cidr,
+            "10.1.2.3/32".parse::<IpNetwork>().unwrap()
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_networks() {
+        assert!(OptOut::new("10.0.0.0/33", "", "", 0, 1, "web").is_err());
+        assert!(OptOut::new("2001:db8::/129", "", "", 0, 1, "web").is_err());
+        assert!(OptOut::new("example.com", "", "", 0, 1, "web").is_err());
+    }
+
+    #[test]
+    fn ipv4_ranges() {
+        assert_eq!(
+            opt_out("10.1.2.3/16").ipv4_range(),
+            Some(("10.1.0.0".parse().unwrap(), "10.1.255.255".parse().unwrap()))
+        );
+        assert_eq!(opt_out("10.1.2.3/16").ipv6_range(), None);
+    }
+
+    #[test]
+    fn ipv6_ranges() {
+        assert_eq!(
+            opt_out("2001:db8::1/128").ipv6_range(),
+            Some((
+                "2001:db8::1".parse().unwrap(),
+                "2001:db8::1".parse().unwrap()
+            ))
+        );
+        assert_eq!(
+            opt_out("2001:db8::1/0").ipv6_range(),
+            Some((Ipv6Addr::UNSPECIFIED, Ipv6Addr::from(u128::MAX)))
+        );
+        assert_eq!(
+            opt_out("2001:db8::/64").ipv6_range(),
+            Some((
+                "2001:db8::".parse().unwrap(),
+                "2001:db8::ffff:ffff:ffff:ffff".parse().unwrap()
+            ))
+        );
+        assert_eq!(opt_out("2001:db8::/64").ipv4_range(), None);
+    }
+}
@@ -2,13 +2,13 @@ use super::DbPush;
 use azalea_protocol::packets::status::clientbound_status_response_packet::ClientboundStatusResponsePacket;
 use serde::Serialize;
 use sqlx::{PgPool, Row};
-use std::net::Ipv4Addr;
+use std::net::IpAddr;
 
 #[derive(Debug, Serialize, sqlx::FromRow)]
 pub struct PingResult {
     pub id: Option<i64>,
     // host info
-    pub ip: i32,
+    pub ip: IpAddr,
     pub port: i16,
     // ping results
     pub version_name: Option<String>,
@@ -27,22 +27,22 @@ pub struct PingResult {
 }
 
 impl PingResult {
-    pub fn ip(&self) -> Ipv4Addr {
-        (self.ip as u32).into()
+    pub fn ip(&self) -> IpAddr {
+        self.ip
     }
 
     pub fn port(&self) -> u16 {
         self.port as u16
     }
 
-    pub fn set_ip(&mut self, ip: Ipv4Addr) {
-        self.ip = u32::from(ip) as i32;
+    pub fn set_ip(&mut self, ip: IpAddr) {
+        self.ip = ip;
     }
 
-    pub fn none(ip: Ipv4Addr, port: u16) -> Self {
+    pub fn none(ip: IpAddr, port: u16) -> Self {
         Self {
             id: None,
-            ip: u32::from(ip) as i32,
+            ip,
             port: port as i16,
             version_name: None,
             version_protocol: None,
@@ -59,10 +59,10 @@ impl PingResult {
         }
     }
 
-    pub fn from_azalea(ip: Ipv4Addr, port: u16, value: &ClientboundStatusResponsePacket) -> Self {
+    pub fn from_azalea(ip: IpAddr, port: u16, value: &ClientboundStatusResponsePacket) -> Self {
         Self {
             id: None,
-            ip: u32::from(ip) as i32,
+            ip,
             port: port as i16,
             version_name: Some(value.version.name.clone()),
             version_protocol: Some(value.version.protocol),
@@ -92,12 +92,12 @@ impl PingResult {
             .unwrap()
     }
 
-    pub async fn from_ip_port(ip: &Ipv4Addr, port: u16, pool: &PgPool) -> Option<Self> {
+    pub async fn from_ip_port(ip: &IpAddr, port: u16, pool: &PgPool) -> Option<Self> {
         const QUERY_STRING: &str = "
-        SELECT * FROM servers WHERE ip = $1::INT AND port = $2::SMALLINT;
+        SELECT * FROM servers WHERE ip = $1::INET AND port = $2::SMALLINT;
         ";
         sqlx::query_as(QUERY_STRING)
-            .bind(u32::from(*ip) as i32)
+            .bind(ip)
             .bind(port as i16)
             .fetch_optional(pool)
             .await
@@ -132,7 +132,7 @@ impl DbPush for PingResult {
                     previews_chat,
                     geyser
                 ) VALUES (
-                    $2::INT,
+                    $2::INET,
                     $3::SMALLINT,
                     $4::TEXT,
                     $5::INT,
@@ -1,4 +1,4 @@
-use super::DbPush;
+use super::{forgejo_user::ForgejoUserInfo, DbPush};
 use sqlx::{PgPool, Row};
 
 #[derive(sqlx::FromRow, Debug, Default)]
@@ -19,6 +19,16 @@ impl User {
         }
     }
 
+    /// Admins are users linked to a Forgejo account that's a Forgejo admin
+    pub async fn is_admin(&self, pool: &PgPool) -> bool {
+        let Some(id) = self.id else {
+            return false;
+        };
+        ForgejoUserInfo::get_user_id(id, pool)
+            .await
+            .is_some_and(|forgejo_user| forgejo_user.is_admin)
+    }
+
     pub async fn get_id(id: i64, pool: &PgPool) -> Option<Self> {
         sqlx::query_as("SELECT * FROM users WHERE id = $1::BIGINT")
             .bind(id)
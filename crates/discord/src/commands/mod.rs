@@ -1,4 +1,5 @@
 pub mod api_key;
+pub mod opt_out;
 pub mod server_info;
 pub mod servers;
 pub mod test;
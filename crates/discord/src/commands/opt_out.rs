@@ -0,0 +1,137 @@
+use crate::{sanitize, Template, EMBED_COLOR_ERROR};
+use chrono::{NaiveDate, Utc};
+use database::{discord_user::DiscordUserInfo, opt_out::OptOut, user::User, DbPush};
+use serenity::{
+    all::{CommandOptionType, ResolvedOption, ResolvedValue, UserId},
+    builder::{
+        CreateCommand, CreateCommandOption, CreateEmbed, CreateEmbedFooter,
+        CreateInteractionResponse, CreateInteractionResponseMessage,
+    },
+};
+use sqlx::PgPool;
+
+pub async fn run(
+    pool: &PgPool,
+    discord_user_id: UserId,
+    options: &[ResolvedOption<'_>],
+) -> CreateInteractionResponse {
+    let user = match DiscordUserInfo::get_discord_id(&discord_user_id.to_string(), pool).await {
+        Some(DiscordUserInfo {
+            user_id: Some(user_id),
+            ..
+        }) => User::get_id(user_id, pool).await,
+        _ => None,
+    };
+    let Some(user) = user else {
+        return error("Nuh Uh !!!!!!", "only admins can record opt-outs");
+    };
+    if !user.is_admin(pool).await {
+        return error("Nuh Uh !!!!!!", "only admins can record opt-outs");
+    }
+
+    let option = |name: &str| {
+        options.iter().find_map(|option| match option {
+            ResolvedOption {
+                name: option_name,
+                value: ResolvedValue::String(value),
+                ..
+            } if *option_name == name => Some(*value),
+            _ => None,
+        })
+    };
+    let (Some(cidr), Some(requester), Some(reason)) =
+        (option("cidr"), option("requester"), option("reason"))
+    else {
+        return error("Missing Argument", "something weird happen");
+    };
+    let requested_at = match option("date") {
+        Some(date) => match NaiveDate::parse_from_str(date, "%Y-%m-%d") {
+            Ok(date) => date.and_hms_opt(0, 0, 0).unwrap().and_utc().timestamp(),
+            Err(err) => {
+                return error(
+                    "Invalid Argument",
+                    format!("Failed to parse `date` as YYYY-MM-DD\n\n`{err}`"),
+                )
+            }
+        },
+        None => Utc::now().timestamp(),
+    };
+
+    let opt_out = OptOut::new(
+        cidr,
+        requester,
+        reason,
+        requested_at,
+        user.id.unwrap(),
+        "discord",
+    );
+    let mut opt_out = match opt_out {
+        Ok(opt_out) => opt_out,
+        Err(err) => return error("Invalid Argument", format!("Invalid `cidr`\n\n`{err}`")),
+    };
+    if let Err(err) = opt_out.push(pool).await {
+        eprintln!("unable to add opt-out: {err}");
+        return error("Oops", "couldn't save the opt-out");
+    }
+    println!("{} opted out {}", user.username, opt_out.cidr);
+
+    CreateInteractionResponse::Message(
+        CreateInteractionResponseMessage::new()
+            .ephemeral(true)
+            .embed(
+                CreateEmbed::template()
+                    .title(format!("Opted out {}", opt_out.cidr))
+                    .field("Requester", sanitize(&opt_out.requester), true)
+                    .field("Requested", format!("<t:{requested_at}:D>"), true)
+                    .field("Reason", sanitize(&opt_out.reason), false)
+                    .footer(CreateEmbedFooter::new(format!(
+                        "Opt-out #{} \u{2022} applies from the next scan",
+                        opt_out.id.unwrap()
+                    ))),
+            ),
+    )
+}
+
+fn error(title: &str, description: impl Into<String>) -> CreateInteractionResponse {
+    CreateInteractionResponse::Message(
+        CreateInteractionResponseMessage::new()
+            .ephemeral(true)
+            .embed(
+                CreateEmbed::template()
+                    .color(EMBED_COLOR_ERROR)
+                    .title(title)
+                    .description(description),
+            ),
+    )
+}
+
+pub fn register() -> CreateCommand {
+    CreateCommand::new("opt_out")
+        .dm_permission(false)
+        .description("Record a network owner asking not to be scanned")
+        .add_option(
+            CreateCommandOption::new(
+                CommandOptionType::String,
+                "cidr",
+                "The network to stop scanning, like 192.0.2.0/24 or 2001:db8::/32",
+            )
+            .required(true),
+        )
+        .add_option(
+            CreateCommandOption::new(
+                CommandOptionType::String,
+                "requester",
+                "Who asked, usually their email address",
+            )
+            .required(true),
+        )
+        .add_option(
+            CreateCommandOption::new(CommandOptionType::String, "reason", "Why they asked")
+                .required(true),
+        )
+        .add_option(CreateCommandOption::new(
+            CommandOptionType::String,
+            "date",
+            "When they asked as YYYY-MM-DD, defaults to today",
+        ))
+}
@@ -7,7 +7,7 @@ use serenity::{
     },
 };
 use sqlx::PgPool;
-use std::{net::SocketAddrV4, str::FromStr, time::Instant};
+use std::{net::SocketAddr, str::FromStr, time::Instant};
 
 pub async fn run(pool: &PgPool, options: &[ResolvedOption<'_>]) -> CreateInteractionResponse {
     if let Some(ResolvedOption {
@@ -15,7 +15,7 @@ pub async fn run(pool: &PgPool, options: &[ResolvedOption<'_>]) -> CreateInterac
         ..
     }) = options.first()
     {
-        let addr = SocketAddrV4::from_str(server);
+        let addr = SocketAddr::from_str(server);
         let Ok(addr) = addr else {
             return CreateInteractionResponse::Message(
                 CreateInteractionResponseMessage::new().embed(
@@ -23,20 +23,20 @@ pub async fn run(pool: &PgPool, options: &[ResolvedOption<'_>]) -> CreateInterac
                         .color(EMBED_COLOR_ERROR)
                         .title("Invalid Argument")
                         .description(format!(
-                            "Failed to parse string as SocketV4Addr\n\n`{addr:?}`"
+                            "Failed to parse string as SocketAddr\n\n`{addr:?}`"
                         )),
                 ),
             );
         };
         let (ip, port) = (addr.ip(), addr.port());
         let start_time = Instant::now();
-        let server = database::server::PingResult::from_ip_port(ip, port, pool).await;
+        let server = database::server::PingResult::from_ip_port(&ip, port, pool).await;
         let end_time = Instant::now();
         let duration = end_time - start_time;
         if let Some(server) = server {
             let id = server.id.unwrap();
             let embed = CreateEmbed::template()
-                .title(format!("{}:{}", server.ip(), server.port()))
+                .title(SocketAddr::new(server.ip(), server.port()).to_string())
                 .url(format!("https://{}/server/{id}", config::get().web.domain))
                 .description(format!(
                     "{}\ndiscovered = {}, last seen = {}",
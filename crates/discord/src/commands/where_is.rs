@@ -8,7 +8,7 @@ use serenity::{
     },
 };
 use sqlx::PgPool;
-use std::time::Instant;
+use std::{net::SocketAddr, time::Instant};
 
 pub async fn run(pool: &PgPool, options: &[ResolvedOption<'_>]) -> CreateInteractionResponse {
     if let Some(ResolvedOption {
@@ -41,10 +41,9 @@ pub async fn run(pool: &PgPool, options: &[ResolvedOption<'_>]) -> CreateInterac
                 .enumerate()
                 .map(|(index, server)| {
                     format!(
-                        "{} `{}:{}`",
+                        "{} `{}`",
                         NUM_CODES[index + 1],
-                        server.ip(),
-                        server.port()
+                        SocketAddr::new(server.ip(), server.port())
                     )
                 })
                 .collect::<Vec<_>>();
@@ -107,6 +107,9 @@ impl EventHandler for Handler {
                     Some(commands::server_info::run(&pool, &command.data.options()).await)
                 }
                 "servers" => Some(commands::servers::run(&pool, &command.data.options()).await),
+                "opt_out" => Some(
+                    commands::opt_out::run(&pool, command.user.id, &command.data.options()).await,
+                ),
                 _ => {
                     let data =
                         CreateInteractionResponseMessage::new().content("not implemented :(");
@@ -139,6 +142,7 @@ impl EventHandler for Handler {
                 vec![
                     commands::api_key::register(),
                     commands::api_key::register_alias(),
+                    commands::opt_out::register(),
                 ],
             )
             .await
@@ -0,0 +1,79 @@
+use crate::{
+    database::DatabaseScanner, network::NetworkScanner, pcap::PcapScanner, pnet::PnetScanner,
+    proxy, Io, Probe, ScannerState,
+};
+use common::net::rate_limit::RateLimiter;
+use config::Backend;
+use database::{player::PlayerInfo, server::PingResult};
+use std::{
+    net::SocketAddr,
+    sync::{mpsc::Sender, Arc},
+};
+use tokio::sync::Mutex;
+
+/// The scanner picked with `scanner.backend`
+pub enum Scanner {
+    Pnet(PnetScanner),
+    Tcp(NetworkScanner),
+    Replay(DatabaseScanner),
+    Pcap(PcapScanner),
+}
+
+impl Scanner {
+    pub fn new(
+        backend: Backend,
+        state: Arc<Mutex<ScannerState>>,
+        sender: Sender<(PingResult, Vec<PlayerInfo>)>,
+        rate_limiter: RateLimiter,
+    ) -> eyre::Result<Self> {
+        Ok(match backend {
+            Backend::Pnet => Scanner::Pnet(PnetScanner::new(state, sender, rate_limiter)?),
+            Backend::Tcp => Scanner::Tcp(NetworkScanner::new(
+                state,
+                sender,
+                proxy::get_proxy()?,
+                rate_limiter,
+            )),
+            Backend::Replay => Scanner::Replay(DatabaseScanner::new(state, sender)?),
+            Backend::Pcap => Scanner::Pcap(PcapScanner::from_config(state, sender, rate_limiter)?),
+        })
+    }
+}
+
+impl Io for Scanner {
+    async fn ping(&mut self, addr: SocketAddr) -> eyre::Result<()> {
+        match self {
+            Scanner::Pnet(scanner) => scanner.ping(addr).await,
+            Scanner::Tcp(scanner) => scanner.ping(addr).await,
+            Scanner::Replay(scanner) => scanner.ping(addr).await,
+            Scanner::Pcap(scanner) => scanner.ping(addr).await,
+        }
+    }
+
+    async fn legacy_ping(&mut self, addr: SocketAddr) -> eyre::Result<()> {
+        match self {
+            Scanner::Pnet(scanner) => scanner.legacy_ping(addr).await,
+            Scanner::Tcp(scanner) => scanner.legacy_ping(addr).await,
+            Scanner::Replay(scanner) => scanner.legacy_ping(addr).await,
+            Scanner::Pcap(scanner) => scanner.legacy_ping(addr).await,
+        }
+    }
+
+    async fn retry(&mut self, addr: SocketAddr, probe: Probe, attempt: u8) -> eyre::Result<()> {
+        match self {
+            Scanner::Pnet(scanner) => scanner.retry(addr, probe, attempt).await,
+            Scanner::Tcp(scanner) => scanner.retry(addr, probe, attempt).await,
+            Scanner::Replay(scanner) => scanner.retry(addr, probe, attempt).await,
+            Scanner::Pcap(scanner) => scanner.retry(addr, probe, attempt).await,
+        }
+    }
+
+    fn flush(&mut self) {
+        match self {
+            Scanner::Pnet(scanner) => scanner.flush(),
+            Scanner::Tcp(scanner) => scanner.flush(),
+            Scanner::Replay(scanner) => scanner.flush(),
+            Scanner::Pcap(scanner) => scanner.flush(),
+        }
+    }
+}
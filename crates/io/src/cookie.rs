@@ -0,0 +1,207 @@
+//! SYN cookies, the sequence numbers that let us recognise responses to our
+//! probes without keeping any state.
+//!
+//! Cookies are a SipHash-2-4 of both ends of the connection and the attempt,
+//! keyed with a secret that changes every scan epoch. Without the key a forged
+//! SYN-ACK only has a 1 in 2^32 chance of having an acknowledgement we expect.
+//! The attempt salts the cookies of retried probes, so we can tell which
+//! attempt a response is to.
+
+use siphasher::sip::SipHasher24;
+use std::{
+    hash::{Hash, Hasher},
+    net::{IpAddr, SocketAddr},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        LazyLock,
+    },
+};
+
+/// Every epoch's key is derived from this, so it never leaves the process
+static SECRET: LazyLock<(u64, u64)> = LazyLock::new(rand::random);
+static EPOCH: AtomicU64 = AtomicU64::new(0);
+
+/// Start a new scan epoch. Responses to probes from the previous epoch are
+/// still accepted until the next rotation.
+pub fn rotate_epoch() {
+    EPOCH.fetch_add(1, Ordering::Relaxed);
+}
+
+fn epoch_key(epoch: u64) -> SipHasher24 {
+    let derive = |half: u8| {
+        let mut hasher = SipHasher24::new_with_keys(SECRET.0, SECRET.1);
+        (epoch, half).hash(&mut hasher);
+        hasher.finish()
+    };
+    SipHasher24::new_with_keys(derive(0), derive(1))
+}
+
+/// Cookie keys for the current and previous epoch. Each thread that needs
+/// cookies keeps its own, they pick up rotations by themselves.
+#[derive(Clone)]
+pub struct Cookies {
+    epoch: u64,
+    current: SipHasher24,
+    previous: SipHasher24,
+}
+
+impl Default for Cookies {
+    fn default() -> Self {
+        let epoch = EPOCH.load(Ordering::Relaxed);
+        Self {
+            epoch,
+            current: epoch_key(epoch),
+            previous: epoch_key(epoch.wrapping_sub(1)),
+        }
+    }
+}
+
+impl Cookies {
+    fn refresh(&mut self) {
+        if EPOCH.load(Ordering::Relaxed) != self.epoch {
+            *self = Self::default();
+        }
+    }
+
+    /// Used to pick the source port, which is then covered by the cookie.
+    /// Retries get a different port so they don't collide with a connection
+    /// that's still open.
+    pub fn source_port_seed(&mut self, addr: &SocketAddr, source_ip: &IpAddr, attempt: u8) -> u32 {
+        self.refresh();
+        let mut hasher = self.current.clone();
+        (addr, source_ip, attempt).hash(&mut hasher);
+        hasher.finish() as u32
+    }
+
+    /// The cookie of a connection from `source` to `addr` in this epoch
+    pub fn cookie(&mut self, addr: &SocketAddr, source: &SocketAddr, attempt: u8) -> u32 {
+        self.refresh();
+        hash(&self.current, addr, source, attempt)
+    }
+
+    /// Find the cookie and attempt `acknowledgement` was derived from, if it's
+    /// one of our cookies for the first `attempts` attempts plus one of
+    /// `offsets`.
+    pub fn validate(
+        &mut self,
+        addr: &SocketAddr,
+        source: &SocketAddr,
+        acknowledgement: u32,
+        offsets: &[u32],
+        attempts: u8,
+    ) -> Option<(u32, u8)> {
+        self.refresh();
+        [&self.current, &self.previous]
+            .into_iter()
+            .flat_map(|key| (0..attempts).map(move |attempt| (key, attempt)))
+            .map(|(key, attempt)| (hash(key, addr, source, attempt), attempt))
+            .find(|(cookie, _)| offsets.contains(&acknowledgement.wrapping_sub(*cookie)))
+    }
+}
+
+fn hash(key: &SipHasher24, addr: &SocketAddr, source: &SocketAddr, attempt: u8) -> u32 {
+    let mut hasher = key.clone();
+    (addr, source, attempt).hash(&mut hasher);
+    hasher.finish() as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Mutex, PoisonError};
+
+    /// The epoch is global, so tests that rotate it can't run at the same time
+    static EPOCH_LOCK: Mutex<()> = Mutex::new(());
+    const OFFSETS: [u32; 2] = [1, 0x21];
+    const ATTEMPTS: u8 = 3;
+
+    fn addr() -> SocketAddr {
+        "203.0.113.1:25565".parse().unwrap()
+    }
+
+    fn source() -> SocketAddr {
+        "192.0.2.1:61000".parse().unwrap()
+    }
+
+    /// What a validator that just started makes of `acknowledgement`
+    fn validate(acknowledgement: u32) -> Option<(u32, u8)> {
+        Cookies::default().validate(&addr(), &source(), acknowledgement, &OFFSETS, ATTEMPTS)
+    }
+
+    #[test]
+    fn accepts_the_current_and_previous_epoch() {
+        let _lock = EPOCH_LOCK.lock().unwrap_or_else(PoisonError::into_inner);
+        for attempt in 0..ATTEMPTS {
+            let cookie = Cookies::default().cookie(&addr(), &source(), attempt);
+            for offset in OFFSETS {
+                assert_eq!(
+                    validate(cookie.wrapping_add(offset)),
+                    Some((cookie, attempt))
+                );
+            }
+            rotate_epoch();
+            for offset in OFFSETS {
+                assert_eq!(
+                    validate(cookie.wrapping_add(offset)),
+                    Some((cookie, attempt))
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_two_rotations_ago() {
+        let _lock = EPOCH_LOCK.lock().unwrap_or_else(PoisonError::into_inner);
+        let cookie = Cookies::default().cookie(&addr(), &source(), 0);
+        rotate_epoch();
+        rotate_epoch();
+        assert_eq!(validate(cookie.wrapping_add(1)), None);
+    }
+
+    #[test]
+    fn rejects_other_connections() {
+        let _lock = EPOCH_LOCK.lock().unwrap_or_else(PoisonError::into_inner);
+        let mut cookies = Cookies::default();
+        let cookie = cookies.cookie(&addr(), &source(), 0);
+        let acknowledgement = cookie.wrapping_add(1);
+        let other_server = "203.0.113.2:25565".parse().unwrap();
+        let other_port = "203.0.113.1:25566".parse().unwrap();
+        let other_source = "192.0.2.2:61000".parse().unwrap();
+        let other_source_port = "192.0.2.1:61001".parse().unwrap();
+        for (addr, source) in [
+            (other_server, source()),
+            (other_port, source()),
+            (addr(), other_source),
+            (addr(), other_source_port),
+        ] {
+            assert_eq!(
+                cookies.validate(&addr, &source, acknowledgement, &OFFSETS, ATTEMPTS),
+                None,
+                "{addr} from {source}"
+            );
+        }
+        // and acknowledgements that aren't one of the offsets
+        assert_eq!(
+            cookies.validate(
+                &addr(),
+                &source(),
+                cookie.wrapping_add(2),
+                &OFFSETS,
+                ATTEMPTS
+            ),
+            None
+        );
+    }
+
+    #[test]
+    fn rotating_changes_the_keys() {
+        let _lock = EPOCH_LOCK.lock().unwrap_or_else(PoisonError::into_inner);
+        let mut cookies = Cookies::default();
+        let cookie = cookies.cookie(&addr(), &source(), 0);
+        let seed = cookies.source_port_seed(&addr(), &source().ip(), 0);
+        rotate_epoch();
+        // the same instance picks up the rotation by itself
+        assert_ne!(cookies.cookie(&addr(), &source(), 0), cookie);
+        assert_ne!(cookies.source_port_seed(&addr(), &source().ip(), 0), seed);
+    }
+}
@@ -3,7 +3,7 @@ use crate::ScannerState;
 use database::{player::PlayerInfo, server::PingResult};
 use std::{
     collections::BTreeSet,
-    net::{Ipv4Addr, SocketAddrV4},
+    net::{IpAddr, Ipv4Addr, SocketAddr},
     sync::{mpsc::Sender, Arc},
 };
 use tokio::sync::Mutex;
@@ -11,55 +11,59 @@ use tokio::sync::Mutex;
 pub struct DatabaseScanner {
     pub state: Arc<Mutex<ScannerState>>,
     pub sender: Sender<(PingResult, Vec<PlayerInfo>)>,
-    pub data: BTreeSet<SocketAddrV4>,
+    pub data: BTreeSet<SocketAddr>,
 }
 
 impl DatabaseScanner {
     pub fn new(
         state: Arc<Mutex<ScannerState>>,
         sender: Sender<(PingResult, Vec<PlayerInfo>)>,
-    ) -> Self {
-        let data = csv::Reader::from_path(
-            config::get()
-                .testing_data
-                .as_ref()
-                .expect("testing_data path not defined"),
-        )
-        .unwrap()
-        .records()
-        .map(|item| {
-            let item = item.unwrap();
-            SocketAddrV4::new(
-                Ipv4Addr::from(item[0].parse::<u32>().unwrap()),
-                item[1].parse::<u16>().unwrap(),
-            )
-        })
-        .collect();
+    ) -> eyre::Result<Self> {
+        let config = config::get();
+        let Some(path) = &config.testing_data else {
+            return Err(eyre::eyre!(
+                "the replay backend needs `testing_data`, set it or pick another `scanner.backend`"
+            ));
+        };
+        let data = csv::Reader::from_path(path)
+            .map_err(|err| eyre::eyre!("unable to read {}: {err}", path.display()))?
+            .records()
+            .map(|item| {
+                let item = item?;
+                let (Some(ip), Some(port)) = (item.get(0), item.get(1)) else {
+                    return Err(eyre::eyre!("expected an ip and a port, got {item:?}"));
+                };
+                Ok(SocketAddr::new(
+                    IpAddr::V4(Ipv4Addr::from(ip.parse::<u32>()?)),
+                    port.parse::<u16>()?,
+                ))
+            })
+            .collect::<eyre::Result<_>>()?;
 
-        Self {
+        Ok(Self {
             state,
             sender,
             data,
-        }
+        })
     }
 }
 
 impl Io for DatabaseScanner {
-    async fn ping(&mut self, addr: SocketAddrV4) -> eyre::Result<()> {
+    async fn ping(&mut self, addr: SocketAddr) -> eyre::Result<()> {
         if self.data.contains(&addr) {
             self.state.lock().await.discovered += 1;
             self.sender
-                .send((PingResult::none(*addr.ip(), addr.port()), vec![]))
+                .send((PingResult::none(addr.ip(), addr.port()), vec![]))
                 .expect("Unable to send ping result");
         }
         Ok(())
     }
 
-    async fn legacy_ping(&mut self, addr: SocketAddrV4) -> eyre::Result<()> {
+    async fn legacy_ping(&mut self, addr: SocketAddr) -> eyre::Result<()> {
         if self.data.contains(&addr) {
             self.state.lock().await.discovered += 1;
             self.sender
-                .send((PingResult::none(*addr.ip(), addr.port()), vec![]))
+                .send((PingResult::none(addr.ip(), addr.port()), vec![]))
                 .expect("Unable to send ping result");
         }
         Ok(())
@@ -0,0 +1,212 @@
+use database::server::PingResult;
+use std::net::IpAddr;
+
+/// Packet id of the kick packet that pre-1.7 servers answer a legacy ping with
+pub const KICK_PACKET_ID: u8 = 0xff;
+/// Protocol version sent in `MC|PingHost`, 78 is 1.6.4
+pub const PING_HOST_PROTOCOL_VERSION: u8 = 78;
+
+/// Build the full 1.6 legacy ping, `0xfe 0x01` followed by a `MC|PingHost`
+/// plugin message.
+///
+/// Servers older than 1.6 ignore everything after `0xfe 0x01`.
+pub fn ping_host_packet(hostname: &str, port: u16) -> Vec<u8> {
+    const CHANNEL: &str = "MC|PingHost";
+    let hostname = hostname.encode_utf16().collect::<Vec<_>>();
+
+    let mut packet = vec![0xfe, 0x01, 0xfa];
+    packet.extend_from_slice(&(CHANNEL.len() as u16).to_be_bytes());
+    for code_unit in CHANNEL.encode_utf16() {
+        packet.extend_from_slice(&code_unit.to_be_bytes());
+    }
+    // protocol version + hostname length + hostname + port
+    let data_length = 1 + 2 + hostname.len() * 2 + 4;
+    packet.extend_from_slice(&(data_length as u16).to_be_bytes());
+    packet.push(PING_HOST_PROTOCOL_VERSION);
+    packet.extend_from_slice(&(hostname.len() as u16).to_be_bytes());
+    for code_unit in hostname {
+        packet.extend_from_slice(&code_unit.to_be_bytes());
+    }
+    packet.extend_from_slice(&(port as i32).to_be_bytes());
+    packet
+}
+
+/// Build the kick packet pre-1.7 servers answer a legacy ping with, what
+/// [`LegacyPingResponse::decode`] reads
+pub fn encode_kick(message: &str) -> Vec<u8> {
+    let code_units = message.encode_utf16().collect::<Vec<_>>();
+    let mut packet = vec![KICK_PACKET_ID];
+    packet.extend_from_slice(&(code_units.len() as u16).to_be_bytes());
+    for code_unit in code_units {
+        packet.extend_from_slice(&code_unit.to_be_bytes());
+    }
+    packet
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LegacyPingResponse {
+    /// Only sent by 1.4+ servers
+    pub protocol: Option<i32>,
+    /// Only sent by 1.4+ servers
+    pub version: Option<String>,
+    pub motd: String,
+    pub online_players: i32,
+    pub max_players: i32,
+}
+
+impl LegacyPingResponse {
+    /// Decode a (possibly incomplete) kick packet.
+    ///
+    /// Returns `Ok(None)` if more data is needed before the packet can be
+    /// decoded.
+    pub fn decode(buffer: &[u8]) -> eyre::Result<Option<Self>> {
+        let [packet_id, length_high, length_low, data @ ..] = buffer else {
+            return Ok(None);
+        };
+        if *packet_id != KICK_PACKET_ID {
+            return Err(eyre::eyre!(
+                "Expected legacy kick packet, got packet id {packet_id:#04x}"
+            ));
+        }
+        // the length is in UTF-16 code units, not bytes
+        let length = u16::from_be_bytes([*length_high, *length_low]) as usize * 2;
+        if data.len() < length {
+            return Ok(None);
+        }
+        let code_units = data[..length]
+            .chunks_exact(2)
+            .map(|pair| u16::from_be_bytes([pair[0], pair[1]]));
+        let string = char::decode_utf16(code_units).collect::<Result<String, _>>()?;
+        Self::parse(&string).map(Some)
+    }
+
+    /// Parse the string sent in the kick packet.
+    ///
+    /// 1.4 to 1.6 send `§1\0protocol\0version\0motd\0online\0max`, older
+    /// servers send `motd§online§max`.
+    pub fn parse(string: &str) -> eyre::Result<Self> {
+        if let Some(fields) = string.strip_prefix("§1\0") {
+            let fields = fields.split('\0').collect::<Vec<_>>();
+            let [protocol, version, motd, online, max] = fields[..] else {
+                return Err(eyre::eyre!(
+                    "Expected 5 fields in legacy ping response, got {}",
+                    fields.len()
+                ));
+            };
+            Ok(Self {
+                protocol: Some(protocol.parse()?),
+                version: Some(version.to_string()),
+                motd: motd.to_string(),
+                online_players: online.parse()?,
+                max_players: max.parse()?,
+            })
+        } else {
+            // the motd can contain formatting codes, so split from the end
+            let mut fields = string.rsplitn(3, '§');
+            let (Some(max), Some(online), Some(motd)) =
+                (fields.next(), fields.next(), fields.next())
+            else {
+                return Err(eyre::eyre!(
+                    "Expected 3 fields in pre-1.4 legacy ping response"
+                ));
+            };
+            Ok(Self {
+                protocol: None,
+                version: None,
+                motd: motd.to_string(),
+                online_players: online.parse()?,
+                max_players: max.parse()?,
+            })
+        }
+    }
+
+    pub fn to_ping_result(&self, ip: IpAddr, port: u16) -> PingResult {
+        let mut ping_result = PingResult::none(ip, port);
+        ping_result.version_name = self.version.clone();
+        ping_result.version_protocol = self.protocol;
+        ping_result.max_players = Some(self.max_players);
+        ping_result.online_players = Some(self.online_players);
+        ping_result.description = Some(self.motd.clone());
+        ping_result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_1_6_response() {
+        let packet = encode_kick("§1\078\01.6.4\0A Minecraft Server\03\020");
+        assert_eq!(
+            LegacyPingResponse::decode(&packet).unwrap(),
+            Some(LegacyPingResponse {
+                protocol: Some(78),
+                version: Some("1.6.4".to_string()),
+                motd: "A Minecraft Server".to_string(),
+                online_players: 3,
+                max_players: 20,
+            })
+        );
+    }
+
+    #[test]
+    fn decodes_1_4_response() {
+        let packet = encode_kick("§1\051\01.4.7\0§aColored §lmotd\00\0100");
+        assert_eq!(
+            LegacyPingResponse::decode(&packet).unwrap(),
+            Some(LegacyPingResponse {
+                protocol: Some(51),
+                version: Some("1.4.7".to_string()),
+                motd: "§aColored §lmotd".to_string(),
+                online_players: 0,
+                max_players: 100,
+            })
+        );
+    }
+
+    #[test]
+    fn decodes_pre_1_4_response() {
+        let packet = encode_kick("§eOld §fschool§7§64");
+        assert_eq!(
+            LegacyPingResponse::decode(&packet).unwrap(),
+            Some(LegacyPingResponse {
+                protocol: None,
+                version: None,
+                motd: "§eOld §fschool".to_string(),
+                online_players: 7,
+                max_players: 64,
+            })
+        );
+    }
+
+    #[test]
+    fn waits_for_truncated_packets() {
+        let packet = encode_kick("§1\078\01.6.4\0A Minecraft Server\03\020");
+        for length in 0..packet.len() {
+            assert_eq!(
+                LegacyPingResponse::decode(&packet[..length]).unwrap(),
+                None,
+                "decoded the first {length} bytes"
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_other_packets() {
+        assert!(LegacyPingResponse::decode(&[0x00, 0x00, 0x01, 0x00, 0x41]).is_err());
+    }
+
+    #[test]
+    fn rejects_wrong_field_count() {
+        assert!(LegacyPingResponse::decode(&encode_kick("§1\078\01.6.4\0motd")).is_err());
+        assert!(LegacyPingResponse::decode(&encode_kick("no fields")).is_err());
+        assert!(LegacyPingResponse::decode(&encode_kick("motd§many§players")).is_err());
+    }
+
+    #[test]
+    fn rejects_invalid_utf16() {
+        // an unpaired surrogate
+        assert!(LegacyPingResponse::decode(&[KICK_PACKET_ID, 0x00, 0x01, 0xd8, 0x00]).is_err());
+    }
+}
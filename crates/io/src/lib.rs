@@ -1,33 +1,69 @@
-use std::hash::{DefaultHasher, Hash, Hasher};
-
+pub mod backend;
+pub mod cookie;
 pub mod database;
+pub mod legacy;
+pub mod metrics;
 pub mod network;
+pub mod pcap;
 pub mod pnet;
 pub mod proxy;
 
-lazy_static::lazy_static! {
-    static ref COOKIE_SEED: u64 = rand::random();
-}
-
 pub trait Io {
     fn ping(
         &mut self,
-        addr: std::net::SocketAddrV4,
+        addr: std::net::SocketAddr,
     ) -> impl std::future::Future<Output = eyre::Result<()>> + Send;
 
     fn legacy_ping(
         &mut self,
-        addr: std::net::SocketAddrV4,
+        addr: std::net::SocketAddr,
     ) -> impl std::future::Future<Output = eyre::Result<()>> + Send;
+
+    /// Ping `addr` again in case the first ping was lost, `attempt` is 1 for
+    /// the first retry. Scanners that can't lose pings don't retry.
+    fn retry(
+        &mut self,
+        _addr: std::net::SocketAddr,
+        _probe: Probe,
+        _attempt: u8,
+    ) -> impl std::future::Future<Output = eyre::Result<()>> + Send {
+        async { Ok(()) }
+    }
+
+    /// Send pings that were queued up to be sent together. Called before
+    /// waiting on anything, so queued pings don't sit around.
+    fn flush(&mut self) {}
 }
 
-pub fn cookie(address: &std::net::SocketAddrV4, seed: u64) -> u32 {
-    let mut hasher = DefaultHasher::new();
-    (*address.ip(), address.port(), seed).hash(&mut hasher);
-    hasher.finish() as u32
+/// Which ping was sent, so a retry sends the same one
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Probe {
+    /// [`Io::ping`]
+    Slp,
+    /// [`Io::legacy_ping`]
+    Legacy,
 }
 
 #[derive(Default)]
 pub struct ScannerState {
     pub discovered: u64,
+    /// Connection stats of each receive worker
+    pub connections: Vec<pnet::connections::ConnectionStats>,
+    pub receive: pnet::ReceiveStats,
+    pub modes: metrics::ModeTotals,
+}
+
+impl ScannerState {
+    /// Connection stats summed over every receive worker
+    pub fn connection_stats(&self) -> pnet::connections::ConnectionStats {
+        self.connections.iter().fold(
+            pnet::connections::ConnectionStats::default(),
+            |total, stats| pnet::connections::ConnectionStats {
+                open: total.open + stats.open,
+                timed_out: total.timed_out + stats.timed_out,
+                evicted: total.evicted + stats.evicted,
+                oversized: total.oversized + stats.oversized,
+            },
+        )
+    }
 }
@@ -0,0 +1,202 @@
+//! Totals for graphing how well scans go, served on `/metrics` by the web
+//! crate. Most of them come from the stats the receive path already publishes
+//! into [`ScannerState`], the rest are counted in [`COUNTERS`] where they
+//! happen.
+
+use crate::ScannerState;
+use std::{
+    collections::BTreeMap,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+pub static COUNTERS: Counters = Counters::new();
+
+/// Counters bumped from many threads at once
+#[derive(Debug)]
+pub struct Counters {
+    /// SYNs sent by the pnet backend or connections started by the tcp
+    /// backend, retries included
+    pub syns_sent: AtomicU64,
+    /// Connections the tcp backend opened
+    pub connected: AtomicU64,
+    /// Connections the tcp backend got refused
+    pub refused: AtomicU64,
+    /// Status responses that were decoded, legacy ones included
+    pub statuses: AtomicU64,
+    /// Responses that couldn't be decoded
+    pub parse_failures: AtomicU64,
+    /// Results handed to the database threads
+    pub queued: AtomicU64,
+    /// Results the database threads are done with
+    pub handled: AtomicU64,
+    /// Results pushed to the database
+    pub db_pushes: AtomicU64,
+    pub db_push_micros: AtomicU64,
+}
+
+impl Counters {
+    const fn new() -> Self {
+        Self {
+            syns_sent: AtomicU64::new(0),
+            connected: AtomicU64::new(0),
+            refused: AtomicU64::new(0),
+            statuses: AtomicU64::new(0),
+            parse_failures: AtomicU64::new(0),
+            queued: AtomicU64::new(0),
+            handled: AtomicU64::new(0),
+            db_pushes: AtomicU64::new(0),
+            db_push_micros: AtomicU64::new(0),
+        }
+    }
+
+    pub fn add(counter: &AtomicU64, value: u64) {
+        counter.fetch_add(value, Ordering::Relaxed);
+    }
+
+    pub fn get(counter: &AtomicU64) -> u64 {
+        counter.load(Ordering::Relaxed)
+    }
+
+    /// Results waiting to be pushed to the database
+    pub fn queue_depth(&self) -> u64 {
+        Self::get(&self.queued).saturating_sub(Self::get(&self.handled))
+    }
+}
+
+/// Everything that's counted, at one point in time or between two
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Totals {
+    pub syns_sent: u64,
+    pub syn_acks: u64,
+    pub rsts: u64,
+    pub statuses: u64,
+    pub parse_failures: u64,
+    /// Connections dropped because the server stopped responding halfway
+    pub reassembly_timeouts: u64,
+    pub db_pushes: u64,
+    pub db_push_micros: u64,
+}
+
+impl Totals {
+    /// What was counted between `earlier` and these totals
+    pub fn since(&self, earlier: &Totals) -> Totals {
+        Totals {
+            syns_sent: self.syns_sent.saturating_sub(earlier.syns_sent),
+            syn_acks: self.syn_acks.saturating_sub(earlier.syn_acks),
+            rsts: self.rsts.saturating_sub(earlier.rsts),
+            statuses: self.statuses.saturating_sub(earlier.statuses),
+            parse_failures: self.parse_failures.saturating_sub(earlier.parse_failures),
+            reassembly_timeouts: self
+                .reassembly_timeouts
+                .saturating_sub(earlier.reassembly_timeouts),
+            db_pushes: self.db_pushes.saturating_sub(earlier.db_pushes),
+            db_push_micros: self.db_push_micros.saturating_sub(earlier.db_push_micros),
+        }
+    }
+
+    pub fn add(&mut self, other: &Totals) {
+        self.syns_sent += other.syns_sent;
+        self.syn_acks += other.syn_acks;
+        self.rsts += other.rsts;
+        self.statuses += other.statuses;
+        self.parse_failures += other.parse_failures;
+        self.reassembly_timeouts += other.reassembly_timeouts;
+        self.db_pushes += other.db_pushes;
+        self.db_push_micros += other.db_push_micros;
+    }
+}
+
+/// Splits the totals up by the scanning mode they were counted in
+#[derive(Debug, Default)]
+pub struct ModeTotals {
+    /// The mode being scanned and the totals when it started
+    current: Option<(String, Totals)>,
+    /// Totals of every mode, not counting the current one
+    finished: BTreeMap<String, Totals>,
+    /// Addresses the exclude list removed from each mode, counted when the
+    /// mode is picked, even if nothing was left to scan
+    excluded: BTreeMap<String, u64>,
+}
+
+impl ModeTotals {
+    pub fn current(&self) -> Option<&str> {
+        self.current.as_ref().map(|(mode, _)| mode.as_str())
+    }
+
+    pub fn excluded(&self) -> &BTreeMap<String, u64> {
+        &self.excluded
+    }
+}
+
+impl ScannerState {
+    /// Everything counted since we started
+    pub fn totals(&self) -> Totals {
+        Totals {
+            syns_sent: Counters::get(&COUNTERS.syns_sent),
+            syn_acks: self.receive.syn_acks.iter().sum::<u64>()
+                + Counters::get(&COUNTERS.connected),
+            rsts: self.receive.rsts + Counters::get(&COUNTERS.refused),
+            statuses: Counters::get(&COUNTERS.statuses),
+            parse_failures: Counters::get(&COUNTERS.parse_failures),
+            reassembly_timeouts: self.connection_stats().timed_out,
+            db_pushes: Counters::get(&COUNTERS.db_pushes),
+            db_push_micros: Counters::get(&COUNTERS.db_push_micros),
+        }
+    }
+
+    /// Count everything from now on towards `mode`
+    pub fn start_mode(&mut self, mode: impl ToString) {
+        let now = self.totals();
+        if let Some((previous, started)) = self.modes.current.take() {
+            self.modes
+                .finished
+                .entry(previous)
+                .or_default()
+                .add(&now.since(&started));
+        }
+        self.modes.current = Some((mode.to_string(), now));
+    }
+
+    /// Count `count` more addresses that were excluded from `mode`
+    pub fn add_excluded(&mut self, mode: impl ToString, count: u64) {
+        let excluded = self.modes.excluded.entry(mode.to_string()).or_default();
+        *excluded = excluded.saturating_add(count);
+    }
+
+    /// Everything counted in each mode, including the current one so far
+    pub fn totals_by_mode(&self) -> BTreeMap<String, Totals> {
+        let mut totals = self.modes.finished.clone();
+        if let Some((mode, started)) = &self.modes.current {
+            totals
+                .entry(mode.clone())
+                .or_default()
+                .add(&self.totals().since(started));
+        }
+        totals
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_exclusions_per_mode() {
+        let mut state = ScannerState::default();
+        state.add_excluded("Ipv6Hitlist", 3);
+        state.add_excluded("AllPortSingleRange", 0);
+        state.add_excluded("Ipv6Hitlist", 4);
+        assert_eq!(
+            state
+                .modes
+                .excluded()
+                .clone()
+                .into_iter()
+                .collect::<Vec<_>>(),
+            vec![
+                ("AllPortSingleRange".to_string(), 0),
+                ("Ipv6Hitlist".to_string(), 7)
+            ]
+        );
+    }
+}
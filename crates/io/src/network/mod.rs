@@ -1,5 +1,10 @@
 use super::Io;
-use crate::ScannerState;
+use crate::{
+    legacy::{self, LegacyPingResponse},
+    metrics::{Counters, COUNTERS},
+    proxy::NetworkProxy,
+    ScannerState,
+};
 use azalea_protocol::{
     connect::RawReadConnection,
     packets::{
@@ -14,58 +19,251 @@ use azalea_protocol::{
     write::write_packet,
 };
 use bytes::BytesMut;
+use common::net::rate_limit::RateLimiter;
 use database::{player::PlayerInfo, server::PingResult};
 use std::{
-    io::Cursor,
-    net::SocketAddrV4,
+    future::Future,
+    io::{self, Cursor},
+    net::SocketAddr,
     sync::{mpsc::Sender, Arc},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    sync::{Mutex, Semaphore},
 };
-use tokio::{net::TcpStream, sync::Mutex};
 
+/// Pings with a full TCP connection for every address, so it works without
+/// raw sockets. Every ping runs in its own task, at most
+/// `scanner.max_connections` at once.
 pub struct NetworkScanner {
     pub state: Arc<Mutex<ScannerState>>,
     pub sender: Sender<(PingResult, Vec<PlayerInfo>)>,
+    pub proxy: NetworkProxy,
+    rate_limiter: RateLimiter,
+    connections: Arc<Semaphore>,
+}
+
+impl NetworkScanner {
+    pub fn new(
+        state: Arc<Mutex<ScannerState>>,
+        sender: Sender<(PingResult, Vec<PlayerInfo>)>,
+        proxy: NetworkProxy,
+        rate_limiter: RateLimiter,
+    ) -> Self {
+        Self {
+            state,
+            sender,
+            proxy,
+            rate_limiter,
+            connections: Arc::new(Semaphore::new(config::get().scanner.max_connections)),
+        }
+    }
+
+    /// Run `status` in the background and send what it finds. Most addresses
+    /// don't have a server, so errors are dropped.
+    async fn spawn<F>(&self, status: F) -> eyre::Result<()>
+    where
+        F: Future<Output = eyre::Result<(PingResult, Vec<PlayerInfo>)>> + Send + 'static,
+    {
+        let permit = self.connections.clone().acquire_owned().await?;
+        self.rate_limiter.acquire().await;
+        Counters::add(&COUNTERS.syns_sent, 1);
+        let state = self.state.clone();
+        let sender = self.sender.clone();
+        let timeout = Duration::from_secs(config::get().scanner.connection_timeout);
+        tokio::spawn(async move {
+            if let Ok(Ok(result)) = tokio::time::timeout(timeout, status).await {
+                state.lock().await.discovered += 1;
+                let _ = sender.send(result);
+            }
+            drop(permit);
+        });
+        Ok(())
+    }
 }
 
 impl Io for NetworkScanner {
-    async fn ping(&mut self, addr: SocketAddrV4) -> Result<(), eyre::Report> {
-        let socket = TcpStream::connect(addr).await?;
-        socket.set_nodelay(true)?;
-        let (socket_r, mut socket_w) = socket.into_split();
-
-        let handshake_packet = ClientIntentionPacket {
-            protocol_version: -1,
-            hostname: String::from("snowstorm"),
-            port: 42069,
-            intention: ConnectionProtocol::Status,
+    async fn ping(&mut self, addr: SocketAddr) -> Result<(), eyre::Report> {
+        self.spawn(status(self.proxy.clone(), addr)).await
+    }
+
+    async fn legacy_ping(&mut self, addr: SocketAddr) -> Result<(), eyre::Report> {
+        self.spawn(legacy_status(self.proxy.clone(), addr)).await
+    }
+}
+
+/// Connect to `addr`, counting whether it worked
+async fn connect(proxy: &NetworkProxy, addr: SocketAddr) -> eyre::Result<TcpStream> {
+    let socket = proxy.connect(addr).await;
+    match &socket {
+        Ok(_) => Counters::add(&COUNTERS.connected, 1),
+        Err(err)
+            if err
+                .downcast_ref::<io::Error>()
+                .is_some_and(|err| err.kind() == io::ErrorKind::ConnectionRefused) =>
+        {
+            Counters::add(&COUNTERS.refused, 1)
         }
-        .get();
-        write_packet(&handshake_packet, &mut socket_w, None, &mut None).await?;
+        Err(_) => {}
+    }
+    socket
+}
+
+async fn status(
+    proxy: NetworkProxy,
+    addr: SocketAddr,
+) -> eyre::Result<(PingResult, Vec<PlayerInfo>)> {
+    let socket = connect(&proxy, addr).await?;
+    socket.set_nodelay(true)?;
+    let (socket_r, mut socket_w) = socket.into_split();
+
+    let handshake_packet = ClientIntentionPacket {
+        protocol_version: -1,
+        hostname: String::from("snowstorm"),
+        port: 42069,
+        intention: ConnectionProtocol::Status,
+    }
+    .get();
+    write_packet(&handshake_packet, &mut socket_w, None, &mut None).await?;
+
+    let ping_packet = ServerboundStatusRequestPacket {}.get();
+    write_packet(&ping_packet, &mut socket_w, None, &mut None).await?;
+
+    let mut raw_read_connection = RawReadConnection {
+        read_stream: socket_r,
+        buffer: BytesMut::new(),
+        compression_threshold: None,
+        dec_cipher: None,
+    };
 
-        let ping_packet = ServerboundStatusRequestPacket {}.get();
-        write_packet(&ping_packet, &mut socket_w, None, &mut None).await?;
+    let clientbound_status = deserialize_packet::<ClientboundStatusPacket>(&mut Cursor::new(
+        raw_read_connection.read().await?.as_slice(),
+    ));
+
+    let Ok(ClientboundStatusPacket::StatusResponse(ping_response)) = clientbound_status else {
+        Counters::add(&COUNTERS.parse_failures, 1);
+        return Err(eyre::eyre!("Expected status response"));
+    };
+    Counters::add(&COUNTERS.statuses, 1);
+    let ping_result = PingResult::from_azalea(addr.ip(), addr.port(), &ping_response);
+    let player_info = PlayerInfo::from_azalea(&ping_response).await;
+    Ok((ping_result, player_info))
+}
 
-        let mut raw_read_connection = RawReadConnection {
-            read_stream: socket_r,
-            buffer: BytesMut::new(),
-            compression_threshold: None,
-            dec_cipher: None,
-        };
+async fn legacy_status(
+    proxy: NetworkProxy,
+    addr: SocketAddr,
+) -> eyre::Result<(PingResult, Vec<PlayerInfo>)> {
+    let mut socket = connect(&proxy, addr).await?;
+    socket.set_nodelay(true)?;
 
-        let clientbound_status = deserialize_packet::<ClientboundStatusPacket>(&mut Cursor::new(
-            raw_read_connection.read().await?.as_slice(),
+    socket
+        .write_all(&legacy::ping_host_packet(
+            &addr.ip().to_string(),
+            addr.port(),
+        ))
+        .await?;
+
+    let mut header = [0; 3];
+    socket.read_exact(&mut header).await?;
+    if header[0] != legacy::KICK_PACKET_ID {
+        Counters::add(&COUNTERS.parse_failures, 1);
+        return Err(eyre::eyre!(
+            "Expected legacy kick packet, got packet id {:#04x}",
+            header[0]
         ));
+    }
+    let length = u16::from_be_bytes([header[1], header[2]]) as usize * 2;
+    let mut packet = header.to_vec();
+    packet.resize(header.len() + length, 0);
+    socket.read_exact(&mut packet[header.len()..]).await?;
+
+    let response = match LegacyPingResponse::decode(&packet) {
+        Ok(Some(response)) => response,
+        Ok(None) => return Err(eyre::eyre!("Incomplete legacy ping response")),
+        Err(err) => {
+            Counters::add(&COUNTERS.parse_failures, 1);
+            return Err(err);
+        }
+    };
+    Counters::add(&COUNTERS.statuses, 1);
+    Ok((response.to_ping_result(addr.ip(), addr.port()), vec![]))
+}
 
-        if let Ok(ClientboundStatusPacket::StatusResponse(ping_response)) = clientbound_status {
-            let ping_result = PingResult::from_azalea(*addr.ip(), addr.port(), &ping_response);
-            let player_info = PlayerInfo::from_azalea(&ping_response).await;
-            self.sender.send((ping_result, player_info))?;
-        };
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
 
-        Ok(())
+    /// Accept one connection, check it sent the legacy ping for `addr` and
+    /// answer it with a kick packet carrying `message`
+    async fn legacy_server(message: &'static str) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let expected = legacy::ping_host_packet(&addr.ip().to_string(), addr.port());
+            let mut request = vec![0; expected.len()];
+            socket.read_exact(&mut request).await.unwrap();
+            assert_eq!(request, expected);
+
+            socket
+                .write_all(&legacy::encode_kick(message))
+                .await
+                .unwrap();
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn legacy_status_1_6() {
+        let addr = legacy_server("§1\078\01.6.4\0A Minecraft Server\03\020").await;
+        let (ping_result, players) = legacy_status(NetworkProxy::Direct, addr).await.unwrap();
+        assert_eq!(ping_result.ip(), addr.ip());
+        assert_eq!(ping_result.port(), addr.port());
+        assert_eq!(ping_result.version_name.as_deref(), Some("1.6.4"));
+        assert_eq!(ping_result.version_protocol, Some(78));
+        assert_eq!(
+            ping_result.description.as_deref(),
+            Some("A Minecraft Server")
+        );
+        assert_eq!(ping_result.online_players, Some(3));
+        assert_eq!(ping_result.max_players, Some(20));
+        assert!(players.is_empty());
+    }
+
+    #[tokio::test]
+    async fn legacy_status_1_4() {
+        let addr = legacy_server("§1\051\01.4.7\0§aColored motd\00\0100").await;
+        let (ping_result, _) = legacy_status(NetworkProxy::Direct, addr).await.unwrap();
+        assert_eq!(ping_result.version_name.as_deref(), Some("1.4.7"));
+        assert_eq!(ping_result.version_protocol, Some(51));
+        assert_eq!(ping_result.description.as_deref(), Some("§aColored motd"));
+        assert_eq!(ping_result.online_players, Some(0));
+        assert_eq!(ping_result.max_players, Some(100));
+    }
+
+    #[tokio::test]
+    async fn legacy_status_pre_1_4() {
+        let addr = legacy_server("Old §eschool§7§64").await;
+        let (ping_result, _) = legacy_status(NetworkProxy::Direct, addr).await.unwrap();
+        assert_eq!(ping_result.version_name, None);
+        assert_eq!(ping_result.version_protocol, None);
+        assert_eq!(ping_result.description.as_deref(), Some("Old §eschool"));
+        assert_eq!(ping_result.online_players, Some(7));
+        assert_eq!(ping_result.max_players, Some(64));
     }
 
-    async fn legacy_ping(&mut self, _addr: SocketAddrV4) -> Result<(), eyre::Report> {
-        todo!()
+    #[tokio::test]
+    async fn legacy_status_rejects_other_packets() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            socket.write_all(&[0x00, 0x00, 0x00]).await.unwrap();
+        });
+        assert!(legacy_status(NetworkProxy::Direct, addr).await.is_err());
     }
 }
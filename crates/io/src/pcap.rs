@@ -0,0 +1,98 @@
+//! The SYN scanner on a replayed capture instead of the network, see
+//! [`common::net::replay`]. Everything past sending the SYN is the real
+//! thing, so changes to the handshake, reassembly or parsing can be checked
+//! without a network.
+
+use super::{Io, Probe};
+use crate::{pnet::PnetScanner, ScannerState};
+use common::net::{
+    rate_limit::RateLimiter,
+    replay::ReplayNetwork,
+    tcp::{Fingerprint, StatelessTcp},
+};
+use database::{player::PlayerInfo, server::PingResult};
+use std::{
+    net::SocketAddr,
+    path::Path,
+    sync::{mpsc::Sender, Arc},
+};
+use tokio::sync::Mutex;
+
+pub struct PcapScanner {
+    scanner: PnetScanner,
+    /// Every server in the capture
+    pub servers: Vec<SocketAddr>,
+}
+
+impl PcapScanner {
+    pub fn new(
+        capture: &Path,
+        output: Option<&Path>,
+        state: Arc<Mutex<ScannerState>>,
+        sender: Sender<(PingResult, Vec<PlayerInfo>)>,
+        rate_limiter: RateLimiter,
+    ) -> eyre::Result<Self> {
+        let config = config::get();
+        let source_port = config.scanner.source_port;
+        if !source_port.is_valid() {
+            return Err(eyre::eyre!("invalid source port range {source_port:?}"));
+        }
+        let network = ReplayNetwork::open(capture, output)?;
+        let servers = network.servers.clone();
+        let socket = StatelessTcp::replay(
+            network,
+            &config.scanner.source_ips,
+            config.scanner.source_ip_selection,
+            source_port,
+            Fingerprint::new(config.scanner.fingerprint.fingerprint()),
+            rate_limiter,
+        );
+        Ok(Self {
+            scanner: PnetScanner::with_socket(socket, state, sender),
+            servers,
+        })
+    }
+
+    /// Create the scanner from `scanner.pcap_replay` and `scanner.pcap_output`
+    pub fn from_config(
+        state: Arc<Mutex<ScannerState>>,
+        sender: Sender<(PingResult, Vec<PlayerInfo>)>,
+        rate_limiter: RateLimiter,
+    ) -> eyre::Result<Self> {
+        let config = config::get();
+        let capture = config
+            .scanner
+            .pcap_replay
+            .as_ref()
+            .ok_or_else(|| eyre::eyre!("the pcap backend needs scanner.pcap_replay"))?;
+        Self::new(
+            capture,
+            config.scanner.pcap_output.as_deref(),
+            state,
+            sender,
+            rate_limiter,
+        )
+    }
+}
+
+// SYNs are sent right away instead of in batches, so a replay doesn't depend
+// on how many addresses came after
+impl Io for PcapScanner {
+    async fn ping(&mut self, addr: SocketAddr) -> eyre::Result<()> {
+        self.scanner.ping(addr).await?;
+        self.scanner.flush();
+        Ok(())
+    }
+
+    async fn legacy_ping(&mut self, addr: SocketAddr) -> eyre::Result<()> {
+        self.scanner.legacy_ping(addr).await?;
+        self.scanner.flush();
+        Ok(())
+    }
+
+    async fn retry(&mut self, addr: SocketAddr, probe: Probe, attempt: u8) -> eyre::Result<()> {
+        self.scanner.retry(addr, probe, attempt).await?;
+        self.scanner.flush();
+        Ok(())
+    }
+}
@@ -0,0 +1,98 @@
+use std::{
+    collections::{hash_map::Entry, HashMap, VecDeque},
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+/// Which attempt every address answered first, remembered for as long as
+/// retries to it can still be answered.
+///
+/// A server that answered the first ping answers the retries too, and those
+/// answers must not be scanned again.
+pub struct Answered {
+    window: Duration,
+    attempts: HashMap<SocketAddr, u8>,
+    /// When each address answered, oldest first
+    order: VecDeque<(Instant, SocketAddr)>,
+}
+
+impl Answered {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            attempts: HashMap::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    pub fn from_config() -> Self {
+        let config = config::get();
+        Self::new(
+            Duration::from_secs(config.scanner.retry_delay) * config.scanner.retries as u32
+                + Duration::from_secs(config.scanner.connection_timeout),
+        )
+    }
+
+    /// Whether a SYN-ACK from `addr` to `attempt` is the first answer to any
+    /// attempt, or a retransmission of it
+    pub fn is_first(&mut self, addr: SocketAddr, attempt: u8, now: Instant) -> bool {
+        self.expire(now);
+        match self.attempts.entry(addr) {
+            Entry::Occupied(first) => *first.get() == attempt,
+            Entry::Vacant(entry) => {
+                entry.insert(attempt);
+                self.order.push_back((now, addr));
+                true
+            }
+        }
+    }
+
+    fn expire(&mut self, now: Instant) {
+        while let Some(&(answered_at, addr)) = self.order.front() {
+            if now.duration_since(answered_at) < self.window {
+                break;
+            }
+            self.order.pop_front();
+            self.attempts.remove(&addr);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WINDOW: Duration = Duration::from_secs(10);
+
+    fn addr(i: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], i))
+    }
+
+    #[test]
+    fn later_attempts_are_duplicates() {
+        let mut answered = Answered::new(WINDOW);
+        let now = Instant::now();
+        assert!(answered.is_first(addr(1), 0, now));
+        assert!(!answered.is_first(addr(1), 1, now));
+        assert!(!answered.is_first(addr(1), 2, now));
+        assert!(answered.is_first(addr(2), 1, now));
+        assert!(!answered.is_first(addr(2), 2, now));
+    }
+
+    #[test]
+    fn retransmissions_arent_duplicates() {
+        let mut answered = Answered::new(WINDOW);
+        let now = Instant::now();
+        assert!(answered.is_first(addr(1), 1, now));
+        assert!(answered.is_first(addr(1), 1, now + Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn forgets_after_the_window() {
+        let mut answered = Answered::new(WINDOW);
+        let now = Instant::now();
+        assert!(answered.is_first(addr(1), 0, now));
+        assert!(answered.is_first(addr(1), 1, now + WINDOW));
+        assert_eq!(answered.attempts.len(), 1);
+    }
+}
@@ -0,0 +1,311 @@
+use super::reassembly::ReassemblyBuffer;
+use common::net::tcp::PeerOptions;
+use pnet::packet::tcp::Tcp;
+use std::{
+    collections::{HashMap, VecDeque},
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+/// How many buckets the connection timeout is split into. Connections are
+/// expired one bucket at a time, so they live for between `timeout` and
+/// `timeout * (1 + 1 / BUCKET_COUNT)`.
+const BUCKET_COUNT: u64 = 8;
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ConnectionStats {
+    /// Connections currently waiting for a response
+    pub open: usize,
+    /// Connections dropped because the server stopped responding
+    pub timed_out: u64,
+    /// Connections dropped to make room because the table was full
+    pub evicted: u64,
+    /// Connections dropped because the response was too large
+    pub oversized: u64,
+}
+
+struct Connection {
+    buffer: ReassemblyBuffer,
+    peer: PeerOptions,
+    bucket: u64,
+}
+
+/// Half-open connections the stateless receiver is reassembling responses for.
+///
+/// The table is bounded in both the number of connections and the amount of
+/// data buffered per connection, and connections that stop responding without
+/// a FIN or RST are expired after a timeout.
+pub struct ConnectionTable {
+    connections: HashMap<SocketAddr, Connection>,
+    /// Addresses of connections opened during each bucket, oldest first
+    buckets: VecDeque<(u64, Vec<SocketAddr>)>,
+    current_bucket: u64,
+    current_bucket_start: Instant,
+    bucket_width: Duration,
+
+    max_connections: usize,
+    max_bytes: usize,
+
+    stats: ConnectionStats,
+}
+
+impl ConnectionTable {
+    pub fn new(timeout: Duration, max_connections: usize, max_bytes: usize) -> Self {
+        Self {
+            connections: HashMap::new(),
+            buckets: VecDeque::from([(0, Vec::new())]),
+            current_bucket: 0,
+            current_bucket_start: Instant::now(),
+            bucket_width: (timeout / BUCKET_COUNT as u32).max(Duration::from_millis(1)),
+
+            max_connections: max_connections.max(1),
+            max_bytes,
+
+            stats: ConnectionStats::default(),
+        }
+    }
+
+    /// Create one of `shards` tables that share `scanner.max_connections`
+    pub fn from_config(shards: usize) -> Self {
+        let config = config::get();
+        Self::new(
+            Duration::from_secs(config.scanner.connection_timeout),
+            config.scanner.max_connections / shards.max(1),
+            config.scanner.max_response_bytes,
+        )
+    }
+
+    /// Start tracking a connection after receiving its SYN-ACK. Does nothing
+    /// if the connection is already tracked.
+    pub fn open(&mut self, addr: SocketAddr, syn_ack_sequence: u32, peer: PeerOptions) {
+        if self.connections.contains_key(&addr) {
+            return;
+        }
+        while self.connections.len() >= self.max_connections {
+            let evicted = self.pop_oldest_bucket();
+            self.stats.evicted += evicted;
+        }
+        self.connections.insert(
+            addr,
+            Connection {
+                buffer: ReassemblyBuffer::new(syn_ack_sequence),
+                peer,
+                bucket: self.current_bucket,
+            },
+        );
+        self.buckets.back_mut().unwrap().1.push(addr);
+    }
+
+    /// Add a segment to a connection's buffer.
+    ///
+    /// Returns `None` if the connection isn't tracked or was dropped because
+    /// its response grew too large, in which case it should be closed.
+    pub fn receive(
+        &mut self,
+        addr: SocketAddr,
+        sequence: u32,
+        payload: &[u8],
+    ) -> Option<&mut ReassemblyBuffer> {
+        let connection = self.connections.get_mut(&addr)?;
+        connection.buffer.insert(sequence, payload);
+        if connection.buffer.buffered_len() > self.max_bytes {
+            self.connections.remove(&addr);
+            self.stats.oversized += 1;
+            return None;
+        }
+        self.connections
+            .get_mut(&addr)
+            .map(|connection| &mut connection.buffer)
+    }
+
+    /// What the server agreed to in its SYN-ACK, updated with `segment`. For
+    /// connections that aren't tracked only the segment's timestamp is known.
+    pub fn peer(&mut self, addr: &SocketAddr, segment: &Tcp) -> PeerOptions {
+        match self.connections.get_mut(addr) {
+            Some(connection) => {
+                connection.peer.update(segment);
+                connection.peer
+            }
+            None => PeerOptions {
+                window_scale: false,
+                ..PeerOptions::from_syn_ack(segment)
+            },
+        }
+    }
+
+    pub fn close(&mut self, addr: &SocketAddr) {
+        self.connections.remove(addr);
+    }
+
+    /// How often [`expire`](Self::expire) should be called
+    pub fn expiry_interval(&self) -> Duration {
+        self.bucket_width
+    }
+
+    /// Expire connections that have been open for longer than the timeout.
+    ///
+    /// Only does work when a new bucket started. Returns whether one did.
+    pub fn expire(&mut self, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.current_bucket_start);
+        if elapsed < self.bucket_width {
+            return false;
+        }
+        let elapsed_buckets = (elapsed.as_nanos() / self.bucket_width.as_nanos()) as u64;
+        self.current_bucket += elapsed_buckets;
+        self.current_bucket_start += self.bucket_width * elapsed_buckets as u32;
+        self.buckets.push_back((self.current_bucket, Vec::new()));
+
+        while let Some((bucket, _)) = self.buckets.front() {
+            if bucket + BUCKET_COUNT >= self.current_bucket {
+                break;
+            }
+            let timed_out = self.pop_oldest_bucket();
+            self.stats.timed_out += timed_out;
+        }
+        true
+    }
+
+    /// Remove every connection still open from the oldest bucket, returning
+    /// how many were removed.
+    fn pop_oldest_bucket(&mut self) -> u64 {
+        let Some((bucket, addrs)) = self.buckets.pop_front() else {
+            return 0;
+        };
+        if self.buckets.is_empty() {
+            self.buckets.push_back((self.current_bucket, Vec::new()));
+        }
+        let mut removed = 0;
+        for addr in addrs {
+            // the connection could have been closed and opened again since
+            if self
+                .connections
+                .get(&addr)
+                .is_some_and(|connection| connection.bucket == bucket)
+            {
+                self.connections.remove(&addr);
+                removed += 1;
+            }
+        }
+        removed
+    }
+
+    pub fn stats(&self) -> ConnectionStats {
+        ConnectionStats {
+            open: self.connections.len(),
+            ..self.stats
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TIMEOUT: Duration = Duration::from_secs(8);
+
+    fn addr(i: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], i))
+    }
+
+    #[test]
+    fn drops_oversized_responses() {
+        let mut table = ConnectionTable::new(TIMEOUT, 16, 100);
+        table.open(addr(1), 0, PeerOptions::default());
+        assert!(table.receive(addr(1), 1, &[0; 60]).is_some());
+        assert!(table.receive(addr(1), 61, &[0; 40]).is_some());
+        assert!(table.receive(addr(1), 101, &[0; 1]).is_none());
+        assert!(table.receive(addr(1), 102, &[0; 1]).is_none());
+        assert_eq!(table.stats().oversized, 1);
+        assert_eq!(table.stats().open, 0);
+    }
+
+    #[test]
+    fn out_of_order_segments_count_towards_the_cap() {
+        let mut table = ConnectionTable::new(TIMEOUT, 16, 100);
+        table.open(addr(1), 0, PeerOptions::default());
+        assert!(table.receive(addr(1), 51, &[0; 50]).is_some());
+        assert!(table.receive(addr(1), 201, &[0; 51]).is_none());
+        assert_eq!(table.stats().oversized, 1);
+    }
+
+    #[test]
+    fn ignores_unknown_connections() {
+        let mut table = ConnectionTable::new(TIMEOUT, 16, 100);
+        assert!(table.receive(addr(1), 1, &[0; 10]).is_none());
+        assert_eq!(table.stats().oversized, 0);
+    }
+
+    #[test]
+    fn expires_after_the_timeout() {
+        let start = Instant::now();
+        let mut table = ConnectionTable::new(TIMEOUT, 16, 100);
+        let bucket = table.expiry_interval();
+        table.open(addr(1), 0, PeerOptions::default());
+
+        assert!(!table.expire(start + bucket / 2));
+        assert!(table.expire(start + bucket + bucket / 2));
+        table.open(addr(2), 0, PeerOptions::default());
+
+        // both are still younger than the timeout
+        assert!(table.expire(start + TIMEOUT + bucket / 2));
+        assert_eq!(table.stats().open, 2);
+        assert_eq!(table.stats().timed_out, 0);
+
+        assert!(table.expire(start + TIMEOUT + bucket + bucket / 2));
+        assert!(table.receive(addr(1), 1, &[0]).is_none());
+        assert!(table.receive(addr(2), 1, &[0]).is_some());
+        assert_eq!(table.stats().timed_out, 1);
+
+        // skipping several buckets at once expires everything older
+        assert!(table.expire(start + TIMEOUT * 3));
+        assert_eq!(table.stats().open, 0);
+        assert_eq!(table.stats().timed_out, 2);
+    }
+
+    #[test]
+    fn reopened_connections_keep_their_new_bucket() {
+        let start = Instant::now();
+        let mut table = ConnectionTable::new(TIMEOUT, 16, 100);
+        let bucket = table.expiry_interval();
+        table.open(addr(1), 0, PeerOptions::default());
+        table.close(&addr(1));
+        table.expire(start + bucket * 4 + bucket / 2);
+        table.open(addr(1), 0, PeerOptions::default());
+
+        table.expire(start + TIMEOUT + bucket * 2);
+        assert_eq!(table.stats().open, 1);
+        assert_eq!(table.stats().timed_out, 0);
+    }
+
+    #[test]
+    fn evicts_the_oldest_bucket_when_full() {
+        let start = Instant::now();
+        let mut table = ConnectionTable::new(TIMEOUT, 2, 100);
+        let bucket = table.expiry_interval();
+        table.open(addr(1), 0, PeerOptions::default());
+        table.expire(start + bucket + bucket / 2);
+        table.open(addr(2), 0, PeerOptions::default());
+        table.open(addr(3), 0, PeerOptions::default());
+
+        assert_eq!(table.stats().evicted, 1);
+        assert_eq!(table.stats().open, 2);
+        assert!(table.receive(addr(1), 1, &[0]).is_none());
+        assert!(table.receive(addr(2), 1, &[0]).is_some());
+        assert!(table.receive(addr(3), 1, &[0]).is_some());
+    }
+
+    #[test]
+    fn evicts_the_current_bucket_when_its_the_only_one() {
+        let mut table = ConnectionTable::new(TIMEOUT, 2, 100);
+        table.open(addr(1), 0, PeerOptions::default());
+        table.open(addr(2), 0, PeerOptions::default());
+        table.open(addr(3), 0, PeerOptions::default());
+
+        assert_eq!(table.stats().evicted, 2);
+        assert_eq!(table.stats().open, 1);
+        assert!(table.receive(addr(3), 1, &[0]).is_some());
+        // and the new connection still expires
+        table.expire(Instant::now() + TIMEOUT * 2);
+        assert_eq!(table.stats().open, 0);
+    }
+}
@@ -9,7 +9,7 @@ macro_rules! constants {
             type Output = u32;
 
             fn add(self, rhs: u32) -> Self::Output {
-                self as u32 + rhs
+                (self as u32).wrapping_add(rhs)
             }
         }
 
@@ -17,7 +17,7 @@ macro_rules! constants {
             type Output = u32;
 
             fn add(self, rhs: $name) -> Self::Output {
-                self + rhs as u32
+                self.wrapping_add(rhs as u32)
             }
         }
     };
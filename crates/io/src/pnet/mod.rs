@@ -1,62 +1,139 @@
 use self::constants::C2SSequenceNumbers;
-use super::{cookie, Io, COOKIE_SEED};
-use crate::ScannerState;
+use super::{Io, Probe};
+use crate::{
+    cookie::Cookies,
+    metrics::{Counters, COUNTERS},
+    ScannerState,
+};
 use common::net::{
+    rate_limit::RateLimiter,
     source_port::SourcePort,
-    tcp::{StatelessTcp, StatelessTcpWriteHalf},
+    tcp::{Fingerprint, StatelessTcp, StatelessTcpWriteHalf, Syn},
 };
 use database::{player::PlayerInfo, server::PingResult};
 use std::{
-    net::SocketAddrV4,
+    net::SocketAddr,
     sync::{mpsc::Sender, Arc},
 };
 use tokio::sync::Mutex;
 
+pub mod connections;
 pub mod constants;
 
+mod answered;
+mod reassembly;
 mod receive;
 
+pub use receive::ReceiveStats;
+
+/// How many SYNs are queued up before being sent with a single syscall
+const SYN_BATCH_SIZE: usize = 64;
+
 pub struct PnetScanner {
     pub state: Arc<Mutex<ScannerState>>,
     pub syn_writer: StatelessTcpWriteHalf,
     pub source_port: SourcePort,
+    cookies: Cookies,
+    pending_syns: Vec<Syn>,
 }
 
 impl PnetScanner {
     pub fn new(
         state: Arc<Mutex<ScannerState>>,
         sender: Sender<(PingResult, Vec<PlayerInfo>)>,
+        rate_limiter: RateLimiter,
+    ) -> eyre::Result<Self> {
+        let config = config::get();
+        let source_port = config.scanner.source_port;
+        if !source_port.is_valid() {
+            return Err(eyre::eyre!("invalid source port range {source_port:?}"));
+        }
+        let fingerprint = Fingerprint::new(config.scanner.fingerprint.fingerprint());
+        let socket = StatelessTcp::new(
+            &config.scanner.interface_name,
+            &config.scanner.source_ips,
+            config.scanner.source_ip_selection,
+            source_port,
+            fingerprint,
+            rate_limiter,
+        )?;
+        Ok(Self::with_socket(socket, state, sender))
+    }
+
+    /// Scan with an already set up socket, which the receive workers take
+    /// over
+    pub fn with_socket(
+        socket: StatelessTcp,
+        state: Arc<Mutex<ScannerState>>,
+        sender: Sender<(PingResult, Vec<PlayerInfo>)>,
     ) -> Self {
-        let source_port = SourcePort::Number(config::get().scanner.source_port);
-        let socket = StatelessTcp::new(source_port);
         let syn_writer = socket.write.clone();
-        tokio::spawn(async move { receive::start_server(socket, sender).await });
+        receive::start(socket, sender, state.clone());
         Self {
             state,
             syn_writer,
-            source_port,
+            source_port: config::get().scanner.source_port,
+            cookies: Cookies::default(),
+            pending_syns: Vec::with_capacity(SYN_BATCH_SIZE),
         }
     }
+
+    fn queue_syn(&mut self, syn: Syn) {
+        self.pending_syns.push(syn);
+        if self.pending_syns.len() >= SYN_BATCH_SIZE {
+            self.flush();
+        }
+    }
+
+    async fn probe(&mut self, addr: SocketAddr, attempt: u8, kind: C2SSequenceNumbers) {
+        // we don't have an address to scan this from
+        let Some(source_ip) = self.syn_writer.pick_source_ip(&addr) else {
+            return;
+        };
+        self.syn_writer.rate_limiter().acquire().await;
+        let source_port =
+            self.source_port
+                .pick(self.cookies.source_port_seed(&addr, &source_ip.ip, attempt));
+        let source = SocketAddr::new(source_ip.ip, source_port);
+        let addr_cookie = self.cookies.cookie(&addr, &source, attempt);
+        self.queue_syn(Syn {
+            addr,
+            source,
+            source_index: source_ip.index,
+            sequence: addr_cookie + kind,
+        });
+    }
 }
 
 impl Io for PnetScanner {
-    async fn ping(&mut self, addr: SocketAddrV4) -> Result<(), eyre::Report> {
-        let addr_cookie = cookie(&addr, *COOKIE_SEED);
-        self.syn_writer.send_syn(
-            addr,
-            self.source_port.pick(addr_cookie),
-            addr_cookie + C2SSequenceNumbers::SlpSynAck,
-        );
+    async fn ping(&mut self, addr: SocketAddr) -> Result<(), eyre::Report> {
+        self.probe(addr, 0, C2SSequenceNumbers::SlpSynAck).await;
         Ok(())
     }
 
-    async fn legacy_ping(&mut self, addr: SocketAddrV4) -> Result<(), eyre::Report> {
-        let addr_cookie = cookie(&addr, *COOKIE_SEED);
-        self.syn_writer.send_syn(
-            addr,
-            self.source_port.pick(addr_cookie),
-            addr_cookie + C2SSequenceNumbers::LegacySynAck,
-        );
+    async fn legacy_ping(&mut self, addr: SocketAddr) -> Result<(), eyre::Report> {
+        self.probe(addr, 0, C2SSequenceNumbers::LegacySynAck).await;
         Ok(())
     }
+
+    async fn retry(
+        &mut self,
+        addr: SocketAddr,
+        probe: Probe,
+        attempt: u8,
+    ) -> Result<(), eyre::Report> {
+        let kind = match probe {
+            Probe::Slp => C2SSequenceNumbers::SlpSynAck,
+            Probe::Legacy => C2SSequenceNumbers::LegacySynAck,
+        };
+        self.probe(addr, attempt, kind).await;
+        Ok(())
+    }
+
+    /// Send every queued SYN
+    fn flush(&mut self) {
+        let sent = self.syn_writer.send_syn_batch(&self.pending_syns);
+        Counters::add(&COUNTERS.syns_sent, sent as u64);
+        self.pending_syns.clear();
+    }
 }
@@ -0,0 +1,239 @@
+use std::collections::BTreeMap;
+
+/// Reassembles the byte stream sent by a server from TCP segments that can
+/// arrive out of order or more than once.
+pub struct ReassemblyBuffer {
+    /// Sequence number of the first byte of `data`
+    start_sequence: u32,
+    /// Contiguous bytes received so far
+    data: Vec<u8>,
+    /// Segments that arrived before the bytes preceding them, keyed by their
+    /// offset from `start_sequence`
+    out_of_order: BTreeMap<usize, Vec<u8>>,
+}
+
+impl ReassemblyBuffer {
+    /// Create a buffer for a connection whose SYN-ACK had the given sequence
+    /// number.
+    pub fn new(syn_ack_sequence: u32) -> Self {
+        Self {
+            start_sequence: syn_ack_sequence.wrapping_add(1),
+            data: Vec::with_capacity(512),
+            out_of_order: BTreeMap::new(),
+        }
+    }
+
+    /// Insert a segment into the buffer. Bytes that were already received are
+    /// ignored.
+    pub fn insert(&mut self, sequence: u32, payload: &[u8]) {
+        if payload.is_empty() {
+            return;
+        }
+        let offset = sequence.wrapping_sub(self.start_sequence) as i32;
+        // a segment starting before the buffer is a retransmit, only the part
+        // after the start (if any) is new
+        if offset < 0 {
+            let already_received = (offset.unsigned_abs() as usize).min(payload.len());
+            return self.insert(
+                sequence.wrapping_add(already_received as u32),
+                &payload[already_received..],
+            );
+        }
+        let offset = offset as usize;
+
+        if offset > self.data.len() {
+            let existing = self.out_of_order.entry(offset).or_default();
+            if existing.len() < payload.len() {
+                *existing = payload.to_vec();
+            }
+            return;
+        }
+
+        self.append_at(offset, payload);
+        while let Some(entry) = self.out_of_order.first_entry() {
+            if *entry.key() > self.data.len() {
+                break;
+            }
+            let (offset, segment) = entry.remove_entry();
+            self.append_at(offset, &segment);
+        }
+    }
+
+    fn append_at(&mut self, offset: usize, segment: &[u8]) {
+        let overlap = self.data.len() - offset;
+        if overlap < segment.len() {
+            self.data.extend_from_slice(&segment[overlap..]);
+        }
+    }
+
+    /// The sequence number of the next byte we expect, i.e. what to ACK
+    pub fn next_sequence(&self) -> u32 {
+        self.start_sequence.wrapping_add(self.data.len() as u32)
+    }
+
+    /// Total bytes held by the buffer, including out of order segments
+    pub fn buffered_len(&self) -> usize {
+        self.data.len() + self.out_of_order.values().map(Vec::len).sum::<usize>()
+    }
+
+    /// Contiguous bytes received so far
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// Returns the received packet including its length prefix once all of it
+    /// has arrived.
+    pub fn packet(&self) -> Option<&[u8]> {
+        let (prefix_len, packet_len) = read_varint(&self.data)?;
+        let total_len = prefix_len + packet_len;
+        if self.data.len() >= total_len {
+            Some(&self.data[..total_len])
+        } else {
+            None
+        }
+    }
+}
+
+/// Read a VarInt from the start of `data`, returning its size in bytes and its
+/// value. Returns `None` if the VarInt is incomplete or invalid.
+fn read_varint(data: &[u8]) -> Option<(usize, usize)> {
+    let mut value = 0u32;
+    for (i, byte) in data.iter().take(5).enumerate() {
+        value |= ((byte & 0b0111_1111) as u32) << (7 * i);
+        if byte & 0b1000_0000 == 0 {
+            return usize::try_from(value as i32)
+                .ok()
+                .map(|value| (i + 1, value));
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SYN_ACK_SEQUENCE: u32 = 1000;
+    const START: u32 = SYN_ACK_SEQUENCE + 1;
+
+    /// A packet with a two byte length prefix, so it can be split inside it
+    fn long_packet() -> Vec<u8> {
+        let body = (0..200).map(|i| i as u8).collect::<Vec<_>>();
+        let mut packet = vec![0b1100_1000, 0b0000_0001]; // 200
+        packet.extend_from_slice(&body);
+        packet
+    }
+
+    #[test]
+    fn in_order() {
+        let packet = long_packet();
+        let mut buffer = ReassemblyBuffer::new(SYN_ACK_SEQUENCE);
+        buffer.insert(START, &packet[..100]);
+        assert_eq!(buffer.packet(), None);
+        buffer.insert(START + 100, &packet[100..]);
+        assert_eq!(buffer.packet(), Some(packet.as_slice()));
+        assert_eq!(buffer.next_sequence(), START + packet.len() as u32);
+    }
+
+    #[test]
+    fn out_of_order() {
+        let packet = long_packet();
+        let mut buffer = ReassemblyBuffer::new(SYN_ACK_SEQUENCE);
+        buffer.insert(START + 150, &packet[150..]);
+        buffer.insert(START + 50, &packet[50..150]);
+        assert_eq!(buffer.data(), &[] as &[u8]);
+        assert_eq!(buffer.next_sequence(), START);
+        assert_eq!(buffer.buffered_len(), packet.len() - 50);
+
+        buffer.insert(START, &packet[..50]);
+        assert_eq!(buffer.packet(), Some(packet.as_slice()));
+        assert_eq!(buffer.buffered_len(), packet.len());
+    }
+
+    #[test]
+    fn duplicates() {
+        let packet = long_packet();
+        let mut buffer = ReassemblyBuffer::new(SYN_ACK_SEQUENCE);
+        buffer.insert(START, &packet[..100]);
+        buffer.insert(START, &packet[..100]);
+        buffer.insert(START + 150, &packet[150..]);
+        buffer.insert(START + 150, &packet[150..]);
+        assert_eq!(buffer.data(), &packet[..100]);
+        buffer.insert(START + 100, &packet[100..150]);
+        buffer.insert(START + 100, &packet[100..150]);
+        assert_eq!(buffer.data(), packet.as_slice());
+        assert_eq!(buffer.buffered_len(), packet.len());
+    }
+
+    #[test]
+    fn overlapping() {
+        let packet = long_packet();
+        let mut buffer = ReassemblyBuffer::new(SYN_ACK_SEQUENCE);
+        buffer.insert(START, &packet[..100]);
+        // retransmit starting before what we have
+        buffer.insert(START + 50, &packet[50..120]);
+        assert_eq!(buffer.data(), &packet[..120]);
+        // out of order segments that overlap each other
+        buffer.insert(START + 160, &packet[160..]);
+        buffer.insert(START + 140, &packet[140..180]);
+        buffer.insert(START + 110, &packet[110..150]);
+        assert_eq!(buffer.data(), packet.as_slice());
+        assert_eq!(buffer.packet(), Some(packet.as_slice()));
+    }
+
+    #[test]
+    fn retransmit_before_start() {
+        let packet = long_packet();
+        let mut buffer = ReassemblyBuffer::new(SYN_ACK_SEQUENCE);
+        // a segment that includes the SYN's sequence number
+        let mut segment = vec![0xaa];
+        segment.extend_from_slice(&packet);
+        buffer.insert(SYN_ACK_SEQUENCE, &segment);
+        assert_eq!(buffer.packet(), Some(packet.as_slice()));
+        // and one that's entirely old
+        buffer.insert(SYN_ACK_SEQUENCE - 10, &[0xbb; 5]);
+        assert_eq!(buffer.data(), packet.as_slice());
+    }
+
+    #[test]
+    fn split_varint() {
+        let packet = long_packet();
+        let mut buffer = ReassemblyBuffer::new(SYN_ACK_SEQUENCE);
+        buffer.insert(START, &packet[..1]);
+        assert_eq!(buffer.packet(), None);
+        buffer.insert(START + 1, &packet[1..2]);
+        assert_eq!(buffer.packet(), None);
+        buffer.insert(START + 2, &packet[2..]);
+        assert_eq!(buffer.packet(), Some(packet.as_slice()));
+    }
+
+    #[test]
+    fn trailing_data_is_not_part_of_the_packet() {
+        let mut buffer = ReassemblyBuffer::new(SYN_ACK_SEQUENCE);
+        buffer.insert(START, &[0x02, 0x00, 0x01, 0xff]);
+        assert_eq!(buffer.packet(), Some([0x02, 0x00, 0x01].as_slice()));
+    }
+
+    #[test]
+    fn wraps_around() {
+        let packet = long_packet();
+        let mut buffer = ReassemblyBuffer::new(u32::MAX - 10);
+        let start = (u32::MAX - 10).wrapping_add(1);
+        buffer.insert(start.wrapping_add(100), &packet[100..]);
+        buffer.insert(start, &packet[..100]);
+        assert_eq!(buffer.packet(), Some(packet.as_slice()));
+        assert_eq!(
+            buffer.next_sequence(),
+            start.wrapping_add(packet.len() as u32)
+        );
+    }
+
+    #[test]
+    fn invalid_varint() {
+        assert_eq!(read_varint(&[0xff, 0xff, 0xff, 0xff, 0xff]), None);
+        // negative lengths
+        assert_eq!(read_varint(&[0xff, 0xff, 0xff, 0xff, 0x0f]), None);
+        assert_eq!(read_varint(&[0x80]), None);
+        assert_eq!(read_varint(&[0xc8, 0x01]), Some((2, 200)));
+    }
+}
@@ -1,10 +1,31 @@
-use super::constants::{C2SAcknowledgementNumbers, C2SSequenceNumbers, S2CAcknowledgementNumbers};
-use crate::{cookie, COOKIE_SEED};
+use super::{
+    answered::Answered,
+    connections::{ConnectionStats, ConnectionTable},
+    constants::{C2SSequenceNumbers, S2CAcknowledgementNumbers},
+};
+use crate::{
+    cookie::Cookies,
+    legacy::LegacyPingResponse,
+    metrics::{Counters, COUNTERS},
+    ScannerState,
+};
 use azalea_protocol::{packets::status::ClientboundStatusPacket, read::deserialize_packet};
-use common::net::tcp::StatelessTcp;
+use common::net::tcp::{PeerOptions, StatelessTcp, StatelessTcpReadHalf, StatelessTcpWriteHalf};
 use database::{player::PlayerInfo, server::PingResult};
-use pnet::packet::tcp::TcpFlags;
-use std::{collections::HashMap, io::Cursor, net::SocketAddrV4, sync::mpsc::Sender};
+use pnet::packet::tcp::{Tcp, TcpFlags};
+use std::{
+    io::Cursor,
+    net::SocketAddr,
+    sync::{mpsc::Sender, Arc},
+    time::{Duration, Instant},
+};
+use tokio::{
+    sync::{
+        mpsc::{self, error::TrySendError},
+        Mutex, Semaphore,
+    },
+    time::MissedTickBehavior,
+};
 
 #[rustfmt::skip]
 pub mod const_packets {
@@ -36,16 +57,214 @@ use const_packets::*;
 const SYN_ACK: u8 = TcpFlags::SYN | TcpFlags::ACK;
 const FIN_ACK: u8 = TcpFlags::FIN | TcpFlags::ACK;
 
-pub async fn start_server(mut socket: StatelessTcp, sender: Sender<(PingResult, Vec<PlayerInfo>)>) {
-    let mut awaiting_data_map: HashMap<SocketAddrV4, Vec<u8>> = HashMap::new();
+// Packets are classified by how much of our sequence space they acknowledge,
+// relative to the cookie. The server's own sequence numbers start at its ISN,
+// which has nothing to do with the cookie, so they can't be used for this.
+const SLP_SYN_ACK: u32 = S2CAcknowledgementNumbers::SlpSynAck as u32;
+const SLP_RESPONSE_PAYLOAD: u32 = S2CAcknowledgementNumbers::SlpResponsePayload as u32;
+const LEGACY_SYN_ACK: u32 = S2CAcknowledgementNumbers::LegacySynAck as u32;
+const LEGACY_RESPONSE_PAYLOAD: u32 = S2CAcknowledgementNumbers::LegacyResponsePayload as u32;
 
-    while let Some((ip, mut tcp)) = socket.read.recv() {
-        let source_addr = SocketAddrV4::new(ip.source, tcp.source);
-        let cookie = cookie(&source_addr, *COOKIE_SEED);
-        let sequence = tcp.sequence - cookie;
-        match sequence {
+/// The acknowledgement of every packet we expect, relative to the cookie
+const EXPECTED_ACKNOWLEDGEMENTS: [u32; 4] = [
+    SLP_SYN_ACK,
+    SLP_RESPONSE_PAYLOAD,
+    LEGACY_SYN_ACK,
+    LEGACY_RESPONSE_PAYLOAD,
+];
+
+/// How often the classifier publishes its [`ReceiveStats`]
+const REPORT_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Default, Clone)]
+pub struct ReceiveStats {
+    /// Packets handed to a worker
+    pub received: u64,
+    /// Packets dropped because their worker's queue was full
+    pub dropped: u64,
+    /// Packets dropped because they don't acknowledge one of our cookies,
+    /// they're either spoofed or very late
+    pub invalid: u64,
+    /// RSTs in response to our SYNs, from addresses with nothing listening
+    pub rsts: u64,
+    /// SYN-ACKs received for every attempt, the first ping being attempt 0
+    pub syn_acks: Vec<u64>,
+    /// SYN-ACKs to retries of addresses that already answered an earlier
+    /// attempt, they're reset instead of being scanned again
+    pub duplicate_syn_acks: u64,
+    /// Packets waiting in each worker's queue
+    pub queue_depths: Vec<usize>,
+}
+
+/// A packet on its way from the classifier to a worker
+struct Segment {
+    source_addr: SocketAddr,
+    /// Which of our addresses the packet was sent to
+    local_addr: SocketAddr,
+    cookie: u32,
+    tcp: Tcp,
+    /// A SYN-ACK from a server that already answered another attempt
+    duplicate: bool,
+}
+
+/// Start receiving responses.
+///
+/// Packets are read by a classifier thread that does nothing but check the
+/// cookie and hand the packet to one of `scanner.receive_workers` workers, so
+/// a connection is always handled by the same worker. If a
+/// worker falls behind its queue fills up and packets for it are dropped,
+/// the server will retransmit them.
+pub fn start(
+    socket: StatelessTcp,
+    sender: Sender<(PingResult, Vec<PlayerInfo>)>,
+    state: Arc<Mutex<ScannerState>>,
+) {
+    let config = config::get();
+    let worker_count = config.scanner.receive_workers.max(1);
+    let (read, write) = socket.into_split();
+
+    let decodes = Arc::new(Semaphore::new(config.scanner.decode_tasks.max(1)));
+    let mut queues = Vec::with_capacity(worker_count);
+    for shard in 0..worker_count {
+        let (queue_sender, queue) = mpsc::channel(config.scanner.receive_queue_size.max(1));
+        queues.push(queue_sender);
+        tokio::spawn(start_worker(
+            shard,
+            queue,
+            write.clone(),
+            ConnectionTable::from_config(worker_count),
+            decodes.clone(),
+            sender.clone(),
+            state.clone(),
+        ));
+    }
+
+    std::thread::spawn(move || classify(read, queues, state));
+}
+
+fn classify(
+    mut read: StatelessTcpReadHalf,
+    queues: Vec<mpsc::Sender<Segment>>,
+    state: Arc<Mutex<ScannerState>>,
+) {
+    let attempts = config::get().scanner.retries.saturating_add(1);
+    let mut stats = ReceiveStats {
+        queue_depths: vec![0; queues.len()],
+        syn_acks: vec![0; attempts as usize],
+        ..Default::default()
+    };
+    let mut last_report = Instant::now();
+    let mut cookies = Cookies::default();
+    let mut answered = Answered::from_config();
+
+    loop {
+        if last_report.elapsed() >= REPORT_INTERVAL {
+            for (depth, queue) in stats.queue_depths.iter_mut().zip(&queues) {
+                *depth = queue.max_capacity() - queue.capacity();
+            }
+            state.blocking_lock().receive = stats.clone();
+            last_report = Instant::now();
+        }
+        // wake up in time for the next report even if nothing comes in, or
+        // the stats would stay stale while the scanner is quiet
+        let (ip, local_ip, tcp) =
+            match read.recv(REPORT_INTERVAL.saturating_sub(last_report.elapsed())) {
+                Ok(Some(packet)) => packet,
+                Ok(None) if queues.iter().any(|queue| queue.is_closed()) => return,
+                Ok(None) => continue,
+                Err(_) => return,
+            };
+
+        let source_addr = SocketAddr::new(ip, tcp.source);
+        let local_addr = SocketAddr::new(local_ip, tcp.destination);
+        // don't even respond to packets that aren't for us
+        let Some((cookie, attempt)) = cookies.validate(
+            &source_addr,
+            &local_addr,
+            tcp.acknowledgement,
+            &EXPECTED_ACKNOWLEDGEMENTS,
+            attempts,
+        ) else {
+            stats.invalid += 1;
+            continue;
+        };
+        let mut duplicate = false;
+        if tcp.flags & SYN_ACK == SYN_ACK {
+            stats.syn_acks[attempt as usize] += 1;
+            // only the first answer is scanned, otherwise a server that
+            // answers every attempt would be pushed once per attempt
+            if attempts > 1 && !answered.is_first(source_addr, attempt, Instant::now()) {
+                stats.duplicate_syn_acks += 1;
+                duplicate = true;
+            }
+        }
+        if tcp.flags & TcpFlags::RST != 0 {
+            stats.rsts += 1;
+        }
+        let queue = &queues[cookie as usize % queues.len()];
+        match queue.try_send(Segment {
+            source_addr,
+            local_addr,
+            cookie,
+            tcp,
+            duplicate,
+        }) {
+            Ok(()) => stats.received += 1,
+            Err(TrySendError::Full(_)) => stats.dropped += 1,
+            Err(TrySendError::Closed(_)) => return,
+        }
+    }
+}
+
+async fn start_worker(
+    shard: usize,
+    mut queue: mpsc::Receiver<Segment>,
+    mut write: StatelessTcpWriteHalf,
+    mut connections: ConnectionTable,
+    decodes: Arc<Semaphore>,
+    sender: Sender<(PingResult, Vec<PlayerInfo>)>,
+    state: Arc<Mutex<ScannerState>>,
+) {
+    // expire connections even when no packets come in, otherwise the last
+    // ones of a scan would never time out
+    let mut expiry = tokio::time::interval(connections.expiry_interval());
+    expiry.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        let segment = tokio::select! {
+            segment = queue.recv() => segment,
+            _ = expiry.tick() => {
+                if connections.expire(Instant::now()) {
+                    report_connections(&state, shard, connections.stats()).await;
+                }
+                continue;
+            }
+        };
+        let Some(Segment {
+            source_addr,
+            local_addr,
+            cookie,
+            tcp,
+            duplicate,
+        }) = segment
+        else {
+            return;
+        };
+        if duplicate {
+            write.send_rst(
+                source_addr,
+                local_addr,
+                tcp.acknowledgement,
+                tcp.sequence.wrapping_add(1),
+                PeerOptions::default(),
+            );
+            continue;
+        }
+
+        let fin = tcp.flags & TcpFlags::FIN == TcpFlags::FIN;
+        let acknowledgement = tcp.acknowledgement.wrapping_sub(cookie);
+        match acknowledgement {
             // syn + ack
-            0x00000000 => {
+            SLP_SYN_ACK => {
                 if tcp.flags & SYN_ACK != SYN_ACK {
                     #[cfg(debug_assertions)]
                     println!(
@@ -53,122 +272,182 @@ pub async fn start_server(mut socket: StatelessTcp, sender: Sender<(PingResult,
                         tcp.flags & SYN_ACK,
                         tcp.flags
                     );
-                    return;
-                }
-                if cookie + S2CAcknowledgementNumbers::SlpSynAck != tcp.acknowledgement {
-                    #[cfg(debug_assertions)]
-                    println!(
-                        "expected ack = {}\ngot ack = {}",
-                        cookie + S2CAcknowledgementNumbers::SlpSynAck,
-                        tcp.acknowledgement
-                    );
-                    return;
+                    continue;
                 }
-                socket.write.send_ack(
+                let peer = PeerOptions::from_syn_ack(&tcp);
+                connections.open(source_addr, tcp.sequence, peer);
+                write.send_ack(
                     source_addr,
-                    tcp.destination,
-                    cookie + C2SSequenceNumbers::SlpSynAck,
-                    cookie + C2SAcknowledgementNumbers::SlpAck,
+                    local_addr,
+                    cookie + C2SSequenceNumbers::SlpResponsePayload,
+                    tcp.sequence.wrapping_add(1),
+                    peer,
                 );
-                socket.write.send_data(
+                write.send_data(
                     source_addr,
-                    tcp.destination,
-                    cookie + C2SSequenceNumbers::SlpSynAck,
-                    cookie + C2SAcknowledgementNumbers::SlpAck,
+                    local_addr,
+                    cookie + C2SSequenceNumbers::SlpResponsePayload,
+                    tcp.sequence.wrapping_add(1),
                     &SLP_PING_PACKET,
+                    peer,
                 );
             }
             // payload
-            0x00000001..=0x00000009 => {
-                if cookie + S2CAcknowledgementNumbers::SlpResponsePayload != tcp.acknowledgement {
-                    #[cfg(debug_assertions)]
-                    println!(
-                        "expected ack = {}\ngot ack = {}",
-                        cookie + S2CAcknowledgementNumbers::SlpResponsePayload,
-                        tcp.acknowledgement
-                    );
-                    return;
-                }
-                let buffer = match awaiting_data_map.get_mut(&source_addr) {
-                    Some(buffer) => buffer,
-                    None => {
-                        let buffer = Vec::with_capacity(512);
-                        awaiting_data_map.insert(source_addr, buffer);
-                        awaiting_data_map.get_mut(&source_addr).unwrap()
+            SLP_RESPONSE_PAYLOAD => {
+                let peer = connections.peer(&source_addr, &tcp);
+                let Some(buffer) = connections.receive(source_addr, tcp.sequence, &tcp.payload)
+                else {
+                    close(&mut write, source_addr, local_addr, &tcp, peer);
+                    continue;
+                };
+                let Some(packet) = buffer.packet() else {
+                    if fin {
+                        #[cfg(debug_assertions)]
+                        println!(
+                            "Connection from {source_addr} closed before response was complete"
+                        );
+                        close(&mut write, source_addr, local_addr, &tcp, peer);
+                        connections.close(&source_addr);
+                    } else {
+                        write.send_ack(
+                            source_addr,
+                            local_addr,
+                            tcp.acknowledgement,
+                            buffer.next_sequence(),
+                            peer,
+                        );
                     }
+                    continue;
                 };
-                let payload_len = tcp.payload.len();
-                buffer.append(&mut tcp.payload);
-                if tcp.flags & TcpFlags::FIN == TcpFlags::FIN {
-                    socket.write.send_fin(
-                        source_addr,
-                        tcp.destination,
-                        tcp.acknowledgement,
-                        tcp.sequence + payload_len as u32,
-                    );
-                    let mut buffer = Cursor::new(buffer.as_slice());
-                    let ping_response =
-                        match deserialize_packet::<ClientboundStatusPacket>(&mut buffer) {
-                            Ok(ClientboundStatusPacket::StatusResponse(ping_response)) => {
-                                ping_response
-                            }
-                            _ => return,
-                        };
-                    let ping_result =
-                        PingResult::from_azalea(ip.source, tcp.source, &ping_response);
-                    let player_info = PlayerInfo::from_azalea(&ping_response).await;
-                    let _ = sender.send((ping_result, player_info));
-                    awaiting_data_map.remove(&source_addr);
-                }
-            }
-            0x00000010 => {
-                #[cfg(debug_assertions)]
-                println!("Connection from {source_addr} cut off after too many packets");
-                socket.write.send_fin(
+                // decoding the status and looking up players can be slow, so
+                // it's done in its own task. There are at most
+                // `scanner.decode_tasks` of them, when they fall behind the
+                // worker waits and its queue fills up.
+                let packet = packet.to_vec();
+                write.send_fin(
                     source_addr,
-                    tcp.destination,
+                    local_addr,
                     tcp.acknowledgement,
-                    tcp.sequence + 1,
+                    buffer.next_sequence().wrapping_add(fin as u32),
+                    peer,
                 );
-                awaiting_data_map.remove(&source_addr);
-            }
-            // syn + ack
-            0x10000000 => {
-                if cookie + S2CAcknowledgementNumbers::LegacySynAck != tcp.acknowledgement {
+                connections.close(&source_addr);
+
+                let Ok(permit) = decodes.clone().acquire_owned().await else {
                     return;
+                };
+                let sender = sender.clone();
+                tokio::spawn(async move {
+                    let _permit = permit;
+                    let Ok(ClientboundStatusPacket::StatusResponse(ping_response)) =
+                        deserialize_packet::<ClientboundStatusPacket>(&mut Cursor::new(&packet))
+                    else {
+                        Counters::add(&COUNTERS.parse_failures, 1);
+                        return;
+                    };
+                    Counters::add(&COUNTERS.statuses, 1);
+                    let ping_result = PingResult::from_azalea(
+                        source_addr.ip(),
+                        source_addr.port(),
+                        &ping_response,
+                    );
+                    let player_info = PlayerInfo::from_azalea(&ping_response).await;
+                    let _ = sender.send((ping_result, player_info));
+                });
+            }
+            // legacy syn + ack
+            LEGACY_SYN_ACK => {
+                if tcp.flags & SYN_ACK != SYN_ACK {
+                    continue;
                 }
-                socket.write.send_ack(
+                let peer = PeerOptions::from_syn_ack(&tcp);
+                connections.open(source_addr, tcp.sequence, peer);
+                write.send_ack(
                     source_addr,
-                    tcp.destination,
-                    cookie + C2SSequenceNumbers::LegacySynAck,
-                    cookie + C2SAcknowledgementNumbers::LegacyAck,
+                    local_addr,
+                    cookie + C2SSequenceNumbers::LegacyResponsePayload,
+                    tcp.sequence.wrapping_add(1),
+                    peer,
                 );
-                socket.write.send_data(
+                write.send_data(
                     source_addr,
-                    tcp.destination,
-                    cookie + C2SSequenceNumbers::LegacySynAck,
-                    cookie + C2SAcknowledgementNumbers::LegacyAck,
+                    local_addr,
+                    cookie + C2SSequenceNumbers::LegacyResponsePayload,
+                    tcp.sequence.wrapping_add(1),
                     &LEGACY_PING_PACKET,
+                    peer,
                 );
             }
-            _ => {
-                if tcp.flags & FIN_ACK == FIN_ACK {
-                    socket.write.send_ack(
-                        source_addr,
-                        tcp.destination,
-                        tcp.acknowledgement,
-                        tcp.sequence + 1,
-                    );
-                } else {
-                    socket.write.send_fin(
-                        source_addr,
-                        tcp.destination,
-                        tcp.acknowledgement,
-                        tcp.sequence + 1,
-                    );
+            // legacy payload
+            LEGACY_RESPONSE_PAYLOAD => {
+                let peer = connections.peer(&source_addr, &tcp);
+                let Some(buffer) = connections.receive(source_addr, tcp.sequence, &tcp.payload)
+                else {
+                    close(&mut write, source_addr, local_addr, &tcp, peer);
+                    continue;
+                };
+                match LegacyPingResponse::decode(buffer.data()) {
+                    Ok(Some(response)) => {
+                        Counters::add(&COUNTERS.statuses, 1);
+                        let ping_result =
+                            response.to_ping_result(source_addr.ip(), source_addr.port());
+                        let _ = sender.send((ping_result, vec![]));
+                    }
+                    Ok(None) if !fin => {
+                        // the kick packet was split, wait for the rest of it
+                        write.send_ack(
+                            source_addr,
+                            local_addr,
+                            tcp.acknowledgement,
+                            buffer.next_sequence(),
+                            peer,
+                        );
+                        continue;
+                    }
+                    Ok(None) => {
+                        #[cfg(debug_assertions)]
+                        println!("Connection from {source_addr} closed before legacy response was complete");
+                    }
+                    Err(_err) => {
+                        Counters::add(&COUNTERS.parse_failures, 1);
+                        #[cfg(debug_assertions)]
+                        println!("Invalid legacy response from {source_addr}: {_err}");
+                    }
                 }
-                awaiting_data_map.remove(&source_addr);
+                write.send_fin(
+                    source_addr,
+                    local_addr,
+                    tcp.acknowledgement,
+                    buffer.next_sequence().wrapping_add(fin as u32),
+                    peer,
+                );
+                connections.close(&source_addr);
             }
+            _ => unreachable!("the classifier only passes on expected acknowledgements"),
         }
     }
 }
+
+async fn report_connections(state: &Mutex<ScannerState>, shard: usize, stats: ConnectionStats) {
+    let mut state = state.lock().await;
+    if state.connections.len() <= shard {
+        state.connections.resize_with(shard + 1, Default::default);
+    }
+    state.connections[shard] = stats;
+}
+
+/// Close a connection we don't (or no longer) care about
+fn close(
+    write: &mut StatelessTcpWriteHalf,
+    source_addr: SocketAddr,
+    local_addr: SocketAddr,
+    tcp: &Tcp,
+    peer: PeerOptions,
+) {
+    let sequence = tcp.sequence.wrapping_add(tcp.payload.len() as u32 + 1);
+    if tcp.flags & FIN_ACK == FIN_ACK {
+        write.send_ack(source_addr, local_addr, tcp.acknowledgement, sequence, peer);
+    } else {
+        write.send_fin(source_addr, local_addr, tcp.acknowledgement, sequence, peer);
+    }
+}
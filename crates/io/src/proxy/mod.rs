@@ -1,16 +1,275 @@
-use boringtun::{
-    noise::Tunn,
-    x25519::{PublicKey, StaticSecret},
+//! Where full connections come from, so status pings by
+//! [`NetworkScanner`](crate::network::NetworkScanner) and joins can use a
+//! different egress than the SYN scanner.
+
+mod wireguard;
+
+pub use wireguard::WireguardTunnel;
+
+use config::ProxyConfig;
+use socks::Socks5Stream;
+use std::{
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
 };
+use tokio::{net::TcpStream, sync::Semaphore};
+
+/// How many connects through SOCKS5 proxies can be running at once. They run
+/// on tokio's blocking threads, which are shared with everything else and
+/// limited to 512.
+const MAX_SOCKS5_CONNECTS: usize = 256;
+
+#[derive(Clone, Default, Debug)]
+pub enum NetworkProxy {
+    /// Connect from this host
+    #[default]
+    Direct,
+    Socks5(Arc<Socks5Pool>),
+    Wireguard(Arc<WireguardTunnel>),
+}
+
+#[derive(Debug)]
+pub struct Socks5Pool {
+    servers: Vec<SocketAddr>,
+    /// Username and password
+    auth: Option<(String, String)>,
+    next: AtomicUsize,
+    /// How long connecting through the proxy can take
+    timeout: Duration,
+    /// Held by every connect until its blocking thread is done, even if we
+    /// stopped waiting for it
+    connects: Arc<Semaphore>,
+}
+
+impl NetworkProxy {
+    /// A WireGuard tunnel is started right away, so this has to be called
+    /// inside the tokio runtime
+    pub fn new(config: &ProxyConfig, timeout: Duration) -> eyre::Result<Self> {
+        Ok(match config {
+            ProxyConfig::Direct => NetworkProxy::Direct,
+            ProxyConfig::Socks5 {
+                servers,
+                username,
+                password,
+            } => {
+                if servers.is_empty() {
+                    return Err(eyre::eyre!("the socks5 proxy needs at least one server"));
+                }
+                let auth = match (username, password) {
+                    (Some(username), Some(password)) => Some((username.clone(), password.clone())),
+                    (None, None) => None,
+                    _ => {
+                        return Err(eyre::eyre!(
+                            "the socks5 proxy needs both a username and a password, or neither"
+                        ))
+                    }
+                };
+                NetworkProxy::Socks5(Arc::new(Socks5Pool {
+                    servers: servers.clone(),
+                    auth,
+                    next: AtomicUsize::new(0),
+                    timeout,
+                    connects: Arc::new(Semaphore::new(MAX_SOCKS5_CONNECTS)),
+                }))
+            }
+            ProxyConfig::Wireguard(config) => {
+                NetworkProxy::Wireguard(Arc::new(WireguardTunnel::new(config, timeout)?))
+            }
+        })
+    }
+
+    /// Open a TCP connection to `addr` through the proxy
+    pub async fn connect(&self, addr: SocketAddr) -> eyre::Result<TcpStream> {
+        match self {
+            NetworkProxy::Direct => Ok(TcpStream::connect(addr).await?),
+            NetworkProxy::Socks5(pool) => pool.connect(addr).await,
+            NetworkProxy::Wireguard(tunnel) => tunnel.connect(addr).await,
+        }
+    }
+}
+
+impl Socks5Pool {
+    async fn connect(&self, addr: SocketAddr) -> eyre::Result<TcpStream> {
+        let server = self.servers[self.next.fetch_add(1, Ordering::Relaxed) % self.servers.len()];
+        let auth = self.auth.clone();
+        // The socks crate only does blocking io and can't time out, so a
+        // proxy that stops answering keeps the thread busy until the OS gives
+        // up on the connection. We just stop waiting for it, and the permit
+        // keeps abandoned threads from piling up.
+        let connects = self.connects.clone();
+        let connect = async move {
+            let permit = connects.acquire_owned().await?;
+            Ok::<_, eyre::Report>(
+                tokio::task::spawn_blocking(move || {
+                    let _permit = permit;
+                    match auth {
+                        Some((username, password)) => {
+                            Socks5Stream::connect_with_password(server, addr, &username, &password)
+                        }
+                        None => Socks5Stream::connect(server, addr),
+                    }
+                })
+                .await?,
+            )
+        };
+        let stream = tokio::time::timeout(self.timeout, connect)
+            .await
+            .map_err(|_| eyre::eyre!("connecting to {addr} through {server} timed out"))??
+            .map_err(|err| eyre::eyre!("unable to connect to {addr} through {server}: {err}"))?
+            .into_inner();
+        stream.set_nonblocking(true)?;
+        Ok(TcpStream::from_std(stream)?)
+    }
+}
+
+/// The proxy from the config
+pub fn get_proxy() -> eyre::Result<NetworkProxy> {
+    let config = config::get();
+    NetworkProxy::new(
+        &config.proxy,
+        Duration::from_secs(config.scanner.connection_timeout),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+        sync::oneshot,
+    };
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+    /// Nothing connects to it, the test server echoes instead
+    const TARGET: &str = "192.0.2.1:25565";
+
+    fn proxy(server: SocketAddr, auth: Option<(&str, &str)>, timeout: Duration) -> NetworkProxy {
+        NetworkProxy::new(
+            &ProxyConfig::Socks5 {
+                servers: vec![server],
+                username: auth.map(|(username, _)| username.to_string()),
+                password: auth.map(|(_, password)| password.to_string()),
+            },
+            timeout,
+        )
+        .unwrap()
+    }
+
+    /// Accept one connection, check it asks for [`TARGET`] with `auth` and
+    /// echo everything sent through it instead of connecting anywhere
+    async fn socks5_server(auth: Option<(&'static str, &'static str)>) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+
+            // version, method count, methods
+            let mut greeting = [0; 2];
+            socket.read_exact(&mut greeting).await.unwrap();
+            assert_eq!(greeting[0], 5);
+            let mut methods = vec![0; greeting[1] as usize];
+            socket.read_exact(&mut methods).await.unwrap();
+            let method = if auth.is_some() { 2 } else { 0 };
+            assert!(methods.contains(&method));
+            socket.write_all(&[5, method]).await.unwrap();
+
+            if let Some((username, password)) = auth {
+                // version, then the username and password prefixed with
+                // their lengths
+                assert_eq!(socket.read_u8().await.unwrap(), 1);
+                let mut received = vec![0; socket.read_u8().await.unwrap() as usize];
+                socket.read_exact(&mut received).await.unwrap();
+                assert_eq!(received, username.as_bytes());
+                let mut received = vec![0; socket.read_u8().await.unwrap() as usize];
+                socket.read_exact(&mut received).await.unwrap();
+                assert_eq!(received, password.as_bytes());
+                socket.write_all(&[1, 0]).await.unwrap();
+            }
+
+            // version, connect, reserved, IPv4 address and port
+            let mut request = [0; 10];
+            socket.read_exact(&mut request).await.unwrap();
+            assert_eq!(request[..4], [5, 1, 0, 1]);
+            let ip = <[u8; 4]>::try_from(&request[4..8]).unwrap();
+            let port = u16::from_be_bytes([request[8], request[9]]);
+            assert_eq!(SocketAddr::from((ip, port)), TARGET.parse().unwrap());
+            socket
+                .write_all(&[5, 0, 0, 1, 0, 0, 0, 0, 0, 0])
+                .await
+                .unwrap();
+
+            let mut buffer = [0; 64];
+            loop {
+                let read = socket.read(&mut buffer).await.unwrap();
+                if read == 0 {
+                    break;
+                }
+                socket.write_all(&buffer[..read]).await.unwrap();
+            }
+        });
+        addr
+    }
+
+    async fn assert_echoes(mut stream: TcpStream) {
+        stream.write_all(b"hello").await.unwrap();
+        let mut response = [0; 5];
+        stream.read_exact(&mut response).await.unwrap();
+        assert_eq!(&response, b"hello");
+    }
+
+    #[tokio::test]
+    async fn connects_through_socks5() {
+        let server = socks5_server(None).await;
+        let stream = proxy(server, None, TIMEOUT)
+            .connect(TARGET.parse().unwrap())
+            .await
+            .unwrap();
+        assert_echoes(stream).await;
+    }
 
-pub trait NetworkProxy {}
+    #[tokio::test]
+    async fn connects_through_socks5_with_a_password() {
+        let server = socks5_server(Some(("snow", "storm"))).await;
+        let stream = proxy(server, Some(("snow", "storm")), TIMEOUT)
+            .connect(TARGET.parse().unwrap())
+            .await
+            .unwrap();
+        assert_echoes(stream).await;
+    }
 
-pub fn get_proxy() -> Result<Box<dyn NetworkProxy>, eyre::Report> {
-    let static_private = StaticSecret::from([0; 32]);
-    let peer_static_public = PublicKey::from([0; 32]);
+    #[tokio::test]
+    async fn times_out_when_the_proxy_doesnt_answer() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let server = listener.local_addr().unwrap();
+        let (done, wait) = oneshot::channel::<()>();
+        tokio::spawn(async move {
+            // hold the connection without answering until the test is done,
+            // closing it lets the blocked connect finish
+            let (_socket, _) = listener.accept().await.unwrap();
+            let _ = wait.await;
+        });
 
-    #[allow(unused_variables)]
-    let a = Tunn::new(static_private, peer_static_public, None, None, 0, None);
+        let started = std::time::Instant::now();
+        let result = proxy(server, None, Duration::from_millis(200))
+            .connect(TARGET.parse().unwrap())
+            .await;
+        assert!(result.is_err());
+        assert!(started.elapsed() < TIMEOUT);
+        let _ = done.send(());
+    }
 
-    todo!()
+    #[test]
+    fn needs_a_server() {
+        let config = ProxyConfig::Socks5 {
+            servers: vec![],
+            username: None,
+            password: None,
+        };
+        assert!(NetworkProxy::new(&config, TIMEOUT).is_err());
+    }
 }
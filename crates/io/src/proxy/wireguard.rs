@@ -0,0 +1,648 @@
+//! A userspace WireGuard tunnel for [`NetworkProxy`](super::NetworkProxy).
+//!
+//! boringtun only encrypts and decrypts IP packets, so the TCP connections
+//! inside the tunnel are run by smoltcp. Every connection is handed out as a
+//! loopback [`TcpStream`] that's bridged to its smoltcp socket, so callers get
+//! the same kind of stream as from any other proxy.
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use boringtun::{
+    noise::{Tunn, TunnResult},
+    x25519::{PublicKey, StaticSecret},
+};
+use config::WireguardConfig;
+use smoltcp::{
+    iface::{Config, Interface, SocketHandle, SocketSet},
+    phy::{self, Device, DeviceCapabilities, Medium},
+    socket::tcp,
+    time::Instant,
+    wire::{HardwareAddress, IpCidr},
+};
+use std::{
+    collections::VecDeque,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    ops::RangeInclusive,
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream, UdpSocket},
+    sync::{
+        mpsc::{self, error::TryRecvError},
+        oneshot, Notify,
+    },
+};
+
+/// Largest packet inside the tunnel, the same as wg-quick's default
+const MTU: usize = 1420;
+/// Fits any datagram we receive and any packet boringtun writes
+const BUFFER_SIZE: usize = 1 << 16;
+/// How often boringtun wants its timers updated
+const TIMER_INTERVAL: Duration = Duration::from_millis(250);
+/// Send and receive buffer of every connection
+const SOCKET_BUFFER_SIZE: usize = 1 << 16;
+/// Chunks waiting between a connection and its loopback stream
+const CHANNEL_SIZE: usize = 16;
+/// Local ports of our connections inside the tunnel
+const EPHEMERAL_PORTS: RangeInclusive<u16> = 49152..=65535;
+
+/// Handle to the task running the tunnel, which stops once every handle is
+/// dropped
+#[derive(Debug)]
+pub struct WireguardTunnel {
+    connects: mpsc::UnboundedSender<Connect>,
+    /// Our address inside the tunnel
+    address: IpAddr,
+    /// How long connecting through the tunnel can take
+    timeout: Duration,
+}
+
+#[derive(Debug)]
+struct Connect {
+    addr: SocketAddr,
+    /// Our end of the loopback stream the caller gets
+    local: TcpStream,
+    established: oneshot::Sender<eyre::Result<()>>,
+}
+
+impl WireguardTunnel {
+    /// Start the tunnel, the handshake is done when the first connection is
+    /// made. Has to be called inside the tokio runtime.
+    pub fn new(config: &WireguardConfig, timeout: Duration) -> eyre::Result<Self> {
+        let private_key = StaticSecret::from(key("private_key", &config.private_key)?);
+        let peer_public_key = PublicKey::from(key("peer_public_key", &config.peer_public_key)?);
+        let preshared_key = config
+            .preshared_key
+            .as_deref()
+            .map(|preshared_key| key("preshared_key", preshared_key))
+            .transpose()?;
+        let tunn = Tunn::new(
+            private_key,
+            peer_public_key,
+            preshared_key,
+            config.persistent_keepalive,
+            0,
+            None,
+        )
+        .map_err(|err| eyre::eyre!("unable to set up the wireguard tunnel: {err}"))?;
+
+        let bind: SocketAddr = match config.endpoint {
+            SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+            SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+        };
+        let udp = std::net::UdpSocket::bind(bind)?;
+        udp.connect(config.endpoint)?;
+        udp.set_nonblocking(true)?;
+        let tunnel = Tunnel::new(tunn, UdpSocket::from_std(udp)?, config.address, timeout)?;
+
+        let (connects, receiver) = mpsc::unbounded_channel();
+        tokio::spawn(tunnel.run(receiver));
+        Ok(Self {
+            connects,
+            address: config.address,
+            timeout,
+        })
+    }
+
+    /// Open a TCP connection to `addr` through the tunnel
+    pub async fn connect(&self, addr: SocketAddr) -> eyre::Result<TcpStream> {
+        if addr.is_ipv4() != self.address.is_ipv4() {
+            return Err(eyre::eyre!(
+                "{addr} can't be reached from {} inside the wireguard tunnel",
+                self.address
+            ));
+        }
+        // the caller gets one end of a loopback connection, the tunnel task
+        // bridges the other one to the connection inside the tunnel
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+        let (stream, (local, _)) = tokio::try_join!(
+            TcpStream::connect(listener.local_addr()?),
+            listener.accept()
+        )?;
+
+        let (established, connected) = oneshot::channel();
+        self.connects
+            .send(Connect {
+                addr,
+                local,
+                established,
+            })
+            .map_err(|_| eyre::eyre!("the wireguard tunnel stopped"))?;
+        match tokio::time::timeout(self.timeout, connected).await {
+            Ok(Ok(Ok(()))) => Ok(stream),
+            Ok(Ok(Err(err))) => Err(err),
+            Ok(Err(_)) => Err(eyre::eyre!("the wireguard tunnel stopped")),
+            Err(_) => Err(eyre::eyre!(
+                "connecting to {addr} through wireguard timed out"
+            )),
+        }
+    }
+}
+
+/// Decode a key like `wg` prints them
+fn key(name: &str, key: &str) -> eyre::Result<[u8; 32]> {
+    let key = STANDARD
+        .decode(key.trim())
+        .map_err(|err| eyre::eyre!("proxy.{name} isn't base64: {err}"))?;
+    key.try_into()
+        .map_err(|_| eyre::eyre!("proxy.{name} has to be 32 bytes"))
+}
+
+/// Everything the tunnel task owns
+struct Tunnel {
+    tunn: Tunn,
+    /// Connected to the peer
+    udp: UdpSocket,
+    interface: Interface,
+    device: VirtualDevice,
+    sockets: SocketSet<'static>,
+    connections: Vec<Connection>,
+    /// Notified by the bridges whenever they moved data
+    wake: Arc<Notify>,
+    next_port: u16,
+    /// boringtun writes the packets it encrypted or decrypted here
+    buffer: Vec<u8>,
+    timeout: Duration,
+}
+
+impl Tunnel {
+    fn new(tunn: Tunn, udp: UdpSocket, address: IpAddr, timeout: Duration) -> eyre::Result<Self> {
+        let mut device = VirtualDevice::default();
+        let mut config = Config::new(HardwareAddress::Ip);
+        config.random_seed = rand::random();
+        let mut interface = Interface::new(config, &mut device, Instant::now());
+        let prefix_len = if address.is_ipv4() { 32 } else { 128 };
+        interface.update_ip_addrs(|addrs| {
+            let _ = addrs.push(IpCidr::new(address.into(), prefix_len));
+        });
+        // there's nobody on the link but the peer, so everything goes to it
+        let route = match address {
+            IpAddr::V4(address) => interface
+                .routes_mut()
+                .add_default_ipv4_route(address.into()),
+            IpAddr::V6(address) => interface
+                .routes_mut()
+                .add_default_ipv6_route(address.into()),
+        };
+        route.map_err(|_| eyre::eyre!("unable to route through the wireguard tunnel"))?;
+
+        Ok(Self {
+            tunn,
+            udp,
+            interface,
+            device,
+            sockets: SocketSet::new(Vec::new()),
+            connections: Vec::new(),
+            wake: Arc::new(Notify::new()),
+            next_port: rand::random::<u16>() % (EPHEMERAL_PORTS.len() as u16)
+                + EPHEMERAL_PORTS.start(),
+            buffer: vec![0; BUFFER_SIZE],
+            timeout,
+        })
+    }
+
+    async fn run(mut self, mut connects: mpsc::UnboundedReceiver<Connect>) {
+        let mut datagram = vec![0; BUFFER_SIZE];
+        let mut timers = tokio::time::interval(TIMER_INTERVAL);
+        let wake = self.wake.clone();
+        loop {
+            self.poll();
+            let delay = self.poll_delay();
+            tokio::select! {
+                connect = connects.recv() => match connect {
+                    Some(connect) => self.open(connect),
+                    // every handle to the tunnel is gone
+                    None => return,
+                },
+                received = self.udp.recv(&mut datagram) => {
+                    // errors are most likely ICMP from the endpoint, the
+                    // handshake is retried by the timers
+                    if let Ok(len) = received {
+                        self.receive(&datagram[..len]);
+                    }
+                }
+                _ = timers.tick() => self.update_timers(),
+                _ = wake.notified() => {}
+                _ = tokio::time::sleep(delay) => {}
+            }
+        }
+    }
+
+    /// Let smoltcp handle what came in, move data between the connections and
+    /// their loopback streams, and send what smoltcp wants to send
+    fn poll(&mut self) {
+        self.interface
+            .poll(Instant::now(), &mut self.device, &mut self.sockets);
+        self.bridge();
+        self.interface
+            .poll(Instant::now(), &mut self.device, &mut self.sockets);
+        self.send_queued();
+    }
+
+    /// How long until smoltcp has to be polled again, if nothing comes in
+    fn poll_delay(&mut self) -> Duration {
+        self.interface
+            .poll_delay(Instant::now(), &self.sockets)
+            .map(Duration::from)
+            .unwrap_or(TIMER_INTERVAL)
+    }
+
+    /// Decrypt a datagram from the peer
+    fn receive(&mut self, datagram: &[u8]) {
+        match self.tunn.decapsulate(None, datagram, &mut self.buffer) {
+            TunnResult::WriteToNetwork(packet) => {
+                let _ = self.udp.try_send(packet);
+                // packets queued up during the handshake are sent now
+                while let TunnResult::WriteToNetwork(packet) =
+                    self.tunn.decapsulate(None, &[], &mut self.buffer)
+                {
+                    let _ = self.udp.try_send(packet);
+                }
+            }
+            TunnResult::WriteToTunnelV4(packet, _) | TunnResult::WriteToTunnelV6(packet, _) => {
+                self.device.received.push_back(packet.to_vec());
+            }
+            // keepalives, and anything that isn't from our peer
+            TunnResult::Done | TunnResult::Err(_) => {}
+        }
+    }
+
+    /// Handshakes, keepalives and rekeying
+    fn update_timers(&mut self) {
+        if let TunnResult::WriteToNetwork(packet) = self.tunn.update_timers(&mut self.buffer) {
+            let _ = self.udp.try_send(packet);
+        }
+    }
+
+    /// Encrypt and send what smoltcp sent. Packets the socket can't take right
+    /// now are dropped, TCP sends them again.
+    fn send_queued(&mut self) {
+        while let Some(packet) = self.device.sent.pop_front() {
+            if let TunnResult::WriteToNetwork(datagram) =
+                self.tunn.encapsulate(&packet, &mut self.buffer)
+            {
+                let _ = self.udp.try_send(datagram);
+            }
+        }
+    }
+
+    fn open(&mut self, connect: Connect) {
+        let mut socket = tcp::Socket::new(
+            tcp::SocketBuffer::new(vec![0; SOCKET_BUFFER_SIZE]),
+            tcp::SocketBuffer::new(vec![0; SOCKET_BUFFER_SIZE]),
+        );
+        // gives up on peers that stop answering, like the kernel would
+        socket.set_timeout(Some(self.timeout.into()));
+        let port = self.next_port();
+        if let Err(err) = socket.connect(self.interface.context(), connect.addr, port) {
+            let _ = connect.established.send(Err(eyre::eyre!(
+                "unable to connect to {} through wireguard: {err}",
+                connect.addr
+            )));
+            return;
+        }
+        let (from_local, to_local) = bridge(connect.local, self.wake.clone());
+        self.connections.push(Connection {
+            addr: connect.addr,
+            handle: self.sockets.add(socket),
+            established: Some(connect.established),
+            from_local: Some(from_local),
+            unsent: Vec::new(),
+            to_local: Some(to_local),
+        });
+    }
+
+    fn next_port(&mut self) -> u16 {
+        let port = self.next_port;
+        self.next_port = if port == *EPHEMERAL_PORTS.end() {
+            *EPHEMERAL_PORTS.start()
+        } else {
+            port + 1
+        };
+        port
+    }
+
+    /// Move data between every connection and its loopback stream, and forget
+    /// the connections that are done
+    fn bridge(&mut self) {
+        let sockets = &mut self.sockets;
+        self.connections.retain_mut(|connection| {
+            let open = connection.bridge(sockets.get_mut::<tcp::Socket>(connection.handle));
+            if !open {
+                sockets.remove(connection.handle);
+            }
+            open
+        });
+    }
+}
+
+/// A connection inside the tunnel and the loopback stream it's bridged to
+struct Connection {
+    addr: SocketAddr,
+    handle: SocketHandle,
+    /// Told once the connection is established or failed
+    established: Option<oneshot::Sender<eyre::Result<()>>>,
+    /// Data from the loopback stream, `None` once it's closed
+    from_local: Option<mpsc::Receiver<Vec<u8>>>,
+    /// What didn't fit in the socket's send buffer yet
+    unsent: Vec<u8>,
+    /// Data for the loopback stream, `None` once the peer closed its side
+    to_local: Option<mpsc::Sender<Vec<u8>>>,
+}
+
+impl Connection {
+    /// Move what we can between the socket and the loopback stream, `false`
+    /// once the connection is done
+    fn bridge(&mut self, socket: &mut tcp::Socket) -> bool {
+        if let Some(established) = self.established.take() {
+            if established.is_closed() {
+                // the caller gave up on connecting, the connection is
+                // forgotten once the reset went out on the next poll
+                socket.abort();
+                return true;
+            }
+            match socket.state() {
+                tcp::State::Established => {
+                    let _ = established.send(Ok(()));
+                }
+                tcp::State::Closed => {
+                    let _ = established.send(Err(eyre::eyre!(
+                        "{} refused the connection through wireguard",
+                        self.addr
+                    )));
+                    return false;
+                }
+                _ => {
+                    self.established = Some(established);
+                    return true;
+                }
+            }
+        }
+
+        // from the peer to the loopback stream
+        if let Some(to_local) = &self.to_local {
+            while socket.can_recv() {
+                let Ok(permit) = to_local.try_reserve() else {
+                    break;
+                };
+                let Ok(data) = socket.recv(|data| (data.len(), data.to_vec())) else {
+                    break;
+                };
+                permit.send(data);
+            }
+            if to_local.is_closed() {
+                // nothing reads the loopback stream anymore, the connection is
+                // forgotten once the reset went out on the next poll
+                socket.abort();
+                self.to_local = None;
+                return true;
+            } else if !socket.may_recv() && !socket.can_recv() {
+                // the peer closed its side and we handed over everything,
+                // dropping the sender closes the loopback stream's
+                self.to_local = None;
+            }
+        }
+
+        // from the loopback stream to the peer
+        loop {
+            if !self.unsent.is_empty() {
+                match socket.send_slice(&self.unsent) {
+                    Ok(sent) => {
+                        self.unsent.drain(..sent);
+                    }
+                    Err(_) => break,
+                }
+                if !self.unsent.is_empty() {
+                    break;
+                }
+            }
+            let Some(from_local) = &mut self.from_local else {
+                break;
+            };
+            if !socket.can_send() {
+                break;
+            }
+            match from_local.try_recv() {
+                Ok(data) => self.unsent = data,
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => {
+                    // the caller is done sending
+                    socket.close();
+                    self.from_local = None;
+                    break;
+                }
+            }
+        }
+
+        !matches!(socket.state(), tcp::State::Closed | tcp::State::TimeWait) || socket.can_recv()
+    }
+}
+
+/// Copy between the loopback stream and the channels the tunnel task reads and
+/// writes, waking it whenever something moved. The copies stop once the
+/// tunnel task drops its ends.
+fn bridge(local: TcpStream, wake: Arc<Notify>) -> (mpsc::Receiver<Vec<u8>>, mpsc::Sender<Vec<u8>>) {
+    let (mut reader, mut writer) = local.into_split();
+    let (to_tunnel, from_local) = mpsc::channel(CHANNEL_SIZE);
+    let (to_local, mut from_tunnel) = mpsc::channel::<Vec<u8>>(CHANNEL_SIZE);
+
+    let reader_wake = wake.clone();
+    tokio::spawn(async move {
+        let mut buffer = vec![0; MTU];
+        loop {
+            let read = match reader.read(&mut buffer).await {
+                Ok(0) | Err(_) => break,
+                Ok(read) => read,
+            };
+            if to_tunnel.send(buffer[..read].to_vec()).await.is_err() {
+                break;
+            }
+            reader_wake.notify_one();
+        }
+        // the tunnel task sees the channel close and sends a FIN
+        drop(to_tunnel);
+        reader_wake.notify_one();
+    });
+    tokio::spawn(async move {
+        while let Some(data) = from_tunnel.recv().await {
+            if writer.write_all(&data).await.is_err() {
+                break;
+            }
+            // there's room in the channel again
+            wake.notify_one();
+        }
+        let _ = writer.shutdown().await;
+        drop(from_tunnel);
+        wake.notify_one();
+    });
+
+    (from_local, to_local)
+}
+
+/// smoltcp's end of the tunnel, packets are queued in both directions and
+/// moved by [`Tunnel`]
+#[derive(Default)]
+struct VirtualDevice {
+    /// Decrypted packets from the peer
+    received: VecDeque<Vec<u8>>,
+    /// Packets smoltcp sent, to be encrypted
+    sent: VecDeque<Vec<u8>>,
+}
+
+impl Device for VirtualDevice {
+    type RxToken<'a>
+        = RxToken
+    where
+        Self: 'a;
+    type TxToken<'a>
+        = TxToken<'a>
+    where
+        Self: 'a;
+
+    fn receive(&mut self, _timestamp: Instant) -> Option<(RxToken, TxToken<'_>)> {
+        let packet = self.received.pop_front()?;
+        Some((RxToken(packet), TxToken(&mut self.sent)))
+    }
+
+    fn transmit(&mut self, _timestamp: Instant) -> Option<TxToken<'_>> {
+        Some(TxToken(&mut self.sent))
+    }
+
+    fn capabilities(&self) -> DeviceCapabilities {
+        let mut capabilities = DeviceCapabilities::default();
+        capabilities.medium = Medium::Ip;
+        capabilities.max_transmission_unit = MTU;
+        capabilities
+    }
+}
+
+struct RxToken(Vec<u8>);
+
+impl phy::RxToken for RxToken {
+    fn consume<R, F>(mut self, f: F) -> R
+    where
+        F: FnOnce(&mut [u8]) -> R,
+    {
+        f(&mut self.0)
+    }
+}
+
+struct TxToken<'a>(&'a mut VecDeque<Vec<u8>>);
+
+impl phy::TxToken for TxToken<'_> {
+    fn consume<R, F>(self, len: usize, f: F) -> R
+    where
+        F: FnOnce(&mut [u8]) -> R,
+    {
+        let mut packet = vec![0; len];
+        let result = f(&mut packet);
+        self.0.push_back(packet);
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+    const OUR_ADDRESS: Ipv4Addr = Ipv4Addr::new(10, 7, 0, 1);
+    const PEER_ADDRESS: Ipv4Addr = Ipv4Addr::new(10, 7, 0, 2);
+    const ECHO_PORT: u16 = 25565;
+
+    /// A peer at [`PEER_ADDRESS`] that echoes one connection to
+    /// [`ECHO_PORT`], made of the same parts as our end of the tunnel
+    async fn echo_peer(private_key: StaticSecret, our_public_key: PublicKey) -> SocketAddr {
+        let udp = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let endpoint = udp.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut datagram = vec![0; BUFFER_SIZE];
+            // where the tunnel is is only known once it sends the handshake
+            let (len, from) = udp.recv_from(&mut datagram).await.unwrap();
+            udp.connect(from).await.unwrap();
+            let tunn = Tunn::new(private_key, our_public_key, None, None, 1, None).unwrap();
+            let mut peer = Tunnel::new(tunn, udp, PEER_ADDRESS.into(), TIMEOUT).unwrap();
+            let mut echo = tcp::Socket::new(
+                tcp::SocketBuffer::new(vec![0; SOCKET_BUFFER_SIZE]),
+                tcp::SocketBuffer::new(vec![0; SOCKET_BUFFER_SIZE]),
+            );
+            echo.listen(ECHO_PORT).unwrap();
+            let echo = peer.sockets.add(echo);
+            peer.receive(&datagram[..len]);
+
+            loop {
+                peer.poll();
+                let socket = peer.sockets.get_mut::<tcp::Socket>(echo);
+                if socket.can_recv() && socket.can_send() {
+                    let data = socket.recv(|data| (data.len(), data.to_vec())).unwrap();
+                    socket.send_slice(&data).unwrap();
+                    peer.poll();
+                }
+                let delay = peer.poll_delay();
+                if let Ok(Ok(len)) = tokio::time::timeout(delay, peer.udp.recv(&mut datagram)).await
+                {
+                    peer.receive(&datagram[..len]);
+                }
+            }
+        });
+        endpoint
+    }
+
+    /// Our end of a tunnel to a new [`echo_peer`]
+    async fn tunnel() -> WireguardTunnel {
+        let private_key = StaticSecret::from([1; 32]);
+        let peer_private_key = StaticSecret::from([2; 32]);
+        let config = WireguardConfig {
+            private_key: STANDARD.encode(private_key.to_bytes()),
+            peer_public_key: STANDARD.encode(PublicKey::from(&peer_private_key).as_bytes()),
+            preshared_key: None,
+            endpoint: echo_peer(peer_private_key, PublicKey::from(&private_key)).await,
+            address: OUR_ADDRESS.into(),
+            persistent_keepalive: None,
+        };
+        WireguardTunnel::new(&config, TIMEOUT).unwrap()
+    }
+
+    #[tokio::test]
+    async fn connects_through_the_tunnel() {
+        let mut stream = tunnel()
+            .await
+            .connect((PEER_ADDRESS, ECHO_PORT).into())
+            .await
+            .unwrap();
+        stream.write_all(b"hello").await.unwrap();
+        let mut response = [0; 5];
+        stream.read_exact(&mut response).await.unwrap();
+        assert_eq!(&response, b"hello");
+    }
+
+    #[tokio::test]
+    async fn closed_ports_refuse() {
+        let result = tunnel()
+            .await
+            .connect((PEER_ADDRESS, ECHO_PORT + 1).into())
+            .await;
+        let err = result.unwrap_err().to_string();
+        assert!(err.contains("refused"), "{err}");
+    }
+
+    #[tokio::test]
+    async fn needs_an_address_of_the_same_family() {
+        let result = tunnel()
+            .await
+            .connect("[2001:db8::1]:25565".parse().unwrap())
+            .await;
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn keys_have_to_be_32_bytes() {
+        assert_eq!(
+            key("private_key", &STANDARD.encode([7; 32])).unwrap(),
+            [7; 32]
+        );
+        assert!(key("private_key", &STANDARD.encode([7; 31])).is_err());
+        assert!(key("private_key", "not base64!").is_err());
+    }
+}
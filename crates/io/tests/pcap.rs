@@ -0,0 +1,140 @@
+//! Runs the pcap backend over `data/scan.pcap`, the same packets snowstorm's
+//! `scanner.pcap_output` records. It has four servers on port 25565:
+//!
+//! - 203.0.113.1 answers the status request in one segment
+//! - 203.0.113.2 splits its status response over two segments
+//! - 203.0.113.3 answers a legacy ping with a 1.6 kick packet
+//! - 203.0.113.4 resets the connection, nothing's listening
+
+use common::net::rate_limit::RateLimiter;
+use database::{player::PlayerInfo, server::PingResult};
+use io::{pcap::PcapScanner, Io, ScannerState};
+use std::{
+    net::SocketAddr,
+    path::Path,
+    sync::{
+        mpsc::{channel, Receiver},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::sync::Mutex;
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+const LEGACY_SERVER: &str = "203.0.113.3:25565";
+
+fn data(file: &str) -> String {
+    format!("{}/tests/data/{file}", env!("CARGO_MANIFEST_DIR"))
+}
+
+/// Every result sent before `count` came in or the timeout ran out, and
+/// anything that came in shortly after
+fn collect(
+    results: Receiver<(PingResult, Vec<PlayerInfo>)>,
+    count: usize,
+) -> Vec<(PingResult, Vec<PlayerInfo>)> {
+    let mut collected = Vec::new();
+    while collected.len() < count {
+        let Ok(result) = results.recv_timeout(TIMEOUT) else {
+            break;
+        };
+        collected.push(result);
+    }
+    while let Ok(result) = results.recv_timeout(Duration::from_millis(500)) {
+        collected.push(result);
+    }
+    collected
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn replays_a_capture() {
+    // the receive workers are configured from it, the defaults are enough
+    std::env::set_var("SNOWSTORM_CONFIG", data("Snowstorm.toml"));
+
+    let (sender, results) = channel();
+    let mut scanner = PcapScanner::new(
+        Path::new(&data("scan.pcap")),
+        None,
+        Arc::new(Mutex::new(ScannerState::default())),
+        sender,
+        RateLimiter::unlimited(),
+    )
+    .unwrap();
+    let legacy_server: SocketAddr = LEGACY_SERVER.parse().unwrap();
+    assert_eq!(scanner.servers.len(), 4);
+    for server in scanner.servers.clone() {
+        if server == legacy_server {
+            scanner.legacy_ping(server).await.unwrap();
+        } else {
+            scanner.ping(server).await.unwrap();
+        }
+    }
+
+    let mut results = tokio::task::spawn_blocking(move || collect(results, 3))
+        .await
+        .unwrap();
+    results.sort_by_key(|(ping_result, _)| ping_result.ip());
+    assert_eq!(results.len(), 3, "{results:#?}");
+
+    let (single, players) = &results[0];
+    assert_eq!(
+        single.ip(),
+        "203.0.113.1".parse::<std::net::IpAddr>().unwrap()
+    );
+    assert_eq!(single.port(), 25565);
+    assert_eq!(single.version_name.as_deref(), Some("1.20.4"));
+    assert_eq!(single.version_protocol, Some(765));
+    assert_eq!(single.online_players, Some(3));
+    assert_eq!(single.max_players, Some(20));
+    assert!(single
+        .description
+        .as_deref()
+        .is_some_and(|description| description.contains("A Minecraft Server")));
+    assert!(players.is_empty());
+
+    let (split, _) = &results[1];
+    assert_eq!(
+        split.ip(),
+        "203.0.113.2".parse::<std::net::IpAddr>().unwrap()
+    );
+    assert_eq!(split.version_name.as_deref(), Some("Paper 1.20.4"));
+    assert_eq!(split.online_players, Some(12));
+    assert_eq!(split.max_players, Some(100));
+    assert!(split
+        .description
+        .as_deref()
+        .is_some_and(|description| description.contains("split over two segments")));
+
+    let (legacy, players) = &results[2];
+    assert_eq!(legacy.ip(), legacy_server.ip());
+    assert_eq!(legacy.port(), 25565);
+    assert_eq!(legacy.version_name.as_deref(), Some("1.6.4"));
+    assert_eq!(legacy.version_protocol, Some(78));
+    assert_eq!(legacy.description.as_deref(), Some("A Legacy Server"));
+    assert_eq!(legacy.online_players, Some(5));
+    assert_eq!(legacy.max_players, Some(10));
+    assert!(players.is_empty());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn publishes_stats_without_packets() {
+    std::env::set_var("SNOWSTORM_CONFIG", data("Snowstorm.toml"));
+
+    let (sender, _results) = channel();
+    let state = Arc::new(Mutex::new(ScannerState::default()));
+    // nothing is pinged, so the replay never sends the classifier a packet
+    let _scanner = PcapScanner::new(
+        Path::new(&data("scan.pcap")),
+        None,
+        state.clone(),
+        sender,
+        RateLimiter::unlimited(),
+    )
+    .unwrap();
+
+    // the stats are published every second
+    tokio::time::sleep(Duration::from_millis(1500)).await;
+    let receive = state.lock().await.receive.clone();
+    assert!(!receive.queue_depths.is_empty(), "{receive:?}");
+    assert_eq!(receive.received, 0);
+}
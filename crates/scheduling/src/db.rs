@@ -21,11 +21,11 @@ pub async fn get_ports(pool: &PgPool) -> Result<DashMap<u16, usize>, sqlx::Error
 
 pub async fn get_ips(pool: &PgPool) -> Result<DashMap<Ipv4Addr, usize>, sqlx::Error> {
     let map = DashMap::new();
-    let ips: Vec<IpWrapper> = sqlx::query_as("SELECT ip FROM servers LIMIT 10000000")
-        .fetch_all(pool)
-        .await?;
-    for ip in ips {
-        let ip = ip.ip();
+    let ips: Vec<IpWrapper> =
+        sqlx::query_as("SELECT ip FROM servers WHERE family(ip) = 4 LIMIT 10000000")
+            .fetch_all(pool)
+            .await?;
+    for ip in ips.iter().filter_map(IpWrapper::ipv4) {
         if let Some(mut value) = map.get_mut(&ip) {
             *value += 1;
         } else {
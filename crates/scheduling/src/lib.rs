@@ -1,24 +1,30 @@
 #![feature(map_many_mut)]
 
 use asn::{get_slash24, get_slash24s_map_key};
-use common::network_range::SocketAddrV4Range;
+use common::{
+    exclude,
+    network_range::{SocketAddrRange, SocketAddrV4Range},
+};
 use dashmap::DashMap;
+use io::ScannerState;
 use prelude::*;
 use rand::{
     distributions::{Distribution, WeightedIndex},
     Rng,
 };
 use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
 use sqlx::PgPool;
 use std::{
     hash::Hash,
-    net::{Ipv4Addr, SocketAddrV4},
+    net::{IpAddr, Ipv4Addr, SocketAddrV4},
     sync::{
         mpsc::{Receiver, Sender},
         Arc,
     },
+    time::Duration,
 };
-use tokio::runtime::Runtime;
+use tokio::{runtime::Runtime, sync::Mutex};
 
 mod db;
 
@@ -29,6 +35,11 @@ pub mod prelude;
 /// (usize::MAX as f64).powf(0.5) as u64
 const DEFAULT_WEIGHT: u64 = 0x100000000;
 
+/// How long to wait before picking again once every mode came up empty,
+/// doubled every time up to [`MAX_EMPTY_BACKOFF`]
+const EMPTY_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_EMPTY_BACKOFF: Duration = Duration::from_secs(60);
+
 #[derive(Debug)]
 pub struct ModePicker {
     pub modes: DashMap<ScanningMode, u64>,
@@ -108,7 +119,9 @@ impl Default for ModePicker {
     }
 }
 
-#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, enum_utils::IterVariants)]
+#[derive(
+    Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize, enum_utils::IterVariants,
+)]
 pub enum ScanningMode {
     /// /0 on 25565
     /// TODO: run on ranges even without Minecraft servers
@@ -130,6 +143,8 @@ pub enum ScanningMode {
     AllPortSingleMinecraftRange,
     /// /24 on 1024-65535
     AllPortSingleRange,
+    /// Every address in `scanner.ipv6_hitlist`
+    Ipv6Hitlist,
 }
 
 impl ScanningMode {
@@ -137,10 +152,13 @@ impl ScanningMode {
         Self::iter().collect()
     }
     pub fn is_enabled(&self) -> bool {
-        !matches!(
-            self,
-            Self::OneRandomPortAllAddress | Self::AllPortMinecraftRange | Self::AllPortSingleRange
-        )
+        match self {
+            Self::OneRandomPortAllAddress
+            | Self::AllPortMinecraftRange
+            | Self::AllPortSingleRange => false,
+            Self::Ipv6Hitlist => config::get().scanner.ipv6_hitlist.is_some(),
+            _ => true,
+        }
     }
 }
 
@@ -156,16 +174,36 @@ impl PortWrapper {
 
 #[derive(PartialEq, Eq, Hash, sqlx::FromRow)]
 struct IpWrapper {
-    ip: i32,
+    ip: IpAddr,
 }
 impl IpWrapper {
-    pub fn ip(&self) -> Ipv4Addr {
-        Ipv4Addr::from(self.ip as u32)
+    /// The scanning modes that use known addresses are IPv4 only
+    pub fn ipv4(&self) -> Option<Ipv4Addr> {
+        match self.ip {
+            IpAddr::V4(ip) => Some(ip),
+            IpAddr::V6(_) => None,
+        }
     }
 }
 
 impl ScanningMode {
-    pub async fn get_addresses(
+    pub async fn get_addresses(&self, pool: &PgPool) -> eyre::Result<Vec<SocketAddrRange>> {
+        if let ScanningMode::Ipv6Hitlist = self {
+            let Some(path) = &config::get().scanner.ipv6_hitlist else {
+                return Ok(vec![]);
+            };
+            let ranges = common::hitlist::load(path, 25565)?;
+            return Ok(ranges.into_iter().map(SocketAddrRange::V6).collect());
+        }
+        Ok(self
+            .get_ipv4_addresses(pool)
+            .await?
+            .into_iter()
+            .map(SocketAddrRange::V4)
+            .collect())
+    }
+
+    async fn get_ipv4_addresses(
         &self,
         pool: &PgPool,
     ) -> Result<Vec<SocketAddrV4Range>, sqlx::Error> {
@@ -282,27 +320,58 @@ impl ScanningMode {
                     (range, constants::MIN_PORT, constants::MAX_PORT).into()
                 ])
             }
+            ScanningMode::Ipv6Hitlist => Ok(vec![]),
         }
     }
 }
 
 pub fn start_scheduler_queue(
-    sender: Sender<(ScanningMode, Vec<SocketAddrV4Range>)>,
+    sender: Sender<(ScanningMode, Vec<SocketAddrRange>)>,
     receiver: Receiver<Option<(ScanningMode, u64)>>,
     modes: Arc<parking_lot::Mutex<ModePicker>>,
+    state: Arc<Mutex<ScannerState>>,
     pool: PgPool,
 ) {
     std::thread::spawn(move || {
         Runtime::new().unwrap().block_on(async move {
             while let Ok(last_scan_results) = receiver.recv() {
-                let new_mode = {
-                    let mut modes_lock = modes.lock();
-                    if let Some((mode, count)) = last_scan_results {
-                        modes_lock.update(mode, count);
+                if let Some((mode, count)) = last_scan_results {
+                    modes.lock().update(mode, count);
+                }
+                let mut empty_picks = 0;
+                let mut backoff = EMPTY_BACKOFF;
+                let (new_mode, addresses) = loop {
+                    let new_mode = modes.lock().pick_random();
+                    // a broken hitlist or database hiccup shouldn't stop the
+                    // scanner, the mode is skipped like an empty one
+                    let addresses = match new_mode.get_addresses(&pool).await {
+                        Ok(addresses) => addresses,
+                        Err(err) => {
+                            eprintln!("unable to get the addresses of {new_mode:?}: {err}");
+                            Vec::new()
+                        }
+                    };
+                    let (addresses, skipped) = exclude::subtract(addresses);
+                    println!("excluded {skipped} addresses from {new_mode:?}");
+                    state
+                        .lock()
+                        .await
+                        .add_excluded(format!("{new_mode:?}"), skipped);
+                    if !addresses.is_empty() {
+                        break (new_mode, addresses);
+                    }
+                    // nothing to scan, or everything it would scan is excluded
+                    modes.lock().update(new_mode, 0);
+                    empty_picks += 1;
+                    if empty_picks >= modes.lock().modes.len() {
+                        eprintln!(
+                            "the last {empty_picks} modes had nothing to scan, check the exclude list and the database. Picking again in {backoff:?}"
+                        );
+                        tokio::time::sleep(backoff).await;
+                        backoff = (backoff * 2).min(MAX_EMPTY_BACKOFF);
+                        empty_picks = 0;
                     }
-                    modes_lock.pick_random()
                 };
-                let addresses = new_mode.get_addresses(&pool).await.unwrap();
                 sender.send((new_mode, addresses)).unwrap();
             }
         });
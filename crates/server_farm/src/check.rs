@@ -0,0 +1,158 @@
+//! Checking what a scanner makes of the farm, shared by `main.rs` and the
+//! tests
+
+use crate::{Behavior, FakeServer};
+use database::{player::PlayerInfo, server::PingResult};
+use io::Io;
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{mpsc::Receiver, Arc},
+    time::Duration,
+};
+
+/// How long we keep listening for rows after every expected one came in, to
+/// catch rows that shouldn't be there
+pub const GRACE_PERIOD: Duration = Duration::from_secs(1);
+
+pub type Row = (PingResult, Vec<PlayerInfo>);
+
+/// Ping every server the way it has to be pinged
+pub async fn ping_all(scanner: &mut impl Io, servers: &[Arc<FakeServer>]) -> eyre::Result<()> {
+    for server in servers {
+        if server.legacy() {
+            scanner.legacy_ping(server.addr).await?;
+        } else {
+            scanner.ping(server.addr).await?;
+        }
+    }
+    scanner.flush();
+    Ok(())
+}
+
+/// How many servers should produce a row
+pub fn expected_rows(servers: &[Arc<FakeServer>]) -> usize {
+    servers
+        .iter()
+        .filter(|server| server.expected().is_some())
+        .count()
+}
+
+/// Wait until we got `expected` rows and nothing came in for a bit, or
+/// `timeout` passed without a row
+pub fn collect(receiver: Receiver<Row>, expected: usize, timeout: Duration) -> Vec<Row> {
+    let mut rows = Vec::with_capacity(expected);
+    loop {
+        let wait = if rows.len() >= expected {
+            GRACE_PERIOD
+        } else {
+            timeout
+        };
+        match receiver.recv_timeout(wait) {
+            Ok(row) => rows.push(row),
+            Err(_) => return rows,
+        }
+    }
+}
+
+/// Every way the rows differ from what the farm sent
+pub fn check(servers: &[Arc<FakeServer>], rows: Vec<Row>, check_accounts: bool) -> Vec<String> {
+    let mut rows_by_addr: HashMap<SocketAddr, Vec<Row>> = HashMap::new();
+    for row in rows {
+        let addr = SocketAddr::new(row.0.ip(), row.0.port());
+        rows_by_addr.entry(addr).or_default().push(row);
+    }
+
+    let mut failures = Vec::new();
+    for server in servers {
+        let rows = rows_by_addr.remove(&server.addr).unwrap_or_default();
+        let kind = match &server.behavior {
+            Behavior::Modern(_) => "modern server".to_string(),
+            Behavior::Legacy(_) => "legacy server".to_string(),
+            Behavior::Malformed(malformed) => format!("{malformed:?} server"),
+        };
+        match (server.expected(), rows.as_slice()) {
+            (None, []) => {}
+            (None, rows) => failures.push(format!(
+                "{} ({kind}) produced {} rows",
+                server.addr,
+                rows.len()
+            )),
+            (Some(_), []) => failures.push(format!("{} ({kind}) produced no row", server.addr)),
+            (Some(expected), [row]) => {
+                if let Err(err) = compare(&expected, row, check_accounts) {
+                    failures.push(format!("{} ({kind}): {err}", server.addr));
+                }
+            }
+            (Some(_), rows) => failures.push(format!(
+                "{} ({kind}) produced {} rows instead of one",
+                server.addr,
+                rows.len()
+            )),
+        }
+    }
+    for (addr, rows) in rows_by_addr {
+        failures.push(format!(
+            "{addr} isn't in the farm but produced {} rows",
+            rows.len()
+        ));
+    }
+    failures
+}
+
+fn compare(expected: &Row, row: &Row, check_accounts: bool) -> Result<(), String> {
+    let ((expected, expected_players), (ping_result, players)) = (expected, row);
+    let fields = |ping_result: &PingResult| {
+        (
+            ping_result.version_name.clone(),
+            ping_result.version_protocol,
+            ping_result.online_players,
+            ping_result.max_players,
+            ping_result.description.as_deref().map(strip_formatting),
+            ping_result.enforces_secure_chat,
+        )
+    };
+    if fields(expected) != fields(ping_result) {
+        return Err(format!(
+            "expected {:?}, got {:?}",
+            fields(expected),
+            fields(ping_result)
+        ));
+    }
+
+    let player_keys = |players: &[PlayerInfo]| {
+        players
+            .iter()
+            .map(|player| {
+                (
+                    player.uuid,
+                    player.username.clone(),
+                    player.java_account.filter(|_| check_accounts),
+                )
+            })
+            .collect::<Vec<_>>()
+    };
+    if player_keys(expected_players) != player_keys(players) {
+        return Err(format!(
+            "expected players {:?}, got {:?}",
+            player_keys(expected_players),
+            player_keys(players)
+        ));
+    }
+    Ok(())
+}
+
+/// Formatting codes depend on how the description was turned into text, so
+/// they aren't compared
+fn strip_formatting(text: &str) -> String {
+    let mut stripped = String::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(char) = chars.next() {
+        if char == '§' {
+            chars.next();
+        } else {
+            stripped.push(char);
+        }
+    }
+    stripped
+}
@@ -0,0 +1,561 @@
+//! A farm of fake Minecraft servers to scan end to end, see `main.rs`.
+//!
+//! Every server listens on its own loopback address counting up from
+//! [`FIRST_ADDRESS`], so thousands of them fit on one port without a network.
+//! They're deliberately varied: modern and legacy servers, all kinds of MOTDs
+//! and player samples, and servers that answer with garbage.
+
+pub mod check;
+
+use ::io::legacy;
+use database::{player::PlayerInfo, server::PingResult};
+use serde_json::json;
+use std::{
+    collections::HashMap,
+    io,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    task::JoinHandle,
+};
+use uuid::Uuid;
+
+/// Address of the first server, the rest count up from here
+pub const FIRST_ADDRESS: Ipv4Addr = Ipv4Addr::new(127, 1, 0, 1);
+/// How long a connection may stay open, scanners that never close their
+/// connections don't pile them up
+const CONNECTION_LIFETIME: Duration = Duration::from_secs(30);
+/// Largest packet we accept, the same as the real server
+const MAX_PACKET_LENGTH: usize = 2_097_151;
+const LEGACY_PING_ID: u8 = 0xfe;
+
+#[derive(Debug, Clone)]
+pub struct FakeServer {
+    pub addr: SocketAddr,
+    pub behavior: Behavior,
+}
+
+#[derive(Debug, Clone)]
+pub enum Behavior {
+    /// A 1.7+ server answering the server list ping
+    Modern(Status),
+    /// A pre-1.7 server, which only understands the legacy ping
+    Legacy(LegacyStatus),
+    /// Answers with something broken, which shouldn't produce a row
+    Malformed(Malformed),
+}
+
+#[derive(Debug, Clone)]
+pub struct Status {
+    pub version_name: String,
+    pub protocol: i32,
+    pub online: i32,
+    pub max: i32,
+    /// Any chat component
+    pub description: serde_json::Value,
+    /// What the description says without formatting
+    pub description_text: String,
+    pub sample: Vec<SamplePlayer>,
+    pub enforces_secure_chat: Option<bool>,
+    /// Length of the favicon, big ones make the response span many segments
+    pub favicon_length: usize,
+}
+
+#[derive(Debug, Clone)]
+pub struct SamplePlayer {
+    pub name: String,
+    /// Not always a valid uuid, servers use the sample for text too
+    pub id: String,
+    /// The name mowojang has for the uuid, which may differ from the sample
+    pub account: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct LegacyStatus {
+    /// Protocol and version name, only sent by 1.4+ servers
+    pub version: Option<(i32, String)>,
+    pub motd: String,
+    pub online: i32,
+    pub max: i32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Malformed {
+    /// Closes the connection without answering
+    Close,
+    /// Never answers
+    Silent,
+    /// Answers with random bytes
+    Garbage,
+    /// A status response that's cut off
+    Truncated,
+    /// A status response that isn't json
+    InvalidJson,
+    /// A kick packet with the wrong number of fields
+    InvalidLegacy,
+}
+
+const MALFORMED: [Malformed; 6] = [
+    Malformed::Close,
+    Malformed::Silent,
+    Malformed::Garbage,
+    Malformed::Truncated,
+    Malformed::InvalidJson,
+    Malformed::InvalidLegacy,
+];
+
+impl FakeServer {
+    /// The `index`th server of the farm. Out of every 16 servers 10 are modern,
+    /// 3 legacy and 3 malformed.
+    pub fn generate(index: usize, addr: SocketAddr) -> Self {
+        let behavior = match index % 16 {
+            0..=9 => Behavior::Modern(Status::generate(index)),
+            10..=12 => Behavior::Legacy(LegacyStatus::generate(index)),
+            kind => Behavior::Malformed(MALFORMED[(index / 16 * 3 + kind - 13) % MALFORMED.len()]),
+        };
+        Self { addr, behavior }
+    }
+
+    /// Whether the server has to be pinged with a legacy ping
+    pub fn legacy(&self) -> bool {
+        matches!(
+            self.behavior,
+            Behavior::Legacy(_) | Behavior::Malformed(Malformed::InvalidLegacy)
+        )
+    }
+
+    /// The row scanning this server should produce, `None` if it shouldn't
+    /// produce one
+    pub fn expected(&self) -> Option<(PingResult, Vec<PlayerInfo>)> {
+        let mut ping_result = PingResult::none(self.addr.ip(), self.addr.port());
+        match &self.behavior {
+            Behavior::Modern(status) => {
+                ping_result.version_name = Some(status.version_name.clone());
+                ping_result.version_protocol = Some(status.protocol);
+                ping_result.max_players = Some(status.max);
+                ping_result.online_players = Some(status.online);
+                ping_result.description = Some(status.description_text.clone());
+                ping_result.enforces_secure_chat = status.enforces_secure_chat;
+                let players = status
+                    .sample
+                    .iter()
+                    .filter_map(|player| {
+                        let uuid = Uuid::parse_str(&player.id).ok()?;
+                        let mut player_info = PlayerInfo::new_unchecked(&player.name, uuid);
+                        player_info.java_account = player
+                            .account
+                            .as_ref()
+                            .map(|account| account.eq_ignore_ascii_case(&player.name));
+                        Some(player_info)
+                    })
+                    .collect();
+                Some((ping_result, players))
+            }
+            Behavior::Legacy(status) => {
+                ping_result.version_protocol =
+                    status.version.as_ref().map(|(protocol, _)| *protocol);
+                ping_result.version_name = status.version.as_ref().map(|(_, name)| name.clone());
+                ping_result.max_players = Some(status.max);
+                ping_result.online_players = Some(status.online);
+                ping_result.description = Some(status.motd.clone());
+                Some((ping_result, vec![]))
+            }
+            Behavior::Malformed(_) => None,
+        }
+    }
+
+    async fn serve(&self, mut stream: TcpStream) -> io::Result<()> {
+        match &self.behavior {
+            Behavior::Modern(status) => {
+                if !read_status_request(&mut stream).await? {
+                    return Ok(());
+                }
+                write_packet(&mut stream, 0x00, &string(&status.json())).await?;
+                // answer pings until the client is done
+                while let Ok(packet) = read_packet(&mut stream).await {
+                    if let [0x01, payload @ ..] = packet.as_slice() {
+                        write_packet(&mut stream, 0x01, payload).await?;
+                    }
+                }
+                return Ok(());
+            }
+            Behavior::Legacy(status) => {
+                if stream.read_u8().await? != LEGACY_PING_ID {
+                    return Ok(());
+                }
+                stream
+                    .write_all(&legacy::encode_kick(&status.kick_message()))
+                    .await?;
+            }
+            Behavior::Malformed(Malformed::Close) => return Ok(()),
+            Behavior::Malformed(Malformed::Silent) => {}
+            Behavior::Malformed(Malformed::Garbage) => {
+                let mut seed = match self.addr.ip() {
+                    IpAddr::V4(ip) => u32::from(ip),
+                    IpAddr::V6(_) => 0,
+                };
+                let garbage = (0..256)
+                    .map(|_| {
+                        seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                        (seed >> 16) as u8
+                    })
+                    .collect::<Vec<_>>();
+                stream.write_all(&garbage).await?;
+            }
+            Behavior::Malformed(Malformed::Truncated) => {
+                if !read_status_request(&mut stream).await? {
+                    return Ok(());
+                }
+                let mut packet = Vec::new();
+                write_varint(&mut packet, 1000);
+                packet.push(0x00);
+                packet.extend_from_slice(&string(r#"{"version":{"name":"1.20.4","#));
+                stream.write_all(&packet).await?;
+            }
+            Behavior::Malformed(Malformed::InvalidJson) => {
+                if !read_status_request(&mut stream).await? {
+                    return Ok(());
+                }
+                let json = r#"{"version": {"name": "1.20.4", "protocol": 765}, "players": "#;
+                write_packet(&mut stream, 0x00, &string(json)).await?;
+            }
+            Behavior::Malformed(Malformed::InvalidLegacy) => {
+                if stream.read_u8().await? != LEGACY_PING_ID {
+                    return Ok(());
+                }
+                stream
+                    .write_all(&legacy::encode_kick("§1\0only\0three fields"))
+                    .await?;
+            }
+        }
+        if !matches!(self.behavior, Behavior::Malformed(Malformed::Silent)) {
+            stream.shutdown().await?;
+        }
+        // closing with unread data sends a RST, so wait for the client to
+        // close too
+        let mut buffer = [0; 1024];
+        while stream.read(&mut buffer).await? > 0 {}
+        Ok(())
+    }
+}
+
+impl Status {
+    fn generate(index: usize) -> Self {
+        const VERSIONS: [(&str, i32); 5] = [
+            ("1.20.4", 765),
+            ("1.8.9", 47),
+            ("Paper 1.20.1", 763),
+            ("BungeeCord 1.8.x-1.20.x", 47),
+            ("§4Maintenance", -1),
+        ];
+        const MAX_PLAYERS: [i32; 5] = [20, 100, 500, 2024, 0];
+        const FAVICON_LENGTHS: [usize; 4] = [0, 0, 4096, 100_000];
+
+        let (version_name, protocol) = VERSIONS[index % VERSIONS.len()];
+        let (description, description_text) = match index % 7 {
+            0 => (
+                json!("A Minecraft Server"),
+                "A Minecraft Server".to_string(),
+            ),
+            1 => (
+                json!({ "text": format!("Server #{index}") }),
+                format!("Server #{index}"),
+            ),
+            2 => (
+                json!({
+                    "text": "",
+                    "extra": [
+                        { "text": "Welcome to ", "color": "gold" },
+                        { "text": "Snowland", "bold": true, "color": "aqua" },
+                    ],
+                }),
+                "Welcome to Snowland".to_string(),
+            ),
+            3 => (
+                json!(format!("§aLegacy §lcodes§r in #{index}")),
+                format!("Legacy codes in #{index}"),
+            ),
+            4 => (json!("❄ snow ☃ 雪 🌨"), "❄ snow ☃ 雪 🌨".to_string()),
+            5 => (json!(""), String::new()),
+            _ => {
+                let text = format!("line one\nline two {}", "=".repeat(1000));
+                (json!({ "text": text }), text)
+            }
+        };
+
+        let player = |n: usize| {
+            let name = format!("farmer_{index}_{n}");
+            let account = match n % 4 {
+                // renamed since the server cached it
+                1 => format!("renamed_{index}_{n}"),
+                2 => name.to_uppercase(),
+                _ => name.clone(),
+            };
+            let uuid = Uuid::from_u128(
+                0x5f5f_0000_0000_4000_8000_0000_0000_0000 | (index as u128) << 16 | n as u128,
+            );
+            SamplePlayer {
+                name,
+                id: uuid.to_string(),
+                account: Some(account),
+            }
+        };
+        let sample = match index % 6 {
+            0 | 1 => vec![],
+            2 => vec![player(0)],
+            3 => (0..3).map(player).collect(),
+            4 => vec![
+                player(0),
+                SamplePlayer {
+                    name: "§aWelcome!".to_string(),
+                    id: String::new(),
+                    account: None,
+                },
+                SamplePlayer {
+                    name: "Bob".to_string(),
+                    id: "not-a-uuid".to_string(),
+                    account: None,
+                },
+                player(1),
+            ],
+            _ => (0..12).map(player).collect(),
+        };
+
+        Self {
+            version_name: version_name.to_string(),
+            protocol,
+            online: (index * 7 % 500) as i32,
+            max: MAX_PLAYERS[index % MAX_PLAYERS.len()],
+            description,
+            description_text,
+            sample,
+            enforces_secure_chat: [None, Some(true), Some(false)][index % 3],
+            favicon_length: FAVICON_LENGTHS[index % FAVICON_LENGTHS.len()],
+        }
+    }
+
+    fn json(&self) -> String {
+        let sample = self
+            .sample
+            .iter()
+            .map(|player| json!({ "name": player.name, "id": player.id }))
+            .collect::<Vec<_>>();
+        let mut status = json!({
+            "version": { "name": self.version_name, "protocol": self.protocol },
+            "players": { "max": self.max, "online": self.online, "sample": sample },
+            "description": self.description,
+        });
+        if let Some(enforces_secure_chat) = self.enforces_secure_chat {
+            status["enforcesSecureChat"] = json!(enforces_secure_chat);
+        }
+        if self.favicon_length > 0 {
+            status["favicon"] = json!(format!(
+                "data:image/png;base64,{}",
+                "A".repeat(self.favicon_length)
+            ));
+        }
+        status.to_string()
+    }
+}
+
+impl LegacyStatus {
+    fn generate(index: usize) -> Self {
+        let version = match index % 3 {
+            0 => Some((78, "1.6.4".to_string())),
+            1 => Some((51, "1.4.7".to_string())),
+            // pre-1.4
+            _ => None,
+        };
+        let motd = match index % 4 {
+            0 => "A Minecraft Server".to_string(),
+            1 => format!("§eOld §fschool #{index}"),
+            2 => "雪 server".to_string(),
+            _ => String::new(),
+        };
+        Self {
+            version,
+            motd,
+            online: (index % 64) as i32,
+            max: 64,
+        }
+    }
+
+    fn kick_message(&self) -> String {
+        match &self.version {
+            Some((protocol, name)) => format!(
+                "§1\0{protocol}\0{name}\0{}\0{}\0{}",
+                self.motd, self.online, self.max
+            ),
+            None => format!("{}§{}§{}", self.motd, self.online, self.max),
+        }
+    }
+}
+
+/// Every server of the farm, which stop when it's dropped
+pub struct ServerFarm {
+    pub servers: Vec<Arc<FakeServer>>,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl ServerFarm {
+    /// Start `count` servers listening on `port`
+    pub async fn start(count: usize, port: u16) -> io::Result<Self> {
+        let mut farm = Self {
+            servers: Vec::with_capacity(count),
+            tasks: Vec::with_capacity(count),
+        };
+        for index in 0..count {
+            let ip = Ipv4Addr::from(u32::from(FIRST_ADDRESS) + index as u32);
+            let server = Arc::new(FakeServer::generate(
+                index,
+                SocketAddr::new(ip.into(), port),
+            ));
+            let listener = TcpListener::bind(server.addr).await?;
+            farm.servers.push(server.clone());
+            farm.tasks.push(tokio::spawn(async move {
+                while let Ok((stream, _)) = listener.accept().await {
+                    let server = server.clone();
+                    tokio::spawn(async move {
+                        let _ =
+                            tokio::time::timeout(CONNECTION_LIFETIME, server.serve(stream)).await;
+                    });
+                }
+            }));
+        }
+        Ok(farm)
+    }
+
+    /// The mowojang accounts of every player in a sample
+    pub fn accounts(&self) -> HashMap<Uuid, String> {
+        self.servers
+            .iter()
+            .filter_map(|server| match &server.behavior {
+                Behavior::Modern(status) => Some(&status.sample),
+                _ => None,
+            })
+            .flatten()
+            .filter_map(|player| {
+                let uuid = Uuid::parse_str(&player.id).ok()?;
+                Some((uuid, player.account.clone()?))
+            })
+            .collect()
+    }
+}
+
+impl Drop for ServerFarm {
+    fn drop(&mut self) {
+        for task in &self.tasks {
+            task.abort();
+        }
+    }
+}
+
+/// Where mowojang lookups go, if it's somewhere we can answer them
+pub fn mowojang_addr(uri: Option<&str>) -> Option<SocketAddr> {
+    let host = uri?.strip_prefix("http://")?.split('/').next()?;
+    let addr = host.parse::<SocketAddr>().ok()?;
+    matches!(addr.ip(), IpAddr::V4(ip) if ip.is_loopback()).then_some(addr)
+}
+
+/// Answer mowojang lookups of `accounts`, so scanning the farm doesn't look up
+/// made up players on the real api
+pub async fn serve_mowojang(
+    addr: SocketAddr,
+    accounts: HashMap<Uuid, String>,
+) -> io::Result<JoinHandle<()>> {
+    let listener = TcpListener::bind(addr).await?;
+    let accounts = Arc::new(accounts);
+    Ok(tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            let accounts = accounts.clone();
+            tokio::spawn(async move {
+                let mut request = Vec::new();
+                let mut buffer = [0; 1024];
+                while !request.ends_with(b"\r\n\r\n") && request.len() < 8192 {
+                    match stream.read(&mut buffer).await {
+                        Ok(0) | Err(_) => return,
+                        Ok(read) => request.extend_from_slice(&buffer[..read]),
+                    }
+                }
+                // GET /<uuid> HTTP/1.1
+                let request = String::from_utf8_lossy(&request);
+                let account = request
+                    .split(' ')
+                    .nth(1)
+                    .and_then(|path| Uuid::parse_str(path.trim_start_matches('/')).ok())
+                    .and_then(|uuid| Some((uuid, accounts.get(&uuid)?)));
+                let (status, body) = match account {
+                    Some((uuid, name)) => (
+                        "200 OK",
+                        json!({ "name": name, "id": uuid.to_string() }).to_string(),
+                    ),
+                    None => ("404 Not Found", "{}".to_string()),
+                };
+                let response = format!(
+                    "HTTP/1.1 {status}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                    body.len()
+                );
+                let _ = stream.write_all(response.as_bytes()).await;
+            });
+        }
+    }))
+}
+
+/// Read the handshake and status request, `false` if the client sent
+/// something else
+async fn read_status_request(stream: &mut TcpStream) -> io::Result<bool> {
+    let handshake = read_packet(stream).await?;
+    // the last field of the handshake is the next state, 1 for status
+    if handshake.first() != Some(&0x00) || handshake.last() != Some(&0x01) {
+        return Ok(false);
+    }
+    Ok(read_packet(stream).await? == [0x00])
+}
+
+async fn read_packet(stream: &mut TcpStream) -> io::Result<Vec<u8>> {
+    let mut length = 0;
+    for i in 0..3 {
+        let byte = stream.read_u8().await?;
+        length |= ((byte & 0x7f) as usize) << (7 * i);
+        if byte & 0x80 == 0 {
+            break;
+        }
+    }
+    if length > MAX_PACKET_LENGTH {
+        return Err(io::Error::from(io::ErrorKind::InvalidData));
+    }
+    let mut packet = vec![0; length];
+    stream.read_exact(&mut packet).await?;
+    Ok(packet)
+}
+
+async fn write_packet(stream: &mut TcpStream, id: u8, data: &[u8]) -> io::Result<()> {
+    let mut packet = Vec::with_capacity(data.len() + 6);
+    write_varint(&mut packet, data.len() as u32 + 1);
+    packet.push(id);
+    packet.extend_from_slice(data);
+    stream.write_all(&packet).await
+}
+
+fn write_varint(buffer: &mut Vec<u8>, mut value: u32) {
+    loop {
+        if value < 0x80 {
+            buffer.push(value as u8);
+            return;
+        }
+        buffer.push((value & 0x7f) as u8 | 0x80);
+        value >>= 7;
+    }
+}
+
+/// A string as the protocol sends it, prefixed with its length
+fn string(string: &str) -> Vec<u8> {
+    let mut buffer = Vec::with_capacity(string.len() + 3);
+    write_varint(&mut buffer, string.len() as u32);
+    buffer.extend_from_slice(string.as_bytes());
+    buffer
+}
@@ -0,0 +1,141 @@
+//! Scans a farm of fake servers and checks every row the scanner produces
+//! against what the server sent, see the library for what the servers do.
+//!
+//! Run it from this crate's directory, its `Snowstorm.toml` scans loopback and
+//! has the farm answer mowojang lookups:
+//! ```sh
+//! cd crates/server_farm
+//! cargo r -r                           # NetworkScanner
+//! sudo -E cargo r -r -- --backend pnet # the SYN scanner
+//! ```
+//! `cargo t` scans a small farm with the tcp backend, the SYN scanner and
+//! database tests are ignored since they need root and postgres.
+//! The SYN scanner needs CAP_NET_RAW and a firewall rule for its source port,
+//! which can be kept off the host by giving it its own network namespace:
+//! ```sh
+//! sudo unshare -n sh -c 'ip link set lo up && ../../target/release/server_farm --backend pnet'
+//! ```
+
+use clap::Parser;
+use common::net::{
+    firewall::{self, FirewallRule},
+    rate_limit::RateLimiter,
+};
+use config::Backend;
+use io::{
+    backend::Scanner, network::NetworkScanner, pnet::PnetScanner, proxy::NetworkProxy, ScannerState,
+};
+use server_farm::{
+    check::{self, GRACE_PERIOD},
+    ServerFarm,
+};
+use std::{
+    sync::{mpsc, Arc},
+    time::{Duration, Instant},
+};
+use tokio::sync::Mutex;
+
+/// How many failures are printed
+const MAX_FAILURES_SHOWN: usize = 20;
+
+#[derive(Parser)]
+struct Args {
+    /// tcp or pnet
+    #[arg(long, default_value = "tcp")]
+    backend: Backend,
+    /// How many servers to start
+    #[arg(long, default_value_t = 4096)]
+    servers: usize,
+    #[arg(long, default_value_t = 25565)]
+    port: u16,
+}
+
+#[tokio::main]
+async fn main() -> eyre::Result<()> {
+    let args = Args::parse();
+    let config = config::get();
+    raise_file_limit();
+
+    let farm = ServerFarm::start(args.servers, args.port).await?;
+    let mowojang = match server_farm::mowojang_addr(config.mowojang.internal_api_uri.as_deref()) {
+        Some(addr) => {
+            server_farm::serve_mowojang(addr, farm.accounts()).await?;
+            true
+        }
+        None => {
+            println!("mowojang.internal_api_uri isn't on loopback, player accounts aren't checked");
+            false
+        }
+    };
+    println!("started {} servers", farm.servers.len());
+
+    let (sender, receiver) = mpsc::channel();
+    let state = Arc::new(Mutex::new(ScannerState::default()));
+    let mut firewall_rule = None;
+    let mut scanner = match args.backend {
+        Backend::Tcp => Scanner::Tcp(NetworkScanner::new(
+            state,
+            sender,
+            NetworkProxy::Direct,
+            RateLimiter::unlimited(),
+        )),
+        Backend::Pnet => {
+            let scanner = PnetScanner::new(state, sender, RateLimiter::unlimited())?;
+            let rule = FirewallRule::install(config.scanner.firewall, config.scanner.source_port)?;
+            if let Err(err) = firewall::verify(config.scanner.source_port) {
+                let _ = rule.remove();
+                return Err(err);
+            }
+            firewall_rule = Some(rule);
+            Scanner::Pnet(scanner)
+        }
+        backend => return Err(eyre::eyre!("the farm can't be scanned with {backend:?}")),
+    };
+
+    let started = Instant::now();
+    check::ping_all(&mut scanner, &farm.servers).await?;
+
+    let expected = check::expected_rows(&farm.servers);
+    let timeout = Duration::from_secs(config.scanner.connection_timeout) + GRACE_PERIOD;
+    let rows =
+        tokio::task::spawn_blocking(move || check::collect(receiver, expected, timeout)).await?;
+    println!("got {} rows in {:?}", rows.len(), started.elapsed());
+
+    if let Some(rule) = firewall_rule {
+        if let Err(err) = rule.remove() {
+            eprintln!("unable to remove firewall rule: {err}");
+        }
+    }
+
+    let failures = check::check(&farm.servers, rows, mowojang);
+    for failure in failures.iter().take(MAX_FAILURES_SHOWN) {
+        eprintln!("{failure}");
+    }
+    if failures.len() > MAX_FAILURES_SHOWN {
+        eprintln!("and {} more", failures.len() - MAX_FAILURES_SHOWN);
+    }
+    if !failures.is_empty() {
+        return Err(eyre::eyre!(
+            "{} of {} servers weren't scanned correctly",
+            failures.len(),
+            farm.servers.len()
+        ));
+    }
+    println!("all {} servers were scanned correctly", farm.servers.len());
+    Ok(())
+}
+
+/// Every server holds a file descriptor, so raise the limit as far as we're
+/// allowed to
+fn raise_file_limit() {
+    let mut limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    unsafe {
+        if libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) == 0 {
+            limit.rlim_cur = limit.rlim_max;
+            libc::setrlimit(libc::RLIMIT_NOFILE, &limit);
+        }
+    }
+}
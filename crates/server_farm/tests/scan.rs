@@ -0,0 +1,139 @@
+//! Scans a small farm end to end, like `main.rs` does with a big one. Every
+//! test gets its own port so they can run together.
+
+use common::net::{
+    firewall::{self, FirewallRule},
+    rate_limit::RateLimiter,
+};
+use database::{
+    database_connection::DatabaseConnection, player::PlayerInfo, server::PingResult, DbPush,
+};
+use io::{network::NetworkScanner, pnet::PnetScanner, proxy::NetworkProxy, Io, ScannerState};
+use server_farm::{
+    check::{self, Row, GRACE_PERIOD},
+    ServerFarm,
+};
+use std::{
+    sync::{
+        mpsc::{self, Receiver},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::sync::Mutex;
+
+/// Every kind of server four times over
+const SERVERS: usize = 64;
+
+/// Point the config at this crate's `Snowstorm.toml`, which scans loopback
+fn use_farm_config() -> Arc<config::Config> {
+    std::env::set_var(
+        "SNOWSTORM_CONFIG",
+        concat!(env!("CARGO_MANIFEST_DIR"), "/Snowstorm.toml"),
+    );
+    config::get()
+}
+
+/// Ping the whole farm and wait for the rows
+async fn scan(farm: &ServerFarm, scanner: &mut impl Io, receiver: Receiver<Row>) -> Vec<Row> {
+    check::ping_all(scanner, &farm.servers).await.unwrap();
+    let expected = check::expected_rows(&farm.servers);
+    let timeout = Duration::from_secs(config::get().scanner.connection_timeout) + GRACE_PERIOD;
+    tokio::task::spawn_blocking(move || check::collect(receiver, expected, timeout))
+        .await
+        .unwrap()
+}
+
+fn assert_scanned_correctly(farm: &ServerFarm, rows: Vec<Row>, check_accounts: bool) {
+    let failures = check::check(&farm.servers, rows, check_accounts);
+    assert!(failures.is_empty(), "{failures:#?}");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn tcp_backend_scans_the_farm() {
+    let config = use_farm_config();
+    let farm = ServerFarm::start(SERVERS, 25600).await.unwrap();
+    // the only test that answers mowojang lookups, the others would fight
+    // over the address
+    let mowojang = server_farm::mowojang_addr(config.mowojang.internal_api_uri.as_deref())
+        .expect("mowojang.internal_api_uri should be on loopback");
+    let mowojang = server_farm::serve_mowojang(mowojang, farm.accounts())
+        .await
+        .unwrap();
+
+    let (sender, receiver) = mpsc::channel();
+    let mut scanner = NetworkScanner::new(
+        Arc::new(Mutex::new(ScannerState::default())),
+        sender,
+        NetworkProxy::Direct,
+        RateLimiter::unlimited(),
+    );
+    let rows = scan(&farm, &mut scanner, receiver).await;
+    mowojang.abort();
+
+    assert_scanned_correctly(&farm, rows, true);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[ignore = "needs CAP_NET_RAW and a firewall"]
+async fn pnet_backend_scans_the_farm() {
+    let config = use_farm_config();
+    let farm = ServerFarm::start(SERVERS, 25601).await.unwrap();
+
+    let (sender, receiver) = mpsc::channel();
+    let mut scanner = PnetScanner::new(
+        Arc::new(Mutex::new(ScannerState::default())),
+        sender,
+        RateLimiter::unlimited(),
+    )
+    .unwrap();
+    let rule = FirewallRule::install(config.scanner.firewall, config.scanner.source_port).unwrap();
+    if let Err(err) = firewall::verify(config.scanner.source_port) {
+        let _ = rule.remove();
+        panic!("{err}");
+    }
+    let rows = scan(&farm, &mut scanner, receiver).await;
+    rule.remove().unwrap();
+
+    assert_scanned_correctly(&farm, rows, false);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[ignore = "needs the scanner database from postgres/scanner/setup.sql"]
+async fn pushed_rows_read_back() {
+    use_farm_config();
+    let farm = ServerFarm::start(SERVERS, 25602).await.unwrap();
+    let database = DatabaseConnection::new().await.unwrap();
+
+    let (sender, receiver) = mpsc::channel();
+    let mut scanner = NetworkScanner::new(
+        Arc::new(Mutex::new(ScannerState::default())),
+        sender,
+        NetworkProxy::Direct,
+        RateLimiter::unlimited(),
+    );
+    let rows = scan(&farm, &mut scanner, receiver).await;
+
+    // check what's in the database rather than what we pushed
+    let mut read_back = Vec::with_capacity(rows.len());
+    for mut row in rows {
+        row.push(&database.pool).await.unwrap();
+        let (ping_result, players) = row;
+        let stored =
+            PingResult::from_ip_port(&ping_result.ip(), ping_result.port(), &database.pool)
+                .await
+                .expect("pushed server isn't in the database");
+        assert_eq!(stored.id, ping_result.id);
+
+        let mut stored_players = Vec::with_capacity(players.len());
+        for player in players {
+            let stored = PlayerInfo::from_uuid(player.uuid, &database.pool)
+                .await
+                .expect("pushed player isn't in the database");
+            stored_players.push(stored);
+        }
+        read_back.push((stored, stored_players));
+    }
+
+    assert_scanned_correctly(&farm, read_back, false);
+}
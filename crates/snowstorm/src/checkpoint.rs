@@ -0,0 +1,241 @@
+use common::network_range::{ScanOrder, SocketAddrRange};
+use scheduling::ScanningMode;
+use serde::{Deserialize, Serialize};
+use siphasher::sip::SipHasher13;
+use std::{
+    hash::Hasher,
+    io::{BufRead, IsTerminal, Write},
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+/// How far a scan got, saved every `scanner.checkpoint_interval` seconds.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Checkpoint {
+    pub mode: ScanningMode,
+    /// Hash of the addresses being scanned, which are saved next to the
+    /// checkpoint since they only change with the mode
+    pub addresses_hash: u64,
+    pub seed: u64,
+    /// Every index below this was pinged
+    pub index: u64,
+    pub total: u64,
+}
+
+pub struct Checkpoints {
+    path: PathBuf,
+    interval: Duration,
+    last_save: Instant,
+    addresses_hash: u64,
+}
+
+impl Checkpoints {
+    pub fn new(path: PathBuf, interval: Duration) -> Self {
+        Self {
+            path,
+            interval,
+            last_save: Instant::now(),
+            addresses_hash: 0,
+        }
+    }
+
+    fn addresses_path(&self) -> PathBuf {
+        self.path.with_extension("addresses.json")
+    }
+
+    /// The saved checkpoint and the addresses it's for, if there is one and
+    /// the addresses weren't changed since.
+    pub fn load(&self) -> eyre::Result<Option<(Checkpoint, Vec<SocketAddrRange>)>> {
+        if !self.path.exists() {
+            return Ok(None);
+        }
+        let checkpoint: Checkpoint = serde_json::from_slice(&std::fs::read(&self.path)?)?;
+        let Ok(addresses) = std::fs::read(self.addresses_path()) else {
+            println!("ignoring checkpoint, the addresses it's for are missing");
+            return Ok(None);
+        };
+        if hash(&addresses) != checkpoint.addresses_hash {
+            println!("ignoring checkpoint, the addresses it's for were changed");
+            return Ok(None);
+        }
+        Ok(Some((checkpoint, serde_json::from_slice(&addresses)?)))
+    }
+
+    /// Save the addresses of a scan that's starting
+    pub fn start(
+        &mut self,
+        mode: ScanningMode,
+        scan_order: &ScanOrder,
+        index: u64,
+    ) -> eyre::Result<()> {
+        let addresses = serde_json::to_vec(scan_order.ranges())?;
+        self.addresses_hash = hash(&addresses);
+        write_atomic(&self.addresses_path(), &addresses)?;
+        self.write(mode, scan_order, index)
+    }
+
+    /// Save the progress of the scan if it wasn't saved for a while
+    pub fn save(
+        &mut self,
+        mode: ScanningMode,
+        scan_order: &ScanOrder,
+        index: u64,
+    ) -> eyre::Result<()> {
+        if self.last_save.elapsed() < self.interval {
+            return Ok(());
+        }
+        self.write(mode, scan_order, index)
+    }
+
+    /// Save the progress of the scan right away, like when we're stopped
+    pub fn write(
+        &mut self,
+        mode: ScanningMode,
+        scan_order: &ScanOrder,
+        index: u64,
+    ) -> eyre::Result<()> {
+        let checkpoint = Checkpoint {
+            mode,
+            addresses_hash: self.addresses_hash,
+            seed: scan_order.seed(),
+            index,
+            total: scan_order.count_addresses(),
+        };
+        write_atomic(&self.path, &serde_json::to_vec(&checkpoint)?)?;
+        self.last_save = Instant::now();
+        Ok(())
+    }
+}
+
+/// Ask whether to resume from `checkpoint`. When we're not run from a terminal
+/// there's no one to ask, so we always resume.
+pub fn ask_to_resume(checkpoint: &Checkpoint) -> bool {
+    println!(
+        "found a checkpoint of {:?} at {}/{} addresses",
+        checkpoint.mode, checkpoint.index, checkpoint.total
+    );
+    if !std::io::stdin().is_terminal() {
+        println!("resuming");
+        return true;
+    }
+    print!("resume it? [Y/n] ");
+    let _ = std::io::stdout().flush();
+    let mut answer = String::new();
+    if std::io::stdin().lock().read_line(&mut answer).is_err() {
+        return false;
+    }
+    !answer.trim().eq_ignore_ascii_case("n")
+}
+
+/// Stable between builds and Rust versions, unlike `DefaultHasher`, so an
+/// upgrade doesn't throw away the progress
+fn hash(bytes: &[u8]) -> u64 {
+    let mut hasher = SipHasher13::new_with_keys(0, 0);
+    hasher.write(bytes);
+    hasher.finish()
+}
+
+/// Write to a temporary file first so a crash never leaves half a checkpoint
+fn write_atomic(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    let temp_path = path.with_extension("tmp");
+    std::fs::write(&temp_path, contents)?;
+    std::fs::rename(temp_path, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::network_range::{SocketAddrV4Range, SocketAddrV6Range};
+
+    const MODE: ScanningMode = ScanningMode::OnePortMinecraftRange;
+
+    /// Checkpoints in an empty directory of their own
+    fn in_temp_dir(name: &str, interval: Duration) -> Checkpoints {
+        let dir = std::env::temp_dir().join(format!(
+            "snowstorm-checkpoint-{name}-{}",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        Checkpoints::new(dir.join("checkpoint.json"), interval)
+    }
+
+    fn scan_order() -> ScanOrder {
+        ScanOrder::new(
+            vec![
+                SocketAddrV4Range::new(
+                    "10.0.0.0:25565".parse().unwrap(),
+                    "10.0.0.255:25565".parse().unwrap(),
+                )
+                .into(),
+                SocketAddrV6Range::new(
+                    "[2001:db8::]:25565".parse().unwrap(),
+                    "[2001:db8::ff]:25565".parse().unwrap(),
+                )
+                .into(),
+            ],
+            1234,
+        )
+    }
+
+    fn loaded_index(checkpoints: &Checkpoints) -> u64 {
+        checkpoints.load().unwrap().unwrap().0.index
+    }
+
+    fn clean_up(checkpoints: Checkpoints) {
+        std::fs::remove_dir_all(checkpoints.path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn loads_what_was_started() {
+        let mut checkpoints = in_temp_dir("round-trip", Duration::ZERO);
+        assert!(checkpoints.load().unwrap().is_none());
+
+        let scan_order = scan_order();
+        checkpoints.start(MODE, &scan_order, 5).unwrap();
+        let (checkpoint, addresses) = checkpoints.load().unwrap().unwrap();
+        assert_eq!(checkpoint.mode, MODE);
+        assert_eq!(checkpoint.seed, 1234);
+        assert_eq!(checkpoint.index, 5);
+        assert_eq!(checkpoint.total, 512);
+        assert_eq!(addresses, scan_order.ranges());
+
+        checkpoints.write(MODE, &scan_order, 100).unwrap();
+        assert_eq!(loaded_index(&checkpoints), 100);
+        clean_up(checkpoints);
+    }
+
+    #[test]
+    fn ignores_changed_addresses() {
+        let mut checkpoints = in_temp_dir("changed", Duration::ZERO);
+        checkpoints.start(MODE, &scan_order(), 5).unwrap();
+        std::fs::write(checkpoints.addresses_path(), "[]").unwrap();
+        assert!(checkpoints.load().unwrap().is_none());
+        clean_up(checkpoints);
+    }
+
+    #[test]
+    fn ignores_missing_addresses() {
+        let mut checkpoints = in_temp_dir("missing", Duration::ZERO);
+        checkpoints.start(MODE, &scan_order(), 5).unwrap();
+        std::fs::remove_file(checkpoints.addresses_path()).unwrap();
+        assert!(checkpoints.load().unwrap().is_none());
+        clean_up(checkpoints);
+    }
+
+    #[test]
+    fn saves_once_the_interval_passed() {
+        let scan_order = scan_order();
+        let mut checkpoints = in_temp_dir("interval", Duration::from_secs(3600));
+        checkpoints.start(MODE, &scan_order, 0).unwrap();
+        checkpoints.save(MODE, &scan_order, 10).unwrap();
+        assert_eq!(loaded_index(&checkpoints), 0);
+        clean_up(checkpoints);
+
+        let mut checkpoints = in_temp_dir("no-interval", Duration::ZERO);
+        checkpoints.start(MODE, &scan_order, 0).unwrap();
+        checkpoints.save(MODE, &scan_order, 10).unwrap();
+        assert_eq!(loaded_index(&checkpoints), 10);
+        clean_up(checkpoints);
+    }
+}
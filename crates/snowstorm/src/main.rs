@@ -1,44 +1,115 @@
 #![feature(linked_list_remove)]
 
-use common::network_range::RangesExt;
-use database::{player::PlayerInfo, server::PingResult, DatabaseConnection, DbPush};
-use io::{Io, ScannerState};
-use scheduling::ModePicker;
+use checkpoint::{Checkpoint, Checkpoints};
+use clap::Parser;
+use common::{
+    addr_range::{Ipv4AddrRange, Ipv6AddrRange},
+    exclude,
+    net::{
+        firewall::{self, FirewallRule},
+        rate_limit::{RateLimit, RateLimiter},
+    },
+    network_range::{ScanOrder, SocketAddrRange},
+};
+use config::Backend;
+use database::{
+    opt_out::OptOut, player::PlayerInfo, server::PingResult, DatabaseConnection, DbPush,
+};
+use io::{
+    backend::Scanner,
+    metrics::{Counters, COUNTERS},
+    Io, Probe, ScannerState,
+};
+use retry::Retries;
+use scheduling::{ModePicker, ScanningMode};
 use std::{
     sync::{
-        mpsc::{channel, Sender},
+        atomic::{AtomicBool, Ordering},
+        mpsc::{channel, Receiver, RecvTimeoutError, Sender, TryRecvError},
         Arc,
     },
     thread,
     time::{Duration, Instant},
 };
-use tokio::{runtime::Runtime, sync::Mutex};
+use tokio::{
+    runtime::Runtime,
+    signal::unix::{signal, SignalKind},
+    sync::{watch, Mutex},
+};
+
+mod checkpoint;
+mod retry;
+
+#[derive(Parser)]
+struct Args {
+    /// pnet, tcp, replay or pcap, overrides `scanner.backend`
+    #[arg(long)]
+    backend: Option<Backend>,
+}
 
 #[tokio::main]
 async fn main() -> eyre::Result<()> {
+    let args = Args::parse();
     let config = config::get();
     let db = DatabaseConnection::new().await?;
     let state = Arc::new(Mutex::new(ScannerState::default()));
     let (ping_results_sender, ping_results) = channel();
+    let rate_limiter = RateLimiter::new(RateLimit {
+        max_pps: config.scanner.max_pps,
+        burst_size: config.scanner.burst_size,
+    });
 
-    #[cfg(debug_assertions)]
-    let pinger = io::database::DatabaseScanner::new(state.clone(), ping_results_sender);
-    #[cfg(not(debug_assertions))]
-    let pinger = io::pnet::PnetScanner::new(state.clone(), ping_results_sender);
+    let backend = args.backend.unwrap_or(config.scanner.backend);
+    println!("scanning with the {backend:?} backend");
+    let firewall_rule = match backend {
+        Backend::Pnet => Some(setup_firewall()?),
+        _ => None,
+    };
+    // caught so the results we already have are saved before we stop
+    let mut terminate = signal(SignalKind::terminate())?;
+    let pinger = Scanner::new(
+        backend,
+        state.clone(),
+        ping_results_sender,
+        rate_limiter.clone(),
+    )?;
 
+    let (stop_scanning, stopped) = watch::channel(false);
+    let mut scanning = None;
     if config.scanner.enabled {
+        // asked before anything is started, so the question isn't buried in
+        // their output and nothing waits on stdin
+        let checkpoints = config.scanner.checkpoint.as_ref().map(|path| {
+            Checkpoints::new(
+                path.clone(),
+                Duration::from_secs(config.scanner.checkpoint_interval),
+            )
+        });
+        let resumed = match &checkpoints {
+            Some(checkpoints) => checkpoints
+                .load()?
+                .filter(|(checkpoint, _)| checkpoint::ask_to_resume(checkpoint)),
+            None => None,
+        };
+
+        // never start scanning without the opt-outs
+        sync_opt_outs(&db).await?;
+        watch_exclude_list(db.clone())?;
         let db = db.clone();
         let state = state.clone();
-        tokio::spawn(async move {
-            ping_loop(db, state, pinger).await.unwrap();
-        });
+        scanning = Some(tokio::spawn(async move {
+            ping_loop(db, state, pinger, checkpoints, resumed, stopped)
+                .await
+                .unwrap();
+        }));
     }
 
     if config.web.enabled {
         let db = db.clone();
         let state = state.clone();
+        let rate_limiter = rate_limiter.clone();
         tokio::spawn(async move {
-            web::start_server(db, state).await.unwrap();
+            web::start_server(db, state, rate_limiter).await.unwrap();
         });
     }
 
@@ -51,46 +122,201 @@ async fn main() -> eyre::Result<()> {
 
     const CHANNEL_COUNT: usize = 8;
 
-    let ping_handlers = {
+    let (ping_handlers, handler_threads) = {
         let mut handlers: Vec<Sender<_>> = Vec::with_capacity(CHANNEL_COUNT);
+        let mut threads = Vec::with_capacity(CHANNEL_COUNT);
         for _ in 0..CHANNEL_COUNT {
             let config = config.clone();
             let db = db.clone();
             let (w, r) = channel::<(PingResult, Vec<PlayerInfo>)>();
             handlers.push(w);
-            thread::spawn(move || {
+            threads.push(thread::spawn(move || {
                 let r = r;
                 while let Ok(mut values) = r.recv() {
                     if config.scanner.push_to_db {
+                        let started = Instant::now();
                         Runtime::new()
                             .unwrap()
                             .block_on(values.push(&db.pool))
                             .unwrap();
+                        Counters::add(&COUNTERS.db_pushes, 1);
+                        Counters::add(
+                            &COUNTERS.db_push_micros,
+                            started.elapsed().as_micros() as u64,
+                        );
                     }
+                    Counters::add(&COUNTERS.handled, 1);
                 }
-            });
+            }));
         }
-        handlers
+        (handlers, threads)
     };
 
-    {
-        let mut handler_iter = 0;
-        for result in ping_results {
-            ping_handlers[handler_iter].send(result)?;
-            handler_iter += 1;
-            if handler_iter >= CHANNEL_COUNT {
-                handler_iter = 0;
-            }
+    let stopping = Arc::new(AtomicBool::new(false));
+    let dispatcher = {
+        let stopping = stopping.clone();
+        thread::spawn(move || dispatch(ping_results, ping_handlers, &stopping))
+    };
+
+    tokio::select! {
+        result = tokio::signal::ctrl_c() => result?,
+        _ = terminate.recv() => {}
+    }
+    let _ = stop_scanning.send(true);
+    if let Some(scanning) = scanning {
+        // if scanning failed it already panicked
+        let _ = scanning.await;
+        println!("waiting for open connections, press ctrl+c again to skip");
+        tokio::select! {
+            _ = tokio::time::sleep(Duration::from_secs(config.scanner.connection_timeout)) => {}
+            _ = tokio::signal::ctrl_c() => {}
         }
     }
 
+    // the dispatcher drops the handler channels once the results are queued,
+    // and the handlers stop after pushing what's left in them
+    stopping.store(true, Ordering::Relaxed);
+    match dispatcher.join() {
+        Ok(Ok(())) => {}
+        Ok(Err(err)) => eprintln!("unable to queue results: {err}"),
+        Err(_) => eprintln!("queueing results panicked"),
+    }
+    println!("pushing queued results");
+    for handler in handler_threads {
+        if handler.join().is_err() {
+            eprintln!("a database handler panicked, some results weren't pushed");
+        }
+    }
+
+    if let Some(rule) = firewall_rule {
+        if let Err(err) = rule.remove() {
+            eprintln!("unable to remove firewall rule: {err}");
+        }
+    }
+    Ok(())
+}
+
+/// How long the results channel has to stay empty while stopping before
+/// we're done queueing
+const DISPATCH_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Hand the results to the database handlers round robin until we're stopping
+/// and every result was handed out
+fn dispatch(
+    ping_results: Receiver<(PingResult, Vec<PlayerInfo>)>,
+    ping_handlers: Vec<Sender<(PingResult, Vec<PlayerInfo>)>>,
+    stopping: &AtomicBool,
+) -> eyre::Result<()> {
+    let mut handler_iter = 0;
+    loop {
+        let result = match ping_results.recv_timeout(DISPATCH_POLL_INTERVAL) {
+            Ok(result) => result,
+            Err(RecvTimeoutError::Timeout) if stopping.load(Ordering::Relaxed) => break,
+            Err(RecvTimeoutError::Timeout) => continue,
+            Err(RecvTimeoutError::Disconnected) => break,
+        };
+        Counters::add(&COUNTERS.queued, 1);
+        ping_handlers[handler_iter].send(result)?;
+        handler_iter += 1;
+        if handler_iter >= ping_handlers.len() {
+            handler_iter = 0;
+        }
+    }
+    Ok(())
+}
+
+/// Install the firewall rule for our source ports if we manage it, and refuse
+/// to start if the kernel would reset our connections. The rule is removed
+/// again when it's dropped, so failing to start doesn't leave it behind.
+fn setup_firewall() -> eyre::Result<FirewallRule> {
+    let config = config::get();
+    let rule = FirewallRule::install(config.scanner.firewall, config.scanner.source_port)?;
+    if let Err(err) = firewall::verify(config.scanner.source_port) {
+        let _ = rule.remove();
+        return Err(err);
+    }
+    Ok(rule)
+}
+
+/// Reload the exclude list on SIGHUP or when `exclude.txt` or the opt-outs
+/// change. Changes apply from the next scanning mode on, since excluded
+/// addresses are subtracted when the addresses are picked.
+fn watch_exclude_list(db: DatabaseConnection) -> eyre::Result<()> {
+    let mut hangup = signal(SignalKind::hangup())?;
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(EXCLUDE_POLL_INTERVAL);
+        loop {
+            let result = tokio::select! {
+                _ = hangup.recv() => exclude::reload(),
+                _ = interval.tick() => match sync_opt_outs(&db).await {
+                    Ok(true) => Ok(()),
+                    Ok(false) => exclude::reload_if_changed().map(|_| ()),
+                    Err(err) => Err(err),
+                },
+            };
+            if let Err(err) = result {
+                eprintln!("unable to reload the exclude list: {err}");
+            }
+        }
+    });
     Ok(())
 }
 
+/// Merge the opt-outs in the database with the exclude list, returning whether
+/// they changed
+async fn sync_opt_outs(db: &DatabaseConnection) -> eyre::Result<bool> {
+    let opt_outs = OptOut::get_active(&db.pool).await?;
+    let ipv4 = opt_outs
+        .iter()
+        .filter_map(OptOut::ipv4_range)
+        .map(|(first, last)| Ipv4AddrRange::new(first, last))
+        .collect();
+    let ipv6 = opt_outs
+        .iter()
+        .filter_map(OptOut::ipv6_range)
+        .map(|(first, last)| Ipv6AddrRange::new(first, last))
+        .collect();
+    exclude::set_opt_outs(ipv4, ipv6)
+}
+
+/// How often `exclude.txt` and the opt-outs are checked for changes
+const EXCLUDE_POLL_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How often we check for due retries once every address was pinged
+const RETRY_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How long pings can stay queued in the scanner before they're sent, which
+/// matters when the rate limit is low enough that batches fill up slowly
+const FLUSH_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Wait for the scheduler to pick the next mode, or `None` if we were stopped
+/// first
+async fn next_mode(
+    receiver: &Receiver<(ScanningMode, Vec<SocketAddrRange>)>,
+    stopped: &watch::Receiver<bool>,
+) -> eyre::Result<Option<(ScanningMode, Vec<SocketAddrRange>)>> {
+    loop {
+        match receiver.try_recv() {
+            Ok(next) => return Ok(Some(next)),
+            Err(TryRecvError::Empty) => {}
+            Err(err) => return Err(err.into()),
+        }
+        if *stopped.borrow() {
+            return Ok(None);
+        }
+        tokio::time::sleep(RETRY_POLL_INTERVAL).await;
+    }
+}
+
+/// Ping every address of the modes the scheduler picks until `stopped` is set,
+/// then save how far we got
 async fn ping_loop(
     db: DatabaseConnection,
     state: Arc<Mutex<ScannerState>>,
     mut pinger: impl Io,
+    mut checkpoints: Option<Checkpoints>,
+    resumed: Option<(Checkpoint, Vec<SocketAddrRange>)>,
+    stopped: watch::Receiver<bool>,
 ) -> eyre::Result<()> {
     let config = config::get();
 
@@ -98,20 +324,69 @@ async fn ping_loop(
     let mode_picker = Arc::new(parking_lot::Mutex::new(ModePicker::new()));
     let requester = channel();
     let receiver = channel();
-    scheduling::start_scheduler_queue(receiver.0, requester.1, mode_picker, db.pool);
+    scheduling::start_scheduler_queue(receiver.0, requester.1, mode_picker, state.clone(), db.pool);
 
-    // We don't have any data yet, so request the scheduler for addresses without providing any data
-    requester.0.send(None)?;
-    let (mut current_mode, mut current_addresses) = receiver.1.recv()?;
-    println!("got new state {current_mode:?}");
-    let mut total_addresses = current_addresses.count_addresses();
-    assert_ne!(total_addresses, 0);
-    println!("total addresses = {total_addresses}");
-    let mut index = 0;
+    // Set when resuming with addresses that were excluded since the checkpoint.
+    // The saved addresses are scanned in the saved order so the index still
+    // means the same, and the excluded ones are skipped when they come up.
+    let mut check_excluded = false;
+    let (mut current_mode, mut scan_order, mut index) = match resumed {
+        Some((checkpoint, addresses)) => {
+            let (_, skipped) = exclude::subtract(addresses.clone());
+            if skipped > 0 {
+                println!("{skipped} addresses were excluded since the checkpoint, they're skipped");
+                check_excluded = true;
+                state
+                    .lock()
+                    .await
+                    .add_excluded(format!("{:?}", checkpoint.mode), skipped);
+            }
+            (
+                checkpoint.mode,
+                ScanOrder::new(addresses, checkpoint.seed),
+                checkpoint.index,
+            )
+        }
+        None => {
+            // We don't have any data yet, so request the scheduler for addresses without providing any data
+            requester.0.send(None)?;
+            let Some((mode, addresses)) = next_mode(&receiver.1, &stopped).await? else {
+                return Ok(());
+            };
+            (mode, ScanOrder::new(addresses, rand::random()), 0)
+        }
+    };
+    let mut retries = Retries::new(
+        config.scanner.retries,
+        Duration::from_secs(config.scanner.retry_delay),
+    );
+    start_mode(
+        current_mode,
+        &scan_order,
+        index,
+        &state,
+        &mut retries,
+        &mut checkpoints,
+    )
+    .await?;
+    // a mode without addresses ends right away and the next one is requested
+    let mut total_addresses = scan_order.count_addresses();
 
     let mut last_update = Instant::now();
+    let mut last_flush = Instant::now();
     loop {
+        if *stopped.borrow() {
+            pinger.flush();
+            if let Some(checkpoints) = &mut checkpoints {
+                checkpoints.write(current_mode, &scan_order, index)?;
+            }
+            println!("stopped scanning at {index}/{total_addresses} addresses");
+            return Ok(());
+        }
         if index % 2u64.pow(16) == 0 {
+            if let Some(checkpoints) = &mut checkpoints {
+                checkpoints.save(current_mode, &scan_order, index)?;
+            }
             match request_state {
                 RequestState::None => {
                     let current_time = Instant::now();
@@ -121,6 +396,7 @@ async fn ping_loop(
                     {
                         let discovered = state.lock().await.discovered;
                         println!("discovered {discovered} servers");
+                        retries.report(&state.lock().await.receive.syn_acks);
                         requester.0.send(Some((current_mode, discovered)))?;
                         request_state = RequestState::Requested;
                         println!("requesting new state");
@@ -128,45 +404,108 @@ async fn ping_loop(
                     }
                 }
                 RequestState::Requested => {
-                    if let Ok(new_state) = receiver.1.try_recv() {
-                        (current_mode, current_addresses) = new_state;
-                        total_addresses = current_addresses.count_addresses();
-                        assert_ne!(total_addresses, 0);
-                        println!("total addresses = {total_addresses}");
+                    if let Ok((new_mode, addresses)) = receiver.1.try_recv() {
+                        pinger.flush();
+                        current_mode = new_mode;
+                        scan_order = ScanOrder::new(addresses, rand::random());
+                        total_addresses = scan_order.count_addresses();
                         index = 0;
+                        check_excluded = false;
                         request_state = RequestState::None;
                         last_update = Instant::now();
-                        state.lock().await.discovered = 0;
-                        println!("got new state {current_mode:?}");
+                        start_mode(
+                            current_mode,
+                            &scan_order,
+                            index,
+                            &state,
+                            &mut retries,
+                            &mut checkpoints,
+                        )
+                        .await?;
                         continue;
                     }
                 }
             }
         }
+        while let Some((retry_index, attempt)) = retries.next_due(index) {
+            let retry_addr = scan_order.get_addr_at(retry_index);
+            if check_excluded && exclude::is_excluded(&retry_addr) {
+                continue;
+            }
+            pinger.retry(retry_addr, Probe::Slp, attempt).await?;
+        }
+        if last_flush.elapsed() >= FLUSH_INTERVAL {
+            pinger.flush();
+            last_flush = Instant::now();
+        }
         if index >= total_addresses {
+            pinger.flush();
+            if !retries.is_done(total_addresses) {
+                tokio::time::sleep(RETRY_POLL_INTERVAL).await;
+                continue;
+            }
             let duration_since_last_update = Instant::now() - last_update;
             let discovered = state.lock().await.discovered * config.scanner.mode_duration
                 / duration_since_last_update.as_secs().max(1);
             println!("discovered {discovered} servers (extrapolated to duration)");
+            retries.report(&state.lock().await.receive.syn_acks);
             requester.0.send(Some((current_mode, discovered)))?;
             println!("requesting new state (ended early)");
-            (current_mode, current_addresses) = receiver.1.recv()?;
-            total_addresses = current_addresses.count_addresses();
-            assert_ne!(total_addresses, 0);
-            println!("total addresses = {total_addresses}");
+            let Some((new_mode, addresses)) = next_mode(&receiver.1, &stopped).await? else {
+                // saved as it is at the start of the loop
+                continue;
+            };
+            current_mode = new_mode;
+            scan_order = ScanOrder::new(addresses, rand::random());
+            total_addresses = scan_order.count_addresses();
             index = 0;
+            check_excluded = false;
             request_state = RequestState::None;
             last_update = Instant::now();
-            state.lock().await.discovered = 0;
-            println!("got new state {current_mode:?}");
+            start_mode(
+                current_mode,
+                &scan_order,
+                index,
+                &state,
+                &mut retries,
+                &mut checkpoints,
+            )
+            .await?;
             continue;
         }
-        let current_addr = current_addresses.get_addr_at(index);
-        pinger.ping(current_addr).await?;
+        let current_addr = scan_order.get_addr_at(index);
+        if !(check_excluded && exclude::is_excluded(&current_addr)) {
+            pinger.ping(current_addr).await?;
+        }
+        retries.pinged(index);
         index += 1;
     }
 }
 
+/// Start scanning `scan_order` for `mode` from `index`, with new cookies and
+/// retries, a checkpoint of the new addresses, and everything counted towards
+/// the new mode from now on
+async fn start_mode(
+    mode: ScanningMode,
+    scan_order: &ScanOrder,
+    index: u64,
+    state: &Mutex<ScannerState>,
+    retries: &mut Retries,
+    checkpoints: &mut Option<Checkpoints>,
+) -> eyre::Result<()> {
+    println!("got new state {mode:?}");
+    println!("total addresses = {}", scan_order.count_addresses());
+    io::cookie::rotate_epoch();
+    retries.reset(index, &state.lock().await.receive.syn_acks);
+    if let Some(checkpoints) = checkpoints {
+        checkpoints.start(mode, scan_order, index)?;
+    }
+    let mut state = state.lock().await;
+    state.discovered = 0;
+    state.start_mode(format!("{mode:?}"));
+    Ok(())
+}
+
 #[derive(PartialEq)]
 enum RequestState {
     None,
@@ -0,0 +1,201 @@
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+/// How often we remember when an index was pinged
+const CHECKPOINT_INTERVAL: u64 = 1024;
+
+/// Schedules retries by walking the addresses again once for every retry,
+/// `delay` behind the previous try. Only the time every
+/// [`CHECKPOINT_INTERVAL`]th index was pinged is remembered, so nothing is
+/// stored per address.
+pub struct Retries {
+    delay: Duration,
+    /// The next index to retry for every retry
+    cursors: Vec<u64>,
+    /// When every [`CHECKPOINT_INTERVAL`]th index was first pinged
+    checkpoints: VecDeque<(u64, Instant)>,
+    /// Pings sent for every attempt, the first ping being attempt 0
+    sent: Vec<u64>,
+    /// SYN-ACKs received for every attempt when the current addresses were
+    /// started, so we only report the hits for them
+    syn_acks_at_start: Vec<u64>,
+}
+
+impl Retries {
+    pub fn new(retries: u8, delay: Duration) -> Self {
+        Self {
+            delay,
+            cursors: vec![0; retries as usize],
+            checkpoints: VecDeque::new(),
+            sent: vec![0; retries as usize + 1],
+            syn_acks_at_start: Vec::new(),
+        }
+    }
+
+    /// Start retrying a new set of addresses, from index `start` on
+    pub fn reset(&mut self, start: u64, syn_acks: &[u64]) {
+        self.cursors.fill(start);
+        self.checkpoints.clear();
+        self.sent.fill(0);
+        self.syn_acks_at_start = syn_acks.to_vec();
+    }
+
+    /// Record that `index` was pinged for the first time
+    pub fn pinged(&mut self, index: u64) {
+        self.pinged_at(index, Instant::now());
+    }
+
+    fn pinged_at(&mut self, index: u64, now: Instant) {
+        // the first index is always remembered, a resumed scan doesn't have to
+        // start on a checkpoint and retries wait for the one before them
+        if self.checkpoints.is_empty() || index % CHECKPOINT_INTERVAL == 0 {
+            self.checkpoints.push_back((index, now));
+        }
+        self.sent[0] += 1;
+    }
+
+    /// The next index to retry and its attempt, if one is due. `pinged` is how
+    /// many indexes have been pinged so far.
+    pub fn next_due(&mut self, pinged: u64) -> Option<(u64, u8)> {
+        self.next_due_at(pinged, Instant::now())
+    }
+
+    fn next_due_at(&mut self, pinged: u64, now: Instant) -> Option<(u64, u8)> {
+        for retry in 0..self.cursors.len() {
+            let cursor = self.cursors[retry];
+            if cursor >= pinged {
+                continue;
+            }
+            let checkpoint = self
+                .checkpoints
+                .partition_point(|(index, _)| *index <= cursor);
+            let Some(&(_, pinged_at)) = checkpoint
+                .checked_sub(1)
+                .and_then(|checkpoint| self.checkpoints.get(checkpoint))
+            else {
+                continue;
+            };
+            if now < pinged_at + self.delay * (retry as u32 + 1) {
+                continue;
+            }
+
+            self.cursors[retry] += 1;
+            let attempt = retry as u8 + 1;
+            self.sent[attempt as usize] += 1;
+
+            // forget checkpoints every retry is past
+            let slowest = self.cursors.iter().min().copied().unwrap_or(pinged);
+            while self.checkpoints.len() > 1 && self.checkpoints[1].0 <= slowest {
+                self.checkpoints.pop_front();
+            }
+            return Some((cursor, attempt));
+        }
+        None
+    }
+
+    /// Whether every retry of the first `total` indexes was sent
+    pub fn is_done(&self, total: u64) -> bool {
+        self.cursors.iter().all(|cursor| *cursor >= total)
+    }
+
+    /// Print the hit rate of every attempt, `syn_acks` being the SYN-ACKs
+    /// received for every attempt so far.
+    pub fn report(&self, syn_acks: &[u64]) {
+        for (attempt, sent) in self.sent.iter().enumerate() {
+            let hits = syn_acks
+                .get(attempt)
+                .copied()
+                .unwrap_or(0)
+                .saturating_sub(self.syn_acks_at_start.get(attempt).copied().unwrap_or(0));
+            println!(
+                "attempt {attempt}: {hits}/{sent} pings answered ({:.4}%)",
+                hits as f64 / (*sent).max(1) as f64 * 100.0
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DELAY: Duration = Duration::from_secs(10);
+
+    /// Ping `start..end` at `now`
+    fn ping(retries: &mut Retries, start: u64, end: u64, now: Instant) {
+        for index in start..end {
+            retries.pinged_at(index, now);
+        }
+    }
+
+    /// Every retry that's due at `now`
+    fn due(retries: &mut Retries, pinged: u64, now: Instant) -> Vec<(u64, u8)> {
+        std::iter::from_fn(|| retries.next_due_at(pinged, now)).collect()
+    }
+
+    #[test]
+    fn retries_after_an_unaligned_start() {
+        let mut retries = Retries::new(1, DELAY);
+        retries.reset(1000, &[]);
+        let now = Instant::now();
+        ping(&mut retries, 1000, 1010, now);
+
+        assert_eq!(
+            due(&mut retries, 1010, now + DELAY),
+            (1000..1010).map(|index| (index, 1)).collect::<Vec<_>>()
+        );
+        assert!(retries.is_done(1010));
+    }
+
+    #[test]
+    fn done_once_every_retry_is_sent() {
+        let mut retries = Retries::new(2, DELAY);
+        let now = Instant::now();
+        ping(&mut retries, 0, 2000, now);
+        assert!(!retries.is_done(2000));
+
+        assert_eq!(due(&mut retries, 2000, now + DELAY * 2).len(), 4000);
+        assert!(retries.is_done(2000));
+        assert_eq!(retries.sent, [2000, 2000, 2000]);
+    }
+
+    #[test]
+    fn every_attempt_waits_longer() {
+        let mut retries = Retries::new(2, DELAY);
+        let now = Instant::now();
+        ping(&mut retries, 0, 1, now);
+
+        assert!(due(&mut retries, 1, now + DELAY - Duration::from_millis(1)).is_empty());
+        assert_eq!(due(&mut retries, 1, now + DELAY), [(0, 1)]);
+        assert!(due(&mut retries, 1, now + DELAY * 2 - Duration::from_millis(1)).is_empty());
+        assert_eq!(due(&mut retries, 1, now + DELAY * 2), [(0, 2)]);
+        assert!(retries.is_done(1));
+    }
+
+    #[test]
+    fn waits_for_later_checkpoints() {
+        let mut retries = Retries::new(1, DELAY);
+        let now = Instant::now();
+        ping(&mut retries, 0, CHECKPOINT_INTERVAL, now);
+        ping(
+            &mut retries,
+            CHECKPOINT_INTERVAL,
+            CHECKPOINT_INTERVAL + 1,
+            now + DELAY,
+        );
+
+        let total = CHECKPOINT_INTERVAL + 1;
+        assert_eq!(
+            due(&mut retries, total, now + DELAY).len() as u64,
+            CHECKPOINT_INTERVAL
+        );
+        assert!(!retries.is_done(total));
+        assert_eq!(
+            due(&mut retries, total, now + DELAY * 2),
+            [(CHECKPOINT_INTERVAL, 1)]
+        );
+        assert!(retries.is_done(total));
+    }
+}
@@ -0,0 +1,133 @@
+use crate::ServerState;
+use axum::{
+    extract::State,
+    http::header,
+    response::{IntoResponse, Response},
+};
+use io::metrics::{Totals, COUNTERS};
+use std::fmt::Write;
+
+/// Scanner metrics in the prometheus text format, counters are split up by
+/// scanning mode
+pub async fn get(server_state: State<ServerState>) -> Response {
+    let state = server_state.state.lock().await;
+    let by_mode = state.totals_by_mode();
+    let mut metrics = String::new();
+
+    let mut counter = |name: &str, help: &str, value: fn(&Totals) -> u64| {
+        let _ = writeln!(metrics, "# HELP snowstorm_{name} {help}");
+        let _ = writeln!(metrics, "# TYPE snowstorm_{name} counter");
+        for (mode, totals) in &by_mode {
+            let _ = writeln!(
+                metrics,
+                "snowstorm_{name}{{mode=\"{mode}\"}} {}",
+                value(totals)
+            );
+        }
+    };
+    counter(
+        "syns_sent_total",
+        "SYNs sent, or connections started by the tcp backend",
+        |totals| totals.syns_sent,
+    );
+    counter(
+        "syn_acks_total",
+        "SYN-ACKs received, or connections opened by the tcp backend",
+        |totals| totals.syn_acks,
+    );
+    counter(
+        "rsts_total",
+        "RSTs received in response to our SYNs",
+        |totals| totals.rsts,
+    );
+    counter("statuses_total", "Status responses decoded", |totals| {
+        totals.statuses
+    });
+    counter(
+        "parse_failures_total",
+        "Responses that couldn't be decoded",
+        |totals| totals.parse_failures,
+    );
+    counter(
+        "reassembly_timeouts_total",
+        "Connections dropped because the server stopped responding",
+        |totals| totals.reassembly_timeouts,
+    );
+
+    let _ = writeln!(
+        metrics,
+        "# HELP snowstorm_db_push_seconds Time spent pushing results to the database"
+    );
+    let _ = writeln!(metrics, "# TYPE snowstorm_db_push_seconds summary");
+    for (mode, totals) in &by_mode {
+        let _ = writeln!(
+            metrics,
+            "snowstorm_db_push_seconds_sum{{mode=\"{mode}\"}} {}",
+            totals.db_push_micros as f64 / 1e6
+        );
+        let _ = writeln!(
+            metrics,
+            "snowstorm_db_push_seconds_count{{mode=\"{mode}\"}} {}",
+            totals.db_pushes
+        );
+    }
+
+    let _ = writeln!(
+        metrics,
+        "# HELP snowstorm_excluded_total Addresses the exclude list removed before scanning"
+    );
+    let _ = writeln!(metrics, "# TYPE snowstorm_excluded_total counter");
+    for (mode, excluded) in state.modes.excluded() {
+        let _ = writeln!(
+            metrics,
+            "snowstorm_excluded_total{{mode=\"{mode}\"}} {excluded}"
+        );
+    }
+
+    let mut gauge = |name: &str, help: &str, value: u64| {
+        let _ = writeln!(metrics, "# HELP snowstorm_{name} {help}");
+        let _ = writeln!(metrics, "# TYPE snowstorm_{name} gauge");
+        let _ = writeln!(metrics, "snowstorm_{name} {value}");
+    };
+    gauge(
+        "discovered",
+        "Servers discovered in the current scanning mode",
+        state.discovered,
+    );
+    gauge(
+        "open_connections",
+        "Connections waiting for a response",
+        state.connection_stats().open as u64,
+    );
+    gauge(
+        "db_queue_depth",
+        "Results waiting to be pushed to the database",
+        COUNTERS.queue_depth(),
+    );
+
+    let _ = writeln!(
+        metrics,
+        "# HELP snowstorm_receive_queue_depth Packets waiting in each receive worker's queue"
+    );
+    let _ = writeln!(metrics, "# TYPE snowstorm_receive_queue_depth gauge");
+    for (worker, depth) in state.receive.queue_depths.iter().enumerate() {
+        let _ = writeln!(
+            metrics,
+            "snowstorm_receive_queue_depth{{worker=\"{worker}\"}} {depth}"
+        );
+    }
+    if let Some(mode) = state.modes.current() {
+        let _ = writeln!(
+            metrics,
+            "# HELP snowstorm_mode The scanning mode being scanned"
+        );
+        let _ = writeln!(metrics, "# TYPE snowstorm_mode gauge");
+        let _ = writeln!(metrics, "snowstorm_mode{{mode=\"{mode}\"}} 1");
+    }
+
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        metrics,
+    )
+        .into_response()
+}
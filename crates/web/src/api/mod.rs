@@ -1,4 +1,7 @@
+pub mod metrics;
+pub mod opt_out;
 pub mod player_info;
+pub mod rate_limit;
 pub mod server_info;
 pub mod servers;
 pub mod whereis;
@@ -0,0 +1,93 @@
+use crate::{authentication::admin_user, ServerState};
+use axum::{
+    extract::{Path, State},
+    headers,
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Form, Json, TypedHeader,
+};
+use database::{
+    opt_out::{OptOut, OptOutLog},
+    DbPush,
+};
+use serde::Deserialize;
+use time::OffsetDateTime;
+
+#[derive(Deserialize)]
+pub struct OptOutForm {
+    cidr: String,
+    requester: String,
+    reason: String,
+    /// Unix timestamp of when they asked, defaults to now
+    requested_at: Option<i64>,
+}
+
+pub async fn get(
+    server_state: State<ServerState>,
+    cookies: Option<TypedHeader<headers::Cookie>>,
+) -> Response {
+    if admin_user(&cookies, &server_state.db.pool).await.is_none() {
+        return StatusCode::FORBIDDEN.into_response();
+    }
+    Json(OptOut::get_all(&server_state.db.pool).await).into_response()
+}
+
+pub async fn log(
+    server_state: State<ServerState>,
+    cookies: Option<TypedHeader<headers::Cookie>>,
+) -> Response {
+    if admin_user(&cookies, &server_state.db.pool).await.is_none() {
+        return StatusCode::FORBIDDEN.into_response();
+    }
+    Json(OptOutLog::get_all(&server_state.db.pool).await).into_response()
+}
+
+pub async fn add(
+    server_state: State<ServerState>,
+    cookies: Option<TypedHeader<headers::Cookie>>,
+    form: Form<OptOutForm>,
+) -> Response {
+    let Some(user) = admin_user(&cookies, &server_state.db.pool).await else {
+        return StatusCode::FORBIDDEN.into_response();
+    };
+    let opt_out = OptOut::new(
+        &form.cidr,
+        &form.requester,
+        &form.reason,
+        form.requested_at
+            .unwrap_or_else(|| OffsetDateTime::now_utc().unix_timestamp()),
+        user.id.unwrap(),
+        "web",
+    );
+    let mut opt_out = match opt_out {
+        Ok(opt_out) => opt_out,
+        Err(err) => return (StatusCode::BAD_REQUEST, err.to_string()).into_response(),
+    };
+    if let Err(err) = opt_out.push(&server_state.db.pool).await {
+        eprintln!("unable to add opt-out: {err}");
+        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    }
+    println!("{} opted out {}", user.username, opt_out.cidr);
+    Json(opt_out).into_response()
+}
+
+pub async fn remove(
+    server_state: State<ServerState>,
+    cookies: Option<TypedHeader<headers::Cookie>>,
+    Path(id): Path<i64>,
+) -> Response {
+    let Some(user) = admin_user(&cookies, &server_state.db.pool).await else {
+        return StatusCode::FORBIDDEN.into_response();
+    };
+    let Some(mut opt_out) = OptOut::get_id(id, &server_state.db.pool).await else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    if let Err(err) = opt_out
+        .remove(user.id.unwrap(), "web", &server_state.db.pool)
+        .await
+    {
+        return (StatusCode::BAD_REQUEST, err.to_string()).into_response();
+    }
+    println!("{} removed the opt-out of {}", user.username, opt_out.cidr);
+    Json(opt_out).into_response()
+}
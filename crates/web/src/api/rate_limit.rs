@@ -0,0 +1,31 @@
+use crate::{authentication::admin_user, ServerState};
+use axum::{
+    extract::State,
+    headers,
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Form, Json, TypedHeader,
+};
+use common::net::rate_limit::RateLimit;
+
+pub async fn get(
+    server_state: State<ServerState>,
+    cookies: Option<TypedHeader<headers::Cookie>>,
+) -> Response {
+    if admin_user(&cookies, &server_state.db.pool).await.is_none() {
+        return StatusCode::FORBIDDEN.into_response();
+    }
+    Json(server_state.rate_limiter.limit()).into_response()
+}
+
+pub async fn set(
+    server_state: State<ServerState>,
+    cookies: Option<TypedHeader<headers::Cookie>>,
+    limit: Form<RateLimit>,
+) -> Response {
+    if admin_user(&cookies, &server_state.db.pool).await.is_none() {
+        return StatusCode::FORBIDDEN.into_response();
+    }
+    server_state.rate_limiter.set_limit(limit.0);
+    Json(server_state.rate_limiter.limit()).into_response()
+}
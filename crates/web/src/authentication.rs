@@ -108,3 +108,17 @@ pub async fn info(cookies: Option<TypedHeader<headers::Cookie>>) -> impl IntoRes
         _ => "No session".to_string(),
     }
 }
+
+/// The logged in user, if they have a valid session and are an admin
+pub async fn admin_user(
+    cookies: &Option<TypedHeader<headers::Cookie>>,
+    pool: &sqlx::PgPool,
+) -> Option<User> {
+    match UserSession::from_cookies(cookies) {
+        Some(Ok(session)) if session.is_valid() => {
+            let user = session.user(pool).await;
+            user.is_admin(pool).await.then_some(user)
+        }
+        _ => None,
+    }
+}
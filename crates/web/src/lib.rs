@@ -1,9 +1,10 @@
 use axum::{
     body::{boxed, Body, BoxBody},
     http::{Request, Response, Uri},
-    routing::{get, post},
+    routing::{delete, get, post},
     Router,
 };
+use common::net::rate_limit::RateLimiter;
 use database::DatabaseConnection;
 use io::ScannerState;
 use reqwest::StatusCode;
@@ -26,11 +27,13 @@ mod ws;
 pub struct ServerState {
     pub db: DatabaseConnection,
     pub state: Arc<Mutex<ScannerState>>,
+    pub rate_limiter: RateLimiter,
 }
 
 pub async fn start_server(
     db: DatabaseConnection,
     state: Arc<Mutex<ScannerState>>,
+    rate_limiter: RateLimiter,
 ) -> eyre::Result<()> {
     #[cfg(debug_assertions)]
     tracing_subscriber::registry()
@@ -41,7 +44,11 @@ pub async fn start_server(
         .with(tracing_subscriber::fmt::layer())
         .init();
 
-    let server_state = ServerState { db, state };
+    let server_state = ServerState {
+        db,
+        state,
+        rate_limiter,
+    };
 
     let routes = Router::new()
         .nest_service("/", get(handler))
@@ -51,6 +58,17 @@ pub async fn start_server(
         .route("/auth/discord", post(oauth::discord::link_account))
         .route("/auth/forgejo", post(oauth::forgejo::link_account))
         .route("/auth/info", get(authentication::info))
+        .route("/metrics", get(api::metrics::get))
+        .route(
+            "/api/rate_limit",
+            get(api::rate_limit::get).post(api::rate_limit::set),
+        )
+        .route(
+            "/api/opt_out",
+            get(api::opt_out::get).post(api::opt_out::add),
+        )
+        .route("/api/opt_out/log", get(api::opt_out::log))
+        .route("/api/opt_out/:id", delete(api::opt_out::remove))
         .route("/oauth2", get(oauth::discord::oauth2))
         .route("/oauth2_discord", get(oauth::discord::oauth2))
         .route("/oauth2_forgejo", get(oauth::forgejo::oauth2));
@@ -0,0 +1,5 @@
+-- Migrates a database created before IPv6 support, where ip was an INT
+-- holding the IPv4 address as a signed 32 bit number.
+ALTER TABLE servers
+    ALTER COLUMN ip TYPE INET
+    USING '0.0.0.0'::INET + (ip::BIGINT & 4294967295);
@@ -0,0 +1,24 @@
+-- Adds the opt-out tables to a database created before they existed.
+CREATE TABLE IF NOT EXISTS opt_outs (
+    id BIGINT PRIMARY KEY GENERATED ALWAYS AS IDENTITY,
+    cidr CIDR NOT NULL,
+    requester TEXT NOT NULL,
+    reason TEXT NOT NULL,
+    requested_at BIGINT NOT NULL,
+    created_by BIGINT NOT NULL, -- users(id)
+    created_via TEXT NOT NULL,
+    created_at BIGINT NOT NULL DEFAULT EXTRACT(epoch from now()),
+    removed_at BIGINT
+);
+
+CREATE TABLE IF NOT EXISTS opt_out_log (
+    id BIGINT PRIMARY KEY GENERATED ALWAYS AS IDENTITY,
+    opt_out_id BIGINT NOT NULL,
+    action TEXT NOT NULL,
+    user_id BIGINT NOT NULL,
+    via TEXT NOT NULL,
+    at BIGINT NOT NULL DEFAULT EXTRACT(epoch from now()),
+    CONSTRAINT fk_opt_out
+        FOREIGN KEY (opt_out_id)
+        REFERENCES opt_outs(id)
+);
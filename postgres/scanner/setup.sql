@@ -2,10 +2,12 @@ DROP TABLE IF EXISTS join_servers_players CASCADE;
 DROP TABLE IF EXISTS servers CASCADE;
 DROP TABLE IF EXISTS server_joins CASCADE;
 DROP TABLE IF EXISTS players CASCADE;
+DROP TABLE IF EXISTS opt_outs CASCADE;
+DROP TABLE IF EXISTS opt_out_log CASCADE;
 
 CREATE TABLE IF NOT EXISTS servers (
     id BIGINT PRIMARY KEY GENERATED ALWAYS AS IDENTITY,
-	ip INT NOT NULL,
+	ip INET NOT NULL,
 	port SMALLINT NOT NULL,
     version_name TEXT,
     version_protocol INT,
@@ -65,3 +67,28 @@ CREATE TABLE IF NOT EXISTS join_servers_players (
 	CONSTRAINT join_pkey
 		PRIMARY KEY (server_id, player_id)
 );
+
+-- networks whose owners asked not to be scanned, merged with exclude.txt
+CREATE TABLE IF NOT EXISTS opt_outs (
+    id BIGINT PRIMARY KEY GENERATED ALWAYS AS IDENTITY,
+    cidr CIDR NOT NULL,
+    requester TEXT NOT NULL,
+    reason TEXT NOT NULL,
+    requested_at BIGINT NOT NULL,
+    created_by BIGINT NOT NULL, -- users(id)
+    created_via TEXT NOT NULL,
+    created_at BIGINT NOT NULL DEFAULT EXTRACT(epoch from now()),
+    removed_at BIGINT
+);
+
+CREATE TABLE IF NOT EXISTS opt_out_log (
+    id BIGINT PRIMARY KEY GENERATED ALWAYS AS IDENTITY,
+    opt_out_id BIGINT NOT NULL,
+    action TEXT NOT NULL,
+    user_id BIGINT NOT NULL,
+    via TEXT NOT NULL,
+    at BIGINT NOT NULL DEFAULT EXTRACT(epoch from now()),
+    CONSTRAINT fk_opt_out
+        FOREIGN KEY (opt_out_id)
+        REFERENCES opt_outs(id)
+);